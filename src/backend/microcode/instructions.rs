@@ -1,13 +1,16 @@
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
-use std::mem::Discriminant;
+
+use itertools::Itertools;
 
 use super::lower::MicrocodeConfig;
 use crate::backend::register_coloring::PhysicalRegister;
+use crate::backend::register_liveness::ClobberingInstruction;
 use crate::ir::{
-    CfgConfig, Function, Instruction, SSABlock, SSAInstruction, SSAInstructionRHS,
+    Function, Instruction, SSAInstruction, SSAInstructionRHS, VirtualRegister,
     VirtualRegisterLValue, WithRegisters,
 };
-use crate::semantics::{BinaryOperator, UnaryOperator};
+use crate::semantics::{BinaryOperator, IntType, UnaryOperator};
 
 pub type LoweredInstruction = Instruction<MicrocodeConfig>;
 
@@ -31,6 +34,35 @@ pub enum BinaryALUOperator {
     Xor,
 }
 
+// what the hardware computes, so that tests can run lowered code
+#[cfg(test)]
+impl UnaryALUOperator {
+    pub const fn apply(self, arg: i64) -> i64 {
+        match self {
+            UnaryALUOperator::Copy => arg,
+            UnaryALUOperator::Inc1 => arg.wrapping_add(1),
+            UnaryALUOperator::Inc4 => arg.wrapping_add(4),
+            UnaryALUOperator::Dec1 => arg.wrapping_sub(1),
+            UnaryALUOperator::Dec4 => arg.wrapping_sub(4),
+        }
+    }
+}
+
+#[cfg(test)]
+impl BinaryALUOperator {
+    pub const fn apply(self, arg1: i64, arg2: i64) -> i64 {
+        match self {
+            BinaryALUOperator::Add => arg1.wrapping_add(arg2),
+            BinaryALUOperator::Sub => arg1.wrapping_sub(arg2),
+            BinaryALUOperator::Slt => (arg1 < arg2) as i64,
+            BinaryALUOperator::Sltu => ((arg1 as u64) < (arg2 as u64)) as i64,
+            BinaryALUOperator::And => arg1 & arg2,
+            BinaryALUOperator::Or => arg1 | arg2,
+            BinaryALUOperator::Xor => arg1 ^ arg2,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum LoweredInstructionRHS<RegType> {
     UnaryALU {
@@ -53,6 +85,10 @@ pub enum LoweredInstructionRHS<RegType> {
         index: u8,
         value: RegType,
     },
+    Call {
+        func: String,
+        args: Box<[RegType]>,
+    },
 }
 
 impl<RegType> LoweredInstructionRHS<RegType> {
@@ -91,6 +127,10 @@ impl<RegType> LoweredInstructionRHS<RegType> {
                     value: mapper(value),
                 }
             }
+            LoweredInstructionRHS::Call { func, args } => LoweredInstructionRHS::Call {
+                func,
+                args: args.into_vec().into_iter().map(mapper).collect(),
+            },
         }
     }
 }
@@ -109,6 +149,7 @@ impl<RegType> WithRegisters<RegType> for LoweredInstructionRHS<RegType> {
             LoweredInstructionRHS::StoreMemory { addr, data } => vec![addr, data],
             LoweredInstructionRHS::LoadRegister(_) => vec![],
             LoweredInstructionRHS::StoreRegister { index: _, value } => vec![value],
+            LoweredInstructionRHS::Call { func: _, args } => args.iter().collect(),
         }
         .into_iter()
     }
@@ -126,11 +167,19 @@ impl<RegType> WithRegisters<RegType> for LoweredInstructionRHS<RegType> {
             LoweredInstructionRHS::StoreMemory { addr, data } => vec![addr, data],
             LoweredInstructionRHS::LoadRegister(_) => vec![],
             LoweredInstructionRHS::StoreRegister { index: _, value } => vec![value],
+            LoweredInstructionRHS::Call { func: _, args } => args.iter_mut().collect(),
         }
         .into_iter()
     }
 }
 
+impl<RegType> ClobberingInstruction for LoweredInstructionRHS<RegType> {
+    fn clobbers_registers(&self) -> bool {
+        // the callee is free to use every physical register
+        matches!(self, LoweredInstructionRHS::Call { .. })
+    }
+}
+
 impl<T: Display> Display for LoweredInstructionRHS<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -149,6 +198,9 @@ impl<T: Display> Display for LoweredInstructionRHS<T> {
             LoweredInstructionRHS::StoreRegister { index, value } => {
                 write!(f, "R[{index}] = {value}")
             }
+            LoweredInstructionRHS::Call { func, args } => {
+                write!(f, "call {func}({})", args.iter().join(", "))
+            }
         }
     }
 }
@@ -157,88 +209,62 @@ pub fn lowered_insts(
     func: &mut Function<MicrocodeConfig>,
     inst: SSAInstruction,
     input_cnt: &mut u8,
+    constants: &HashMap<VirtualRegister, i64>,
 ) -> impl IntoIterator<Item = LoweredInstruction> {
     match inst.rhs {
         SSAInstructionRHS::BinaryOperation {
             operator,
             arg1,
             arg2,
+            ..
+        } if operator.is_comparison() => lowered_comparison(func, inst.lhs, operator, arg1, arg2),
+        SSAInstructionRHS::BinaryOperation {
+            operator,
+            width,
+            arg1,
+            arg2,
         } => {
-            vec![LoweredInstruction {
-                lhs: inst.lhs,
-                rhs: LoweredInstructionRHS::BinaryALU {
-                    operator: match operator {
-                        BinaryOperator::Add => BinaryALUOperator::Add,
-                        BinaryOperator::Mul => todo!("implement multiplication"),
-                        BinaryOperator::Sub => BinaryALUOperator::Sub,
-                        BinaryOperator::Div => todo!("implement division"),
-                        BinaryOperator::Xor => BinaryALUOperator::Xor,
-                        BinaryOperator::And => BinaryALUOperator::And,
-                    },
-                    arg1,
-                    arg2,
-                },
-            }]
+            let needs_wrap = width.bits < 64 && !operator.preserves_width();
+            let alu_operator = match operator {
+                BinaryOperator::Add => Some(BinaryALUOperator::Add),
+                BinaryOperator::Sub => Some(BinaryALUOperator::Sub),
+                BinaryOperator::Xor => Some(BinaryALUOperator::Xor),
+                BinaryOperator::And => Some(BinaryALUOperator::And),
+                BinaryOperator::Or => Some(BinaryALUOperator::Or),
+                _ => None,
+            };
+            let mut out = match alu_operator {
+                Some(operator) => vec![binary_alu(None, operator, arg1, arg2)],
+                None => lowered_expansion(func, operator, width, arg1, arg2, constants),
+            };
+            // the last instruction computes the result, at 64 bits
+            if needs_wrap {
+                let temp @ VirtualRegisterLValue(temp_ref) = func.new_reg();
+                out.last_mut().unwrap().lhs = Some(temp);
+                out.extend(lowered_wrap(func, inst.lhs, width, temp_ref));
+            } else {
+                out.last_mut().unwrap().lhs = inst.lhs;
+            }
+            out
         }
         SSAInstructionRHS::UnaryOperation {
             operator: UnaryOperator::Not,
+            width,
             arg,
         } => {
-            let temp @ VirtualRegisterLValue(temp_ref) = func.new_reg();
-            let temp2 @ VirtualRegisterLValue(temp2_ref) = func.new_reg();
-            let temp3 @ VirtualRegisterLValue(temp3_ref) = func.new_reg();
-            vec![
-                LoweredInstruction {
-                    lhs: temp,
-                    rhs: LoweredInstructionRHS::LoadOneImmediate,
-                },
-                LoweredInstruction {
-                    lhs: temp2,
-                    rhs: LoweredInstructionRHS::UnaryALU {
-                        operator: UnaryALUOperator::Dec1,
-                        arg: temp_ref,
-                    },
-                },
-                LoweredInstruction {
-                    lhs: temp3,
-                    rhs: LoweredInstructionRHS::UnaryALU {
-                        operator: UnaryALUOperator::Dec1,
-                        arg: temp2_ref,
-                    },
-                },
-                LoweredInstruction {
-                    lhs: inst.lhs,
-                    rhs: LoweredInstructionRHS::BinaryALU {
-                        operator: BinaryALUOperator::Xor,
-                        arg1: arg,
-                        arg2: temp3_ref,
-                    },
-                },
-            ]
-        }
-        SSAInstructionRHS::LoadIntegerLiteral { value } => {
-            let temp @ VirtualRegisterLValue(temp_ref) = func.new_reg();
-            match value {
-                1 => vec![LoweredInstruction {
-                    lhs: inst.lhs,
-                    rhs: LoweredInstructionRHS::LoadOneImmediate,
-                }],
-                0 => vec![
-                    LoweredInstruction {
-                        lhs: temp,
-                        rhs: LoweredInstructionRHS::LoadOneImmediate,
-                    },
-                    LoweredInstruction {
-                        lhs: inst.lhs,
-                        rhs: LoweredInstructionRHS::UnaryALU {
-                            operator: UnaryALUOperator::Dec1,
-                            arg: temp_ref,
-                        },
-                    },
-                ],
-                _ => todo!("implement integer generation (aside from 0 and 1)"),
-            }
+            // flipping only the bits within an unsigned width keeps the result in range
+            let ones @ VirtualRegisterLValue(ones_ref) = func.new_reg();
+            let ones_value = if width.signed { -1 } else { width.mask() };
+            let mut out = lowered_constant(func, Some(ones), ones_value);
+            out.push(binary_alu(inst.lhs, BinaryALUOperator::Xor, arg, ones_ref));
+            out
         }
+        SSAInstructionRHS::UnaryOperation {
+            operator: UnaryOperator::Wrap,
+            width,
+            arg,
+        } => lowered_wrap(func, inst.lhs, width, arg),
+        SSAInstructionRHS::LoadIntegerLiteral { value } => lowered_constant(func, inst.lhs, value),
         SSAInstructionRHS::Move { src } => {
             println!("unexpected reg move in lowered IR");
             vec![LoweredInstruction {
@@ -256,13 +282,636 @@ pub fn lowered_insts(
                 rhs: LoweredInstructionRHS::LoadRegister(*input_cnt - 1),
             }]
         }
+        SSAInstructionRHS::Param { index } => {
+            vec![LoweredInstruction {
+                lhs: inst.lhs,
+                rhs: LoweredInstructionRHS::LoadRegister(index as u8),
+            }]
+        }
         SSAInstructionRHS::ReadMemory(src) => {
             vec![LoweredInstruction {
                 lhs: inst.lhs,
                 rhs: LoweredInstructionRHS::LoadMemory(src),
             }]
         }
+        SSAInstructionRHS::Call { func, args } => {
+            vec![LoweredInstruction {
+                lhs: inst.lhs,
+                rhs: LoweredInstructionRHS::Call { func, args },
+            }]
+        }
+        SSAInstructionRHS::StoreMemory { addr, data } => {
+            vec![LoweredInstruction {
+                lhs: inst.lhs,
+                rhs: LoweredInstructionRHS::StoreMemory { addr, data },
+            }]
+        }
+    }
+}
+
+const fn binary_alu(
+    lhs: Option<VirtualRegisterLValue>,
+    operator: BinaryALUOperator,
+    arg1: VirtualRegister,
+    arg2: VirtualRegister,
+) -> LoweredInstruction {
+    LoweredInstruction {
+        lhs,
+        rhs: LoweredInstructionRHS::BinaryALU {
+            operator,
+            arg1,
+            arg2,
+        },
+    }
+}
+
+fn unary_alu(
+    func: &mut Function<MicrocodeConfig>,
+    operator: UnaryALUOperator,
+    arg: VirtualRegister,
+) -> (LoweredInstruction, VirtualRegister) {
+    let out @ VirtualRegisterLValue(out_ref) = func.new_reg();
+    (
+        LoweredInstruction {
+            lhs: Some(out),
+            rhs: LoweredInstructionRHS::UnaryALU { operator, arg },
+        },
+        out_ref,
+    )
+}
+
+// the only immediate is 1, so other constants are built from it one bit at a time
+fn lowered_constant(
+    func: &mut Function<MicrocodeConfig>,
+    lhs: Option<VirtualRegisterLValue>,
+    value: i64,
+) -> Vec<LoweredInstruction> {
+    if value < -1 {
+        // negative values have many leading ones, so build the complement and flip it
+        let complement @ VirtualRegisterLValue(complement_ref) = func.new_reg();
+        let ones @ VirtualRegisterLValue(ones_ref) = func.new_reg();
+        let mut out = lowered_constant(func, Some(complement), !value);
+        out.extend(lowered_constant(func, Some(ones), -1));
+        out.push(binary_alu(
+            lhs,
+            BinaryALUOperator::Xor,
+            complement_ref,
+            ones_ref,
+        ));
+        return out;
+    }
+    let one @ VirtualRegisterLValue(one_ref) = func.new_reg();
+    let mut out = vec![LoweredInstruction {
+        lhs: Some(one),
+        rhs: LoweredInstructionRHS::LoadOneImmediate,
+    }];
+    if value <= 0 {
+        let (inst, zero_ref) = unary_alu(func, UnaryALUOperator::Dec1, one_ref);
+        out.push(inst);
+        if value == -1 {
+            out.push(unary_alu(func, UnaryALUOperator::Dec1, zero_ref).0);
+        }
+    } else {
+        // Horner's rule, starting from the highest set bit
+        let mut acc = one_ref;
+        for bit in (0..63 - value.leading_zeros()).rev() {
+            let doubled @ VirtualRegisterLValue(doubled_ref) = func.new_reg();
+            out.push(binary_alu(Some(doubled), BinaryALUOperator::Add, acc, acc));
+            acc = doubled_ref;
+            if value & (1 << bit) != 0 {
+                let inst;
+                (inst, acc) = unary_alu(func, UnaryALUOperator::Inc1, acc);
+                out.push(inst);
+            }
+        }
+    }
+    out.last_mut().unwrap().lhs = lhs;
+    out
+}
+
+// truncates a value to the given width, then zero- or sign-extends it back to 64 bits
+fn lowered_wrap(
+    func: &mut Function<MicrocodeConfig>,
+    lhs: Option<VirtualRegisterLValue>,
+    width: IntType,
+    arg: VirtualRegister,
+) -> Vec<LoweredInstruction> {
+    let mask @ VirtualRegisterLValue(mask_ref) = func.new_reg();
+    let mut out = lowered_constant(func, Some(mask), width.mask());
+    if !width.signed {
+        out.push(binary_alu(lhs, BinaryALUOperator::And, arg, mask_ref));
+        return out;
+    }
+    // sign-extending x is (x ^ sign) - sign, where sign is the top bit at this width
+    let truncated @ VirtualRegisterLValue(truncated_ref) = func.new_reg();
+    let sign @ VirtualRegisterLValue(sign_ref) = func.new_reg();
+    let flipped @ VirtualRegisterLValue(flipped_ref) = func.new_reg();
+    out.push(binary_alu(
+        Some(truncated),
+        BinaryALUOperator::And,
+        arg,
+        mask_ref,
+    ));
+    out.extend(lowered_constant(func, Some(sign), 1 << (width.bits - 1)));
+    out.push(binary_alu(
+        Some(flipped),
+        BinaryALUOperator::Xor,
+        truncated_ref,
+        sign_ref,
+    ));
+    out.push(binary_alu(
+        lhs,
+        BinaryALUOperator::Sub,
+        flipped_ref,
+        sign_ref,
+    ));
+    out
+}
+
+fn lowered_comparison(
+    func: &mut Function<MicrocodeConfig>,
+    lhs: Option<VirtualRegisterLValue>,
+    operator: BinaryOperator,
+    arg1: VirtualRegister,
+    arg2: VirtualRegister,
+) -> Vec<LoweredInstruction> {
+    let alu = |lhs, operator, arg1, arg2| LoweredInstruction {
+        lhs,
+        rhs: LoweredInstructionRHS::BinaryALU {
+            operator,
+            arg1,
+            arg2,
+        },
+    };
+    // a <= b is lowered as !(b < a), by xor-ing the flipped comparison with 1
+    let negated = |func: &mut Function<MicrocodeConfig>, lhs, operator, arg1, arg2| {
+        let temp @ VirtualRegisterLValue(temp_ref) = func.new_reg();
+        let one @ VirtualRegisterLValue(one_ref) = func.new_reg();
+        vec![
+            alu(Some(temp), operator, arg1, arg2),
+            LoweredInstruction {
+                lhs: Some(one),
+                rhs: LoweredInstructionRHS::LoadOneImmediate,
+            },
+            alu(lhs, BinaryALUOperator::Xor, temp_ref, one_ref),
+        ]
+    };
+    match operator {
+        BinaryOperator::Lt => vec![alu(lhs, BinaryALUOperator::Slt, arg1, arg2)],
+        BinaryOperator::Gt => vec![alu(lhs, BinaryALUOperator::Slt, arg2, arg1)],
+        BinaryOperator::Le => negated(func, lhs, BinaryALUOperator::Slt, arg2, arg1),
+        BinaryOperator::Ge => negated(func, lhs, BinaryALUOperator::Slt, arg1, arg2),
+        BinaryOperator::Ltu => vec![alu(lhs, BinaryALUOperator::Sltu, arg1, arg2)],
+        BinaryOperator::Gtu => vec![alu(lhs, BinaryALUOperator::Sltu, arg2, arg1)],
+        BinaryOperator::Leu => negated(func, lhs, BinaryALUOperator::Sltu, arg2, arg1),
+        BinaryOperator::Geu => negated(func, lhs, BinaryALUOperator::Sltu, arg1, arg2),
+        BinaryOperator::Eq | BinaryOperator::Ne => {
+            // a == b iff (a - b) <u 1, and a != b iff 0 <u (a - b)
+            let diff @ VirtualRegisterLValue(diff_ref) = func.new_reg();
+            let one @ VirtualRegisterLValue(one_ref) = func.new_reg();
+            let mut out = vec![
+                alu(Some(diff), BinaryALUOperator::Sub, arg1, arg2),
+                LoweredInstruction {
+                    lhs: Some(one),
+                    rhs: LoweredInstructionRHS::LoadOneImmediate,
+                },
+            ];
+            if let BinaryOperator::Eq = operator {
+                out.push(alu(lhs, BinaryALUOperator::Sltu, diff_ref, one_ref));
+            } else {
+                let zero @ VirtualRegisterLValue(zero_ref) = func.new_reg();
+                out.push(LoweredInstruction {
+                    lhs: Some(zero),
+                    rhs: LoweredInstructionRHS::UnaryALU {
+                        operator: UnaryALUOperator::Dec1,
+                        arg: one_ref,
+                    },
+                });
+                out.push(alu(lhs, BinaryALUOperator::Sltu, zero_ref, diff_ref));
+            }
+            out
+        }
+        _ => unreachable!("not a comparison operator"),
+    }
+}
+
+// appends an ALU instruction writing a fresh register, and returns that register
+fn push_alu(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    operator: BinaryALUOperator,
+    arg1: VirtualRegister,
+    arg2: VirtualRegister,
+) -> VirtualRegister {
+    let lhs @ VirtualRegisterLValue(lhs_ref) = func.new_reg();
+    out.push(binary_alu(Some(lhs), operator, arg1, arg2));
+    lhs_ref
+}
+
+fn push_constant(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    value: i64,
+) -> VirtualRegister {
+    let lhs @ VirtualRegisterLValue(lhs_ref) = func.new_reg();
+    out.extend(lowered_constant(func, Some(lhs), value));
+    lhs_ref
+}
+
+// a mask is -1 if the bit is 1 and 0 if it is 0, and selects if_set or if_clear respectively
+fn push_select(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    mask: VirtualRegister,
+    if_set: VirtualRegister,
+    if_clear: VirtualRegister,
+) -> VirtualRegister {
+    let diff = push_alu(func, out, BinaryALUOperator::Xor, if_set, if_clear);
+    let picked = push_alu(func, out, BinaryALUOperator::And, diff, mask);
+    push_alu(func, out, BinaryALUOperator::Xor, if_clear, picked)
+}
+
+// the ALU has no multiplier, divider or shifter, and lowering cannot add the blocks a loop would need,
+// so these are unrolled into one step per bit, using masks instead of branches.
+// The last instruction computes the result, and its lhs is a placeholder for the caller to replace
+fn lowered_expansion(
+    func: &mut Function<MicrocodeConfig>,
+    operator: BinaryOperator,
+    width: IntType,
+    arg1: VirtualRegister,
+    arg2: VirtualRegister,
+    constants: &HashMap<VirtualRegister, i64>,
+) -> Vec<LoweredInstruction> {
+    let mut out = vec![];
+    let zero = push_constant(func, &mut out, 0);
+    let amount = constants.get(&arg2).map(|amount| amount & 63);
+    match operator {
+        BinaryOperator::Mul => lowered_mul(func, &mut out, zero, arg1, arg2),
+        BinaryOperator::Shl => lowered_shift_left(func, &mut out, zero, arg1, arg2, amount),
+        BinaryOperator::Shr => {
+            lowered_shift_right(func, &mut out, zero, arg1, arg2, amount, true);
+        }
+        BinaryOperator::Shru => {
+            // the vacated high bits are zero at the operation width
+            let value = if width.bits < 64 {
+                let mask = push_constant(func, &mut out, width.mask());
+                push_alu(func, &mut out, BinaryALUOperator::And, arg1, mask)
+            } else {
+                arg1
+            };
+            lowered_shift_right(func, &mut out, zero, value, arg2, amount, false);
+        }
+        BinaryOperator::Div | BinaryOperator::Rem => lowered_signed_division(
+            func,
+            &mut out,
+            zero,
+            arg1,
+            arg2,
+            matches!(operator, BinaryOperator::Rem),
+        ),
+        BinaryOperator::Divu | BinaryOperator::Remu => {
+            lowered_unsigned_division(
+                func,
+                &mut out,
+                zero,
+                arg1,
+                arg2,
+                matches!(operator, BinaryOperator::Remu),
+            );
+        }
+        _ => unreachable!("{operator:?} is performed by the ALU directly"),
+    }
+    out
+}
+
+// shift-and-add, taking the bits of arg2 from the top, so that acc = 2 * acc + (bit ? arg1 : 0)
+fn lowered_mul(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    zero: VirtualRegister,
+    arg1: VirtualRegister,
+    arg2: VirtualRegister,
+) {
+    let mut acc = zero;
+    let mut rest = arg2;
+    for _ in 0..64 {
+        let bit = push_alu(func, out, BinaryALUOperator::Slt, rest, zero);
+        let mask = push_alu(func, out, BinaryALUOperator::Sub, zero, bit);
+        rest = push_alu(func, out, BinaryALUOperator::Add, rest, rest);
+        let addend = push_alu(func, out, BinaryALUOperator::And, arg1, mask);
+        let doubled = push_alu(func, out, BinaryALUOperator::Add, acc, acc);
+        acc = push_alu(func, out, BinaryALUOperator::Add, doubled, addend);
+    }
+}
+
+// doubles the value once per step, but only on the steps below the shift amount if it is not constant
+fn lowered_shift_left(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    zero: VirtualRegister,
+    value: VirtualRegister,
+    amount_reg: VirtualRegister,
+    amount: Option<i64>,
+) {
+    if let Some(amount) = amount {
+        if amount == 0 {
+            out.push(unary_alu(func, UnaryALUOperator::Copy, value).0);
+        }
+        let mut acc = value;
+        for _ in 0..amount {
+            acc = push_alu(func, out, BinaryALUOperator::Add, acc, acc);
+        }
+        return;
+    }
+    let low_bits = push_constant(func, out, 63);
+    let amount = push_alu(func, out, BinaryALUOperator::And, amount_reg, low_bits);
+    let mut acc = value;
+    let mut step = zero;
+    for _ in 0..63 {
+        let taken = push_alu(func, out, BinaryALUOperator::Sltu, step, amount);
+        let mask = push_alu(func, out, BinaryALUOperator::Sub, zero, taken);
+        let doubled = push_alu(func, out, BinaryALUOperator::Add, acc, acc);
+        let inst;
+        (inst, step) = unary_alu(func, UnaryALUOperator::Inc1, step);
+        out.push(inst);
+        acc = push_select(func, out, mask, doubled, acc);
+    }
+}
+
+// brings down the top 64 - amount bits of the value one at a time, starting from the sign bits of the
+// value (for an arithmetic shift) or from zero. If the amount is not constant, every step is taken,
+// but only the steps below 64 - amount keep their result
+fn lowered_shift_right(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    zero: VirtualRegister,
+    value: VirtualRegister,
+    amount_reg: VirtualRegister,
+    amount: Option<i64>,
+    arithmetic: bool,
+) {
+    let mut acc = if arithmetic {
+        let sign = push_alu(func, out, BinaryALUOperator::Slt, value, zero);
+        push_alu(func, out, BinaryALUOperator::Sub, zero, sign)
+    } else {
+        zero
+    };
+    let steps = match amount {
+        Some(amount) => 64 - amount,
+        None => 64,
+    };
+    let kept = amount.is_none().then(|| {
+        let low_bits = push_constant(func, out, 63);
+        let width = push_constant(func, out, 64);
+        let amount = push_alu(func, out, BinaryALUOperator::And, amount_reg, low_bits);
+        push_alu(func, out, BinaryALUOperator::Sub, width, amount)
+    });
+    let mut rest = value;
+    let mut step = zero;
+    for _ in 0..steps {
+        let bit = push_alu(func, out, BinaryALUOperator::Slt, rest, zero);
+        rest = push_alu(func, out, BinaryALUOperator::Add, rest, rest);
+        let doubled = push_alu(func, out, BinaryALUOperator::Add, acc, acc);
+        let shifted = push_alu(func, out, BinaryALUOperator::Add, doubled, bit);
+        acc = match kept {
+            Some(kept) => {
+                let taken = push_alu(func, out, BinaryALUOperator::Sltu, step, kept);
+                let mask = push_alu(func, out, BinaryALUOperator::Sub, zero, taken);
+                let inst;
+                (inst, step) = unary_alu(func, UnaryALUOperator::Inc1, step);
+                out.push(inst);
+                push_select(func, out, mask, shifted, acc)
+            }
+            None => shifted,
+        };
     }
 }
 
-fn lower_to_microcode(_func: &SSABlock) {}
+// restoring division, bringing down the bits of the dividend from the top. Dividing by zero sets
+// every bit of the quotient and leaves the dividend as the remainder, as required.
+// Returns the quotient and remainder, and the requested one is computed last
+fn lowered_unsigned_division(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    zero: VirtualRegister,
+    dividend: VirtualRegister,
+    divisor: VirtualRegister,
+    remainder_last: bool,
+) -> (VirtualRegister, VirtualRegister) {
+    let one = push_constant(func, out, 1);
+    let mut quotient = zero;
+    let mut remainder = zero;
+    let mut rest = dividend;
+    for _ in 0..64 {
+        // if doubling the remainder overflows, it certainly exceeds the divisor
+        let overflow = push_alu(func, out, BinaryALUOperator::Slt, remainder, zero);
+        let bit = push_alu(func, out, BinaryALUOperator::Slt, rest, zero);
+        rest = push_alu(func, out, BinaryALUOperator::Add, rest, rest);
+        let doubled = push_alu(func, out, BinaryALUOperator::Add, remainder, remainder);
+        let brought_down = push_alu(func, out, BinaryALUOperator::Add, doubled, bit);
+        let below = push_alu(func, out, BinaryALUOperator::Sltu, brought_down, divisor);
+        let not_below = push_alu(func, out, BinaryALUOperator::Xor, below, one);
+        let fits = push_alu(func, out, BinaryALUOperator::Or, not_below, overflow);
+        let mask = push_alu(func, out, BinaryALUOperator::Sub, zero, fits);
+        let subtrahend = push_alu(func, out, BinaryALUOperator::And, divisor, mask);
+        let quotient_doubled = push_alu(func, out, BinaryALUOperator::Add, quotient, quotient);
+        if remainder_last {
+            quotient = push_alu(func, out, BinaryALUOperator::Add, quotient_doubled, fits);
+            remainder = push_alu(func, out, BinaryALUOperator::Sub, brought_down, subtrahend);
+        } else {
+            remainder = push_alu(func, out, BinaryALUOperator::Sub, brought_down, subtrahend);
+            quotient = push_alu(func, out, BinaryALUOperator::Add, quotient_doubled, fits);
+        }
+    }
+    (quotient, remainder)
+}
+
+// divides the magnitudes, then negates the quotient if exactly one operand is negative (unless
+// dividing by zero, which always yields -1), and gives the remainder the sign of the dividend
+fn lowered_signed_division(
+    func: &mut Function<MicrocodeConfig>,
+    out: &mut Vec<LoweredInstruction>,
+    zero: VirtualRegister,
+    dividend: VirtualRegister,
+    divisor: VirtualRegister,
+    remainder: bool,
+) {
+    // negating x under a mask m of all ones is (x ^ m) - m, as in two's complement
+    let negate_if = |func: &mut Function<MicrocodeConfig>, out: &mut Vec<_>, value, mask| {
+        let flipped = push_alu(func, out, BinaryALUOperator::Xor, value, mask);
+        push_alu(func, out, BinaryALUOperator::Sub, flipped, mask)
+    };
+    let dividend_negative = push_alu(func, out, BinaryALUOperator::Slt, dividend, zero);
+    let dividend_mask = push_alu(func, out, BinaryALUOperator::Sub, zero, dividend_negative);
+    let divisor_negative = push_alu(func, out, BinaryALUOperator::Slt, divisor, zero);
+    let divisor_mask = push_alu(func, out, BinaryALUOperator::Sub, zero, divisor_negative);
+    let dividend_magnitude = negate_if(func, out, dividend, dividend_mask);
+    let divisor_magnitude = negate_if(func, out, divisor, divisor_mask);
+    let (quotient, remainder_magnitude) = lowered_unsigned_division(
+        func,
+        out,
+        zero,
+        dividend_magnitude,
+        divisor_magnitude,
+        false,
+    );
+    if remainder {
+        negate_if(func, out, remainder_magnitude, dividend_mask);
+    } else {
+        let signs_differ = push_alu(
+            func,
+            out,
+            BinaryALUOperator::Xor,
+            dividend_negative,
+            divisor_negative,
+        );
+        let nonzero = push_alu(func, out, BinaryALUOperator::Sltu, zero, divisor);
+        let negative = push_alu(func, out, BinaryALUOperator::And, signs_differ, nonzero);
+        let mask = push_alu(func, out, BinaryALUOperator::Sub, zero, negative);
+        negate_if(func, out, quotient, mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{lowered_insts, LoweredInstructionRHS};
+    use crate::backend::microcode::lower::MicrocodeConfig;
+    use crate::ir::{Function, Instruction, SSAInstructionRHS, VirtualRegister};
+    use crate::semantics::{BinaryOperator, IntType};
+
+    const VALUES: [i64; 12] = [0, 1, 2, 3, 7, 63, 64, -1, -2, -7, i64::MAX, i64::MIN];
+
+    // lowers a single operation, and runs the straight-line microcode that it produces
+    fn run(operator: BinaryOperator, width: IntType, arg1: i64, arg2: i64, constant: bool) -> i64 {
+        let mut func = Function::<MicrocodeConfig>::new();
+        let arg1_reg = func.new_reg().0;
+        let arg2_reg = func.new_reg().0;
+        let out = func.new_reg();
+        let out_reg = out.0;
+        let constants = if constant {
+            HashMap::from([(arg2_reg, arg2)])
+        } else {
+            HashMap::new()
+        };
+        let inst = Instruction::new(
+            out,
+            SSAInstructionRHS::BinaryOperation {
+                operator,
+                width,
+                arg1: arg1_reg,
+                arg2: arg2_reg,
+            },
+        );
+        let mut regs = HashMap::<VirtualRegister, i64>::from([(arg1_reg, arg1), (arg2_reg, arg2)]);
+        for inst in lowered_insts(&mut func, inst, &mut 0, &constants) {
+            let value = match inst.rhs {
+                LoweredInstructionRHS::LoadOneImmediate => 1,
+                LoweredInstructionRHS::UnaryALU { operator, arg } => operator.apply(regs[&arg]),
+                LoweredInstructionRHS::BinaryALU {
+                    operator,
+                    arg1,
+                    arg2,
+                } => operator.apply(regs[&arg1], regs[&arg2]),
+                rhs => panic!("unexpected instruction {rhs}"),
+            };
+            regs.insert(inst.lhs.unwrap().0, value);
+        }
+        regs[&out_reg]
+    }
+
+    fn check(operators: &[BinaryOperator], widths: &[IntType], amounts: &[i64]) {
+        for &operator in operators {
+            for &width in widths {
+                for arg1 in VALUES.map(|value| width.wrap(value)) {
+                    for arg2 in amounts.iter().map(|&value| width.wrap(value)) {
+                        let expected = operator.apply(width, arg1, arg2);
+                        for constant in [false, true] {
+                            assert_eq!(
+                                run(operator, width, arg1, arg2, constant),
+                                expected,
+                                "{arg1} {operator:?} {arg2} at {width}, constant: {constant}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    const WIDTHS: [IntType; 4] = [
+        IntType::I64,
+        IntType::U64,
+        IntType {
+            signed: true,
+            bits: 8,
+        },
+        IntType {
+            signed: false,
+            bits: 16,
+        },
+    ];
+
+    #[test]
+    fn multiplication_and_division() {
+        check(
+            &[
+                BinaryOperator::Mul,
+                BinaryOperator::Div,
+                BinaryOperator::Rem,
+                BinaryOperator::Divu,
+                BinaryOperator::Remu,
+            ],
+            &WIDTHS,
+            &VALUES,
+        );
+    }
+
+    #[test]
+    fn shifts() {
+        check(
+            &[
+                BinaryOperator::Shl,
+                BinaryOperator::Shr,
+                BinaryOperator::Shru,
+            ],
+            &WIDTHS,
+            &[0, 1, 5, 31, 63, 64, 65, -1],
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        // VALUES covers i64::MIN, -1 and 0, and pairs each value with itself
+        check(
+            &[
+                BinaryOperator::Eq,
+                BinaryOperator::Ne,
+                BinaryOperator::Lt,
+                BinaryOperator::Le,
+                BinaryOperator::Gt,
+                BinaryOperator::Ge,
+                BinaryOperator::Ltu,
+                BinaryOperator::Leu,
+                BinaryOperator::Gtu,
+                BinaryOperator::Geu,
+            ],
+            &WIDTHS,
+            &VALUES,
+        );
+        // -1 is below 0 when signed, but above every other value when unsigned
+        let compare = |operator, arg1, arg2| run(operator, IntType::I64, arg1, arg2, false);
+        assert_eq!(compare(BinaryOperator::Lt, -1, 0), 1);
+        assert_eq!(compare(BinaryOperator::Ltu, -1, 0), 0);
+        assert_eq!(compare(BinaryOperator::Lt, i64::MIN, -1), 1);
+        assert_eq!(compare(BinaryOperator::Ltu, i64::MIN, -1), 1);
+        assert_eq!(compare(BinaryOperator::Lt, i64::MAX, i64::MIN), 0);
+        assert_eq!(compare(BinaryOperator::Ltu, i64::MAX, i64::MIN), 1);
+        // their difference wraps to 0 only when the operands are equal
+        assert_eq!(compare(BinaryOperator::Eq, i64::MIN, i64::MIN), 1);
+        assert_eq!(compare(BinaryOperator::Eq, i64::MIN, i64::MAX), 0);
+        assert_eq!(compare(BinaryOperator::Ne, i64::MIN, 0), 1);
+        assert_eq!(compare(BinaryOperator::Ne, -1, -1), 0);
+    }
+}
@@ -1,9 +1,13 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
 use super::instructions::LoweredInstructionRHS;
 use crate::backend::lower_func::lower;
 use crate::backend::microcode::instructions::lowered_insts;
 use crate::ir::{
-    CfgConfig, FullBlock, Function, JumpInstruction, SSAFunction, SSAJumpInstruction,
-    VirtualRegister, VirtualRegisterLValue,
+    arity, CfgConfig, FullBlock, Function, JumpInstruction, SSAFunction, SSAInstructionRHS,
+    SSAJumpInstruction, VirtualRegister, VirtualRegisterLValue,
 };
 use crate::utils::rcequality::RcDereferencable;
 
@@ -24,10 +28,28 @@ impl CfgConfig for MicrocodeConfig {
 }
 
 pub fn lower_func(func: SSAFunction) -> Function<MicrocodeConfig> {
-    let mut input_cnt = 0;
+    // arguments are passed in the first registers, and (input)s are read from the ones after them
+    let mut input_cnt = arity(&func) as u8;
+    // shifts by a constant amount can be lowered more cheaply than shifts by a variable one
+    let constants = func
+        .blocks()
+        .flat_map(|block| {
+            block
+                .borrow()
+                .instructions
+                .iter()
+                .filter_map(|inst| match (&inst.lhs, &inst.rhs) {
+                    (Some(lhs), SSAInstructionRHS::LoadIntegerLiteral { value }) => {
+                        Some((lhs.0, *value))
+                    }
+                    _ => None,
+                })
+                .collect_vec()
+        })
+        .collect::<HashMap<_, _>>();
     lower(
         func,
-        |func, _, inst| lowered_insts(func, inst, &mut input_cnt),
+        |func, _, inst| lowered_insts(func, inst, &mut input_cnt, &constants),
         |_, block_lookup, jmp| {
             (
                 vec![],
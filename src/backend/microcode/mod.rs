@@ -1,4 +1,5 @@
-use std::cell::{Ref, RefCell};
+use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::iter::empty;
 
@@ -10,8 +11,8 @@ use super::lower_func::lower;
 use super::register_coloring::{
     build_register_graph, color_registers, PhysicalRegister, RegisterAllocation,
 };
-use super::register_liveness::find_liveness;
-use crate::ir::{CfgConfig, FullBlock, Instruction, SSAFunction};
+use super::register_liveness::{clobbered_at, find_liveness};
+use crate::ir::{CfgConfig, FullBlock, Function, Instruction, SSAFunction, VirtualRegisterLValue};
 use crate::utils::rcequality::RcDereferencable;
 
 mod instructions;
@@ -27,23 +28,97 @@ impl CfgConfig for AllocatedMicrocodeConfig {
     type BlockType = FullBlock<Self>;
 }
 
-pub fn lower_to_microcode(func: SSAFunction) {
+pub fn lower_to_microcode(func: SSAFunction) -> Function<AllocatedMicrocodeConfig> {
     let lowered_func = lower_func(func);
     let register_lifetimes = lowered_func
         .blocks()
         .flat_map(|block| {
             empty()
                 .chain(block.borrow().phis.iter().map(|phi| phi.dest.0))
-                .chain(block.borrow().instructions.iter().map(|inst| inst.lhs.0))
+                .chain(
+                    block
+                        .borrow()
+                        .instructions
+                        .iter()
+                        .filter_map(|inst| inst.lhs.as_ref().map(|lhs| lhs.0)),
+                )
                 .collect_vec()
         })
         .map(|reg| (reg, find_liveness(&lowered_func, reg)))
         .collect::<HashMap<_, _>>();
 
+    // the callee may overwrite every physical register, so each register live across a call is
+    // saved to a register slot before the call and restored from it afterwards
+    let mut saved_across = HashMap::<_, (_, Vec<_>)>::new();
+    for (reg, lifetime) in &register_lifetimes {
+        for (block, index) in clobbered_at(lifetime) {
+            saved_across
+                .entry(block.as_key())
+                .or_insert_with(|| (block, vec![]))
+                .1
+                .push((index, *reg));
+        }
+    }
+
     let register_conflicts = build_register_graph(&register_lifetimes);
     let register_allocation = color_registers(&register_conflicts, 2);
 
-    let spilled_pos = RefCell::new(HashMap::new());
+    // params and (input)s are read from the first register slots, so spills and saves go after them
+    let first_slot = lowered_func
+        .blocks()
+        .flat_map(|block| {
+            block
+                .borrow()
+                .instructions
+                .iter()
+                .filter_map(|inst| match inst.rhs {
+                    LoweredInstructionRHS::LoadRegister(index) => Some(index + 1),
+                    _ => None,
+                })
+                .collect_vec()
+        })
+        .max()
+        .unwrap_or(0);
+    let slots = RefCell::new(HashMap::new());
+    let slot = |vreg| {
+        let mut slots = slots.borrow_mut();
+        let next_slot = first_slot + slots.len() as u8;
+        *slots.entry(vreg).or_insert(next_slot)
+    };
+
+    for (block, mut saves) in saved_across.into_values() {
+        // spilled registers already live in their slots
+        saves
+            .retain(|(_, reg)| matches!(register_allocation[reg], RegisterAllocation::Register(_)));
+        // inserting from the back keeps the indices of earlier calls valid
+        saves.sort_by_key(|&(index, reg)| (Reverse(index), reg));
+        let instructions = &mut block.borrow_mut().instructions;
+        let calls = saves.into_iter().group_by(|&(index, _)| index);
+        for (index, saves) in &calls {
+            let regs = saves.map(|(_, reg)| reg).collect_vec();
+            // every reg is restored after the call, which reassigns it, but that is fine now
+            // that it has been allocated a register
+            instructions.splice(
+                index + 1..index + 1,
+                regs.iter().map(|&reg| {
+                    Instruction::new(
+                        VirtualRegisterLValue(reg),
+                        LoweredInstructionRHS::LoadRegister(slot(reg)),
+                    )
+                }),
+            );
+            instructions.splice(
+                index..index,
+                regs.iter().map(|&reg| Instruction {
+                    lhs: None,
+                    rhs: LoweredInstructionRHS::StoreRegister {
+                        index: slot(reg),
+                        value: reg,
+                    },
+                }),
+            );
+        }
+    }
 
     // todo: handle writebacks to spilled
     // todo: handle multiple temps due to spills
@@ -55,24 +130,22 @@ pub fn lower_to_microcode(func: SSAFunction) {
             RegisterAllocation::Register(reg) => reg,
             RegisterAllocation::Spilled => {
                 let out = PhysicalRegister { index: 0 };
-                let next_offset = spilled_pos.borrow_mut().len() as u8;
-                let index = *spilled_pos.borrow_mut().entry(vreg).or_insert(next_offset);
-                prelude.push(Instruction {
-                    lhs: out,
-                    rhs: instructions::LoweredInstructionRHS::LoadRegister(index),
-                });
+                prelude.push(Instruction::new(
+                    out,
+                    instructions::LoweredInstructionRHS::LoadRegister(slot(vreg)),
+                ));
                 out
             }
         };
 
-    let allocated_func = lower(
+    lower(
         lowered_func,
         |_, _blocks, inst| {
             let mut prelude = vec![];
             let rhs = inst
                 .rhs
                 .allocate_registers(|reg| read_register(reg, &mut prelude));
-            let lhs = read_register(inst.lhs.0, &mut prelude);
+            let lhs = inst.lhs.map(|lhs| read_register(lhs.0, &mut prelude));
             prelude.push(Instruction { lhs, rhs });
             prelude
         },
@@ -88,8 +161,168 @@ pub fn lower_to_microcode(func: SSAFunction) {
         },
         |lvalue| read_register(lvalue.0, &mut vec![]), // fixme spills
         |rvalue| read_register(rvalue, &mut vec![]),   // fixme spills
-    );
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        lower_to_microcode, AllocatedMicrocodeConfig, LoweredInstructionRHS, PhysicalRegister,
+    };
+    use crate::ir::{gen_test_ir, Function, JumpInstruction};
+    use crate::optimizations::optimize;
+    use crate::utils::rcequality::RcDereferencable;
 
-    for block in allocated_func.blocks() {
-        println!("{}", block.borrow());
-    }}
+    // left in every physical register after a call, which the callee is free to overwrite
+    const CLOBBERED: i64 = 0xBAD;
+
+    // runs lowered code, where each call has its own register slots, starting with its args
+    struct Machine {
+        funcs: HashMap<String, Function<AllocatedMicrocodeConfig>>,
+        memory: HashMap<i64, i64>,
+    }
+
+    impl Machine {
+        fn call(&mut self, name: &str, args: Vec<i64>) -> Option<i64> {
+            let func = &self.funcs[name];
+            let mut slots = (0..).zip(args).collect::<HashMap<u8, _>>();
+            let mut regs = [CLOBBERED; 2];
+            let mut block = func.start_block.clone();
+            let mut prev = None;
+            loop {
+                let next = {
+                    let current = block.borrow();
+                    if let Some(prev) = prev {
+                        let values = current
+                            .phis
+                            .iter()
+                            .map(|phi| (phi.dest.index, regs[phi.srcs[&prev].index as usize]))
+                            .collect::<Vec<_>>();
+                        for (dest, value) in values {
+                            regs[dest as usize] = value;
+                        }
+                    }
+                    for inst in current.instructions.iter() {
+                        let read = |reg: &PhysicalRegister| regs[reg.index as usize];
+                        let value = match &inst.rhs {
+                            LoweredInstructionRHS::UnaryALU { operator, arg } => {
+                                operator.apply(read(arg))
+                            }
+                            LoweredInstructionRHS::BinaryALU {
+                                operator,
+                                arg1,
+                                arg2,
+                            } => operator.apply(read(arg1), read(arg2)),
+                            LoweredInstructionRHS::LoadOneImmediate => 1,
+                            LoweredInstructionRHS::LoadMemory(addr) => {
+                                self.memory.get(&read(addr)).copied().unwrap_or_default()
+                            }
+                            LoweredInstructionRHS::StoreMemory { addr, data } => {
+                                self.memory.insert(read(addr), read(data));
+                                continue;
+                            }
+                            LoweredInstructionRHS::LoadRegister(index) => {
+                                *slots.get(index).unwrap_or_else(|| {
+                                    panic!("R[{index}] is read before it is written")
+                                })
+                            }
+                            LoweredInstructionRHS::StoreRegister { index, value } => {
+                                slots.insert(*index, read(value));
+                                continue;
+                            }
+                            LoweredInstructionRHS::Call { func, args } => {
+                                let args = args.iter().map(read).collect();
+                                let out = self.call(func, args);
+                                regs = [CLOBBERED; 2];
+                                out.unwrap_or(CLOBBERED)
+                            }
+                        };
+                        if let Some(lhs) = inst.lhs {
+                            regs[lhs.index as usize] = value;
+                        }
+                    }
+                    match &current.exit {
+                        JumpInstruction::BranchIfElseZero { pred, conseq, alt } => {
+                            if regs[pred.index as usize] == 0 {
+                                conseq.clone()
+                            } else {
+                                alt.clone()
+                            }
+                        }
+                        JumpInstruction::UnconditionalJump { dest } => dest.clone(),
+                        JumpInstruction::Ret(value) => {
+                            return value.map(|value| regs[value.index as usize]);
+                        }
+                    }
+                };
+                prev = Some(block.as_key());
+                block = next;
+            }
+        }
+    }
+
+    // compiles source to microcode as the compile command does, then runs main, whose
+    // (input)s read the first register slots
+    fn run(source: &str, inputs: &[i64]) -> Option<i64> {
+        let mut program = gen_test_ir(source).unwrap();
+        optimize(&mut program, false, true).unwrap();
+        let funcs = program
+            .funcs
+            .into_iter()
+            .map(|(name, func)| (name, lower_to_microcode(func)))
+            .collect();
+        let mut machine = Machine {
+            funcs,
+            memory: HashMap::new(),
+        };
+        machine.call("main", inputs.to_vec())
+    }
+
+    #[test]
+    fn saves_registers_live_across_calls() {
+        let source = "
+(func (f y) (return (+ y 1)))
+(func (main)
+    (define a (f 1))
+    (return (+ a (f 2))))";
+        assert_eq!(run(source, &[]), Some(5));
+        // a and b are both live across the call, so both must be restored after it
+        let source = "
+(func (f) (return 1))
+(func (main) (define a (input)) (define b (input)) (f) (return (+ a b)))";
+        assert_eq!(run(source, &[3, 4]), Some(7));
+    }
+
+    #[test]
+    fn saves_registers_live_across_nested_calls() {
+        // a is live across both calls, and the result of f across the call to g
+        let source = "
+(func (f x) (return (+ x 1)))
+(func (g x) (return (+ x 2)))
+(func (main) (define a (input)) (return (+ a (f (g a)))))";
+        assert_eq!(run(source, &[10]), Some(23));
+        let source = "
+(func (f x) (return (+ x 1)))
+(func (g x) (return (+ x 2)))
+(func (main) (return (- (f (input)) (g (input)))))";
+        assert_eq!(run(source, &[10, 3]), Some(6));
+        // recursion saves the param of each call in its own register slots
+        let source = "
+(func (sum n) (if n (return 0) (return (+ n (sum (- n 1))))))
+(func (main) (return (sum (input))))";
+        assert_eq!(run(source, &[10]), Some(55));
+    }
+
+    #[test]
+    fn branches_on_zero() {
+        // if takes its consequent when the predicate is 0, whether or not it is a constant
+        let source = "(define x (input)) (return (+ (if x 1 2) (if 0 10 20)))";
+        assert_eq!(run(source, &[0]), Some(11));
+        assert_eq!(run(source, &[5]), Some(12));
+        let source = "(return (if (= (input) (input)) 1 2))";
+        assert_eq!(run(source, &[-1, -1]), Some(2));
+        assert_eq!(run(source, &[-1, 0]), Some(1));
+    }
+}
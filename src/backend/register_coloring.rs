@@ -44,6 +44,8 @@ pub fn build_register_graph<RType>(
 ) -> HashMap<VirtualRegister, HashSet<VirtualRegister>> {
     let mut out = HashMap::<_, HashSet<_>>::new();
     for (reg1, reg1_lifetimes) in register_lifetimes {
+        // registers that are never consumed (e.g. unused call results) still need a color
+        out.entry(*reg1).or_default();
         for (reg2, reg2_lifetimes) in register_lifetimes {
             for (block_ref, reg1_lifetime) in reg1_lifetimes {
                 if let Some(reg2_lifetime) = reg2_lifetimes.get(block_ref) {
@@ -134,7 +136,7 @@ pub fn color_registers(
             .keys()
             .sorted_by_key(|key| colorcounts[key])
             .rev()
-            .take(colorcounts.len() - num_registers),
+            .take(colorcounts.len().saturating_sub(num_registers)),
     );
 
     coloring
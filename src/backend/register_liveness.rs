@@ -62,10 +62,17 @@ pub struct RegisterLiveness<BType> {
     pub until_index: ConsumingPosition<BType>,
 }
 
+pub type RegisterLivenessLookup<BType> =
+    HashMap<RcEquality<Rc<RefCell<BType>>>, RegisterLiveness<BType>>;
+
+pub trait ClobberingInstruction {
+    fn clobbers_registers(&self) -> bool;
+}
+
 pub fn find_liveness<Conf: CfgConfig<BlockType = FullBlock<Conf>>>(
     func: &Function<Conf>,
     reg: Conf::RValue,
-) -> HashMap<RcEquality<Rc<RefCell<FullBlock<Conf>>>>, RegisterLiveness<FullBlock<Conf>>> {
+) -> RegisterLivenessLookup<FullBlock<Conf>> {
     let mut out: HashMap<RcEquality<_>, _> = HashMap::new();
     let mut todo = vec![];
     'blocks: for block in func.blocks() {
@@ -138,7 +145,7 @@ pub fn find_liveness<Conf: CfgConfig<BlockType = FullBlock<Conf>>>(
             }
         }
         for (i, inst) in block.borrow().instructions.iter().enumerate() {
-            if inst.lhs.rvalue() == reg {
+            if inst.lhs.as_ref().map(RegisterLValue::rvalue) == Some(reg) {
                 entry.since_index = DefiningPosition::Instruction(i);
                 continue 'todo;
             }
@@ -153,3 +160,32 @@ pub fn find_liveness<Conf: CfgConfig<BlockType = FullBlock<Conf>>>(
 
     out
 }
+
+// the clobbering instructions (e.g. calls) that a register is live across, as blocks and indices,
+// i.e. those it is defined strictly before and consumed strictly after
+pub fn clobbered_at<Conf: CfgConfig<BlockType = FullBlock<Conf>>>(
+    liveness: &RegisterLivenessLookup<FullBlock<Conf>>,
+) -> Vec<(Rc<RefCell<FullBlock<Conf>>>, usize)>
+where
+    Conf::RHSType: ClobberingInstruction,
+{
+    liveness
+        .iter()
+        .flat_map(|(block, lifetime)| {
+            block
+                .get_ref()
+                .borrow()
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(index, inst)| {
+                    inst.rhs.clobbers_registers()
+                        && lifetime.since_index
+                            < ConsumingPosition::<FullBlock<Conf>>::Instruction(*index)
+                        && DefiningPosition::Instruction(*index) < lifetime.until_index
+                })
+                .map(|(index, _)| (block.get_ref().clone(), index))
+                .collect_vec()
+        })
+        .collect()
+}
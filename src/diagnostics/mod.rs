@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::Result;
+
+// a half-open range of byte offsets into the SourceMap
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    // the index of the macro expansion that produced this, if any, in which case the range is
+    // that of the macro call
+    pub expansion: Option<usize>,
+}
+
+impl Span {
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self {
+            start,
+            end,
+            expansion: None,
+        }
+    }
+
+    // the smallest span covering both self and other
+    pub fn to(self, other: Self) -> Self {
+        Self {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+            expansion: self.expansion.or(other.expansion),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SpannedError {
+    pub span: Span,
+    pub message: String,
+    // printed after the underlined source, e.g. to explain where a macro was expanded
+    pub notes: Vec<String>,
+}
+
+impl SpannedError {
+    pub fn new(span: Span, message: impl Display) -> Self {
+        Self {
+            span,
+            message: message.to_string(),
+            notes: vec![],
+        }
+    }
+}
+
+impl Display for SpannedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for SpannedError {}
+
+// independent errors, which are reported together rather than stopping at the first
+#[derive(Debug)]
+pub struct ErrorList(pub Vec<anyhow::Error>);
+
+impl Display for ErrorList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} errors", self.0.len())
+    }
+}
+
+impl Error for ErrorList {}
+
+// runs every step, even after one fails, and reports all of their errors together
+pub fn collect_errors<T>(results: impl IntoIterator<Item = Result<T>>) -> Result<Vec<T>> {
+    let mut out = vec![];
+    let mut errors = vec![];
+    for result in results {
+        match result {
+            Ok(val) => out.push(val),
+            Err(err) => match err.downcast::<ErrorList>() {
+                Ok(ErrorList(inner)) => errors.extend(inner),
+                Err(err) => errors.push(err),
+            },
+        }
+    }
+    match errors.len() {
+        0 => Ok(out),
+        1 => Err(errors.pop().unwrap()),
+        _ => Err(ErrorList(errors).into()),
+    }
+}
+
+macro_rules! bail_at {
+    ($span:expr, $($arg:tt)*) => {
+        return Err($crate::diagnostics::SpannedError::new($span, format!($($arg)*)).into())
+    };
+}
+
+pub(crate) use bail_at;
+
+pub trait WithSpan<T> {
+    // attaches a span to an error, unless it already has a (more precise) one
+    fn at(self, span: Span) -> Result<T>;
+}
+
+impl<T> WithSpan<T> for Result<T> {
+    fn at(self, span: Span) -> Result<T> {
+        self.map_err(|err| {
+            if err.downcast_ref::<SpannedError>().is_some()
+                || err.downcast_ref::<ErrorList>().is_some()
+            {
+                err
+            } else {
+                SpannedError::new(span, format!("{err:#}")).into()
+            }
+        })
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub contents: String,
+    // the offset of this file's first byte in the SourceMap
+    pub base: usize,
+}
+
+// a macro call, whose span is given to everything that its template produces
+pub struct Expansion {
+    pub span: Span,
+    pub name: String,
+}
+
+// every loaded file is given its own range of offsets, so a Span identifies its file as well
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    expansions: Vec<Expansion>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns the offset to add to positions within this file
+    pub fn add(&mut self, name: String, contents: String) -> usize {
+        // leave a gap so that a span at the very end of one file is not mistaken for the next
+        let base = self
+            .files
+            .last()
+            .map_or(0, |file| file.base + file.contents.len() + 1);
+        self.files.push(SourceFile {
+            name,
+            contents,
+            base,
+        });
+        base
+    }
+
+    pub fn lookup(&self, pos: usize) -> Option<&SourceFile> {
+        self.files.iter().rev().find(|file| file.base <= pos)
+    }
+
+    // returns the index of the expansion, to mark the spans it produces with
+    pub fn add_expansion(&mut self, expansion: Expansion) -> usize {
+        self.expansions.push(expansion);
+        self.expansions.len() - 1
+    }
+
+    // a note for each macro expansion that produced span, innermost first
+    pub fn expansion_notes(&self, span: Span) -> Vec<String> {
+        let mut out = vec![];
+        let mut expansion = span.expansion;
+        while let Some(index) = expansion {
+            let Expansion { span, name } = &self.expansions[index];
+            out.push(format!("in expansion of macro {}", name));
+            expansion = span.expansion;
+        }
+        out
+    }
+}
+
+// renders an error, with the offending source line and a caret underline if it has a span
+pub fn render_error(err: &anyhow::Error, sources: &SourceMap) -> String {
+    if let Some(ErrorList(errors)) = err.downcast_ref::<ErrorList>() {
+        let mut out = errors
+            .iter()
+            .map(|err| render_error(err, sources))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        out.push_str(&format!(
+            "\n\nerror: aborting due to {} errors",
+            errors.len()
+        ));
+        return out;
+    }
+    match err.downcast_ref::<SpannedError>() {
+        Some(err) => render("error", err, sources),
+        None => format!("error: {err:#}"),
+    }
+}
+
+// warnings are not errors, so they are passed around directly rather than as anyhow::Errors
+pub fn render_warning(warning: &SpannedError, sources: &SourceMap) -> String {
+    render("warning", warning, sources)
+}
+
+fn render(
+    level: &str,
+    SpannedError {
+        span,
+        message,
+        notes,
+    }: &SpannedError,
+    sources: &SourceMap,
+) -> String {
+    let Some(SourceFile {
+        name: filename,
+        contents: source,
+        base,
+    }) = sources.lookup(span.start) else {
+        return format!("{level}: {message}");
+    };
+    let notes = notes.iter().cloned().chain(sources.expansion_notes(*span));
+    let span = Span::new(span.start - base, span.end - base);
+
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let line = &source[line_start..line_end];
+    let line_number = source[..start].matches('\n').count() + 1;
+    let column = source[line_start..start].chars().count() + 1;
+    let underline_len = source[start..span.end.clamp(start, line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    let gutter = " ".repeat(line_number.to_string().len());
+    let mut out = format!(
+        "{level}: {message}\n{gutter}--> {filename}:{line_number}:{column}\n{gutter} |\n{line_number} | {line}\n{gutter} | {}{}",
+        " ".repeat(column - 1),
+        "^".repeat(underline_len)
+    );
+    for note in notes {
+        out.push_str(&format!("\n{gutter} = note: {note}"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{render_error, SourceMap, Span, SpannedError};
+    use crate::semantics::load_program_from_source;
+
+    fn render(span: Span, sources: &SourceMap) -> String {
+        render_error(&SpannedError::new(span, "bad").into(), sources)
+    }
+
+    #[test]
+    fn last_line() {
+        let mut sources = SourceMap::new();
+        sources.add("a.lang".to_string(), "(f)\n(g x)".to_string());
+        assert_eq!(
+            render(Span::new(7, 8), &sources),
+            "error: bad\n --> a.lang:2:4\n  |\n2 | (g x)\n  |    ^"
+        );
+        // the file has no trailing newline, so these spans run up to its very end
+        assert_eq!(
+            render(Span::new(4, 9), &sources),
+            "error: bad\n --> a.lang:2:1\n  |\n2 | (g x)\n  | ^^^^^"
+        );
+        assert_eq!(
+            render(Span::new(9, 9), &sources),
+            "error: bad\n --> a.lang:2:6\n  |\n2 | (g x)\n  |      ^"
+        );
+    }
+
+    #[test]
+    fn imported_file() {
+        let mut sources = SourceMap::new();
+        sources.add("a.lang".to_string(), "(import b)\n".to_string());
+        let base = sources.add(
+            "b.lang".to_string(),
+            "(func (f)\n    (return y))".to_string(),
+        );
+        assert_eq!(
+            render(Span::new(base + 22, base + 23), &sources),
+            "error: bad\n --> b.lang:2:13\n  |\n2 |     (return y))\n  |             ^"
+        );
+    }
+
+    #[test]
+    fn macro_expansion() {
+        let source = "
+(struct P (x))
+(defmacro (field ptr) `(get ,ptr P y))
+(defmacro (sum a b) `(+ (field ,a) ,b))
+(return (sum 1 2))";
+        let mut sources = SourceMap::new();
+        let Err(err) = load_program_from_source(Path::new("a.lang"), source.to_string(), &mut sources) else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            render_error(&err, &sources),
+            "error: struct P has no field y
+ --> a.lang:5:9
+  |
+5 | (return (sum 1 2))
+  |         ^^^^^^^^^
+  = note: in expansion of macro field
+  = note: in expansion of macro sum"
+        );
+    }
+}
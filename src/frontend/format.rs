@@ -0,0 +1,392 @@
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use anyhow::{Context, Result};
+
+use super::lexer::{tokenize, Token};
+use crate::diagnostics::{bail_at, Span};
+
+const MAX_WIDTH: usize = 80;
+const INDENT: usize = 4;
+
+enum NodeKind {
+    // atoms are kept as written, so that e.g. hex and character literals survive
+    Atom(String),
+    List(Vec<Node>),
+    Comment(String),
+}
+
+struct Node {
+    // any quasiquote, unquote and #; shorthands written directly before the datum
+    prefix: String,
+    kind: NodeKind,
+    // whether the source had a blank line before this node
+    blank_before: bool,
+    // whether this follows other code on the same line, for comments
+    trailing: bool,
+}
+
+struct Reader<'a> {
+    source: &'a str,
+    base: usize,
+    tokens: Peekable<IntoIter<(Token, Span)>>,
+    // where the previous token ended
+    prev_end: Option<usize>,
+}
+
+impl Reader<'_> {
+    fn text(&self, span: Span) -> &str {
+        &self.source[span.start - self.base..span.end - self.base]
+    }
+
+    // returns the next token, along with whether it had a blank line before it and whether it
+    // is on the same line as the previous token
+    fn next_token(&mut self) -> Option<(Token, Span, bool, bool)> {
+        let (token, span) = self.tokens.next()?;
+        let (blank_before, trailing) = match self.prev_end {
+            Some(prev_end) => {
+                let newlines = self.source[prev_end - self.base..span.start - self.base]
+                    .matches('\n')
+                    .count();
+                (newlines > 1, newlines == 0)
+            }
+            None => (false, false),
+        };
+        self.prev_end = Some(span.end);
+        Some((token, span, blank_before, trailing))
+    }
+
+    // reads the next node onto the end of out, preceded by any comments between a prefix and
+    // its datum, which are moved in front of the prefix
+    fn read_node(&mut self, out: &mut Vec<Node>) -> Result<()> {
+        let (token, span, blank_before, trailing) =
+            self.next_token().context("input ended unexpectedly")?;
+        let kind = match token {
+            Token::LeftParen => {
+                let mut items = vec![];
+                loop {
+                    match self.tokens.peek() {
+                        Some((Token::RightParen, _)) => {
+                            self.next_token();
+                            break;
+                        }
+                        Some(_) => self.read_node(&mut items)?,
+                        None => bail_at!(span, "unclosed parenthesis"),
+                    }
+                }
+                NodeKind::List(items)
+            }
+            Token::RightParen => bail_at!(span, "unexpected right parenthesis"),
+            Token::DatumComment | Token::Quasiquote | Token::Unquote | Token::UnquoteSplicing => {
+                let prefix = self.text(span).to_string();
+                let start = out.len();
+                loop {
+                    match self.tokens.peek() {
+                        None | Some((Token::RightParen, _)) => {
+                            bail_at!(span, "expected an expression after {}", prefix)
+                        }
+                        Some((Token::Comment, _)) => self.read_node(out)?,
+                        Some(_) => break,
+                    }
+                }
+                self.read_node(out)?;
+                out.last_mut().unwrap().prefix.insert_str(0, &prefix);
+                // the first node takes the place of the prefix
+                for node in &mut out[start..] {
+                    node.blank_before = false;
+                    node.trailing = false;
+                }
+                out[start].blank_before = blank_before;
+                out[start].trailing = trailing;
+                return Ok(());
+            }
+            Token::Comment => NodeKind::Comment(self.text(span).trim_end().to_string()),
+            Token::Integer(_) | Token::Symbol(_) | Token::String(_) => {
+                NodeKind::Atom(self.text(span).to_string())
+            }
+        };
+        out.push(Node {
+            prefix: String::new(),
+            kind,
+            blank_before,
+            trailing,
+        });
+        Ok(())
+    }
+}
+
+// how a list is laid out when it does not fit on one line
+struct Layout {
+    // the number of operands kept on the same line as the operator
+    headers: usize,
+    // bodies of functions and loops are always given their own lines
+    always_break: bool,
+    // calls may keep everything but their last operand on the first line
+    hang: bool,
+}
+
+impl Layout {
+    fn of(items: &[Node]) -> Self {
+        let operator = match items.first() {
+            Some(Node {
+                kind: NodeKind::Atom(operator),
+                prefix,
+                ..
+            }) if prefix.is_empty() => operator.as_str(),
+            _ => "",
+        };
+        let label = match items.get(1) {
+            Some(Node {
+                kind: NodeKind::Atom(keyword),
+                ..
+            }) if keyword == ":label" => 2,
+            _ => 0,
+        };
+        let (headers, always_break, hang) = match operator {
+            "func" | "defmacro" => (1, true, false),
+            "loop" => (label, true, false),
+            "while" | "for" => (label + 1, true, false),
+            "cond" => (0, true, false),
+            "if" => (1, false, false),
+            "begin" => (0, false, false),
+            _ => (0, false, true),
+        };
+        Layout {
+            headers,
+            always_break,
+            hang,
+        }
+    }
+}
+
+fn is_comment(node: &Node) -> bool {
+    matches!(node.kind, NodeKind::Comment(_))
+}
+
+// the node on a single line, if it can be written that way
+fn flat(node: &Node) -> Option<String> {
+    match &node.kind {
+        NodeKind::Atom(text) => Some(format!("{}{}", node.prefix, text)),
+        NodeKind::List(items) => {
+            if Layout::of(items).always_break {
+                return None;
+            }
+            let items = items.iter().map(flat).collect::<Option<Vec<_>>>()?;
+            Some(format!("{}({})", node.prefix, items.join(" ")))
+        }
+        // block comments can sit between other items, unless they span several lines
+        NodeKind::Comment(text) if text.starts_with("#|") && !text.contains('\n') => {
+            Some(text.clone())
+        }
+        NodeKind::Comment(_) => None,
+    }
+}
+
+struct Printer {
+    out: String,
+}
+
+impl Printer {
+    // widths are counted in characters rather than bytes, so that non-ASCII symbols are not
+    // broken onto their own lines early
+    fn column(&self) -> usize {
+        let line_start = self.out.rfind('\n').map_or(0, |i| i + 1);
+        self.out[line_start..].chars().count()
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        self.column() + text.chars().count() <= MAX_WIDTH
+    }
+
+    fn newline(&mut self, indent: usize, blank: bool) {
+        if blank {
+            self.out.push('\n');
+        }
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(indent));
+    }
+
+    // prints node starting at the current position, where indent is that of the current line
+    fn print(&mut self, node: &Node, indent: usize) {
+        if let Some(flat) = flat(node) {
+            if self.fits(&flat) {
+                self.out.push_str(&flat);
+                return;
+            }
+        }
+        self.out.push_str(&node.prefix);
+        let items = match &node.kind {
+            NodeKind::Atom(text) | NodeKind::Comment(text) => {
+                self.out.push_str(text);
+                return;
+            }
+            NodeKind::List(items) => items,
+        };
+        let layout = Layout::of(items);
+
+        if let (true, Some((last, rest))) = (layout.hang, items.split_last()) {
+            let rest = rest.iter().map(flat).collect::<Option<Vec<_>>>();
+            if let (NodeKind::List(_), Some(rest)) = (&last.kind, rest) {
+                let line = format!("({} ", rest.join(" "));
+                if !rest.is_empty() && self.fits(&line) {
+                    self.out.push_str(&line);
+                    self.print(last, indent);
+                    self.out.push(')');
+                    return;
+                }
+            }
+        }
+
+        self.out.push('(');
+        let mut first_line = true;
+        for (i, item) in items.iter().enumerate() {
+            if is_comment(item) && item.trailing {
+                self.out.push(' ');
+                self.print(item, indent);
+                // anything after a line comment must go on the next line
+                if flat(item).is_none() {
+                    first_line = false;
+                }
+            } else if !is_comment(item) && (i == 0 || (first_line && i <= layout.headers)) {
+                if i > 0 {
+                    self.out.push(' ');
+                }
+                self.print(item, indent);
+            } else {
+                first_line = false;
+                self.newline(indent + INDENT, item.blank_before);
+                self.print(item, indent + INDENT);
+            }
+        }
+        self.newline(indent, false);
+        self.out.push(')');
+    }
+}
+
+// reformats a whole source file, keeping its comments and at most one blank line between
+// expressions. base locates the source within a SourceMap, for errors
+pub fn format_source(source: &str, base: usize) -> Result<String> {
+    let tokens = tokenize(&mut source.char_indices().map(|(i, c)| (base + i, c)).peekable())?;
+    let mut reader = Reader {
+        source,
+        base,
+        tokens: tokens.into_iter().peekable(),
+        prev_end: None,
+    };
+    let mut nodes = vec![];
+    while reader.tokens.peek().is_some() {
+        reader.read_node(&mut nodes)?;
+    }
+
+    let mut printer = Printer { out: String::new() };
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            if is_comment(node) && node.trailing {
+                printer.out.push(' ');
+            } else {
+                printer.newline(0, node.blank_before);
+            }
+        }
+        printer.print(node, 0);
+    }
+    if !nodes.is_empty() {
+        printer.out.push('\n');
+    }
+    Ok(printer.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+
+    fn format(source: &str) -> String {
+        format_source(source, 0).unwrap()
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let source = "\
+; a line comment
+(func (main x) ; after the header
+    #| a block comment |# (define y x)
+
+
+    #;(set y 1)
+    (return y) ; the end
+)
+";
+        let expected = "\
+; a line comment
+(func (main x) ; after the header
+    #| a block comment |#
+    (define y x)
+
+    #;(set y 1)
+    (return y) ; the end
+)
+";
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn comment_after_datum_comment() {
+        let source = "(func (main)\n    #; ; the old version\n    (return 1)\n    (return 2))\n";
+        let expected = "(func (main)\n    ; the old version\n    #;(return 1)\n    (return 2)\n)\n";
+        assert_eq!(format(source), expected);
+        let source = "(+ 1 #; #| two |# 2 3)\n";
+        assert_eq!(format(source), "(+ 1 #| two |# #;2 3)\n");
+    }
+
+    #[test]
+    fn inline_block_comments() {
+        let source = "(func (main x)\n    (define y (+ x #| one |# 1)) (return y) #| end |#)\n";
+        let expected =
+            "(func (main x)\n    (define y (+ x #| one |# 1))\n    (return y) #| end |#\n)\n";
+        assert_eq!(format(source), expected);
+        // a block comment spanning several lines still gets lines of its own
+        let source = "(+ 1\n#| two\n three |# 2)\n";
+        assert_eq!(
+            format(source),
+            "(+\n    1\n    #| two\n three |#\n    2\n)\n"
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        let sources = [
+            include_str!("../../test.lang"),
+            include_str!("../../fib.lang"),
+            include_str!("../../factorial.lang"),
+            include_str!("../../mem.lang"),
+            include_str!("../../cssa_test.lang"),
+            include_str!("../../constant_folding.lang"),
+            include_str!("../../and_not_xor.lang"),
+            "(func (main)\n    #; ; the old version\n    (return 1)\n    (return 2))\n",
+            "(define x (+ 1 #; #| two |# 2 3)) ; trailing\n\n\n#|\nblock\n|#\n(return x)",
+            "(defmacro (twice e) `(begin ,e ,e))\n(twice (write 0 (+ (read 0) 1111111111 2222222222 3333333333 4444444444)))",
+        ];
+        for source in sources {
+            let once = format(source);
+            assert_eq!(
+                format(&once),
+                once,
+                "formatting is not idempotent on:\n{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn non_ascii_width() {
+        // 78 characters, but 138 bytes
+        let source = format!("(f{})\n", " 変数-1".repeat(15));
+        assert_eq!(format(&source), source);
+        // indented by four spaces, it runs to column 82
+        let source = format!("(func (main)\n    (f{})\n)\n", " 変数-1".repeat(15));
+        let expected = format!(
+            "(func (main)\n    (f\n{}    )\n)\n",
+            "        変数-1\n".repeat(15)
+        );
+        assert_eq!(format(&source), expected);
+    }
+}
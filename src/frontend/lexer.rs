@@ -1,50 +1,98 @@
 use std::iter::Peekable;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
+
+use crate::diagnostics::{bail_at, Span};
 
 pub enum Token {
     LeftParen,
     RightParen,
     Symbol(String),
     Integer(i64),
+    String(String),
+    // #; comments out the datum that follows it
+    DatumComment,
+    // a ; line comment or #| |# block comment, which only the formatter keeps
+    Comment,
+    // `x, ,x and ,@x are shorthand for (quasiquote x), (unquote x) and (unquote-splicing x)
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 }
 
-pub fn tokenize(stream: &mut Peekable<impl Iterator<Item = char>>) -> Result<Vec<Token>> {
-    let mut out = vec![];
+const TOKEN_ENDS: &str = "();`,\"";
 
-    let token_ends = "()";
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_graphic() || c.is_alphanumeric()
+}
+
+pub fn tokenize(
+    stream: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+) -> Result<Vec<(Token, Span)>> {
+    let mut out = vec![];
 
     loop {
         // single-char tokens
         match stream.peek() {
-            Some(&'(') => {
+            Some(&(i, '(')) => {
+                stream.next();
+                out.push((Token::LeftParen, Span::new(i, i + 1)));
+            }
+            Some(&(i, ')')) => {
+                stream.next();
+                out.push((Token::RightParen, Span::new(i, i + 1)));
+            }
+            Some(&(i, '`')) => {
                 stream.next();
-                out.push(Token::LeftParen);
+                out.push((Token::Quasiquote, Span::new(i, i + 1)));
             }
-            Some(&')') => {
+            Some(&(i, ',')) => {
                 stream.next();
-                out.push(Token::RightParen);
+                if let Some(&(j, '@')) = stream.peek() {
+                    stream.next();
+                    out.push((Token::UnquoteSplicing, Span::new(i, j + 1)));
+                } else {
+                    out.push((Token::Unquote, Span::new(i, i + 1)));
+                }
             }
-            Some(d) if d.is_whitespace() => {
+            Some((_, d)) if d.is_whitespace() => {
                 stream.next();
             }
-            Some(d) if d.is_ascii() => {
-                let mut s = String::new();
-                while let Some(d) = stream.peek() {
-                    if token_ends.contains(*d) || d.is_whitespace() {
+            Some(&(start, ';')) => {
+                let mut end = start;
+                while let Some(&(i, d)) = stream.peek() {
+                    if d == '\n' {
                         break;
                     }
-                    s.push(*d);
+                    end = i + d.len_utf8();
                     stream.next();
                 }
-                if let Ok(val) = s.parse() {
-                    out.push(Token::Integer(val));
-                } else {
-                    out.push(Token::Symbol(s));
+                out.push((Token::Comment, Span::new(start, end)));
+            }
+            Some(&(start, '#')) => {
+                stream.next();
+                match stream.peek() {
+                    Some(&(i, '|')) => {
+                        stream.next();
+                        let end = skip_block_comment(stream, Span::new(start, i + 1))?;
+                        out.push((Token::Comment, Span::new(start, end)));
+                    }
+                    Some(&(i, ';')) => {
+                        stream.next();
+                        out.push((Token::DatumComment, Span::new(start, i + 1)));
+                    }
+                    _ => out.push(read_atom(stream, start, "#".to_string())?),
                 }
             }
-            Some(d) => {
-                bail!("invalid character {}", d)
+            Some(&(start, '"')) => {
+                stream.next();
+                out.push(read_string(stream, start)?);
+            }
+            Some(&(start, d)) if is_symbol_char(d) => {
+                out.push(read_atom(stream, start, String::new())?);
+            }
+            Some(&(i, d)) => {
+                bail_at!(Span::new(i, i + d.len_utf8()), "invalid character {}", d)
             }
             None => {
                 break;
@@ -54,3 +102,243 @@ pub fn tokenize(stream: &mut Peekable<impl Iterator<Item = char>>) -> Result<Vec
 
     Ok(out)
 }
+
+// block comments can be nested, so #| #| |# |# is a single comment. Returns the end of the comment
+fn skip_block_comment(
+    stream: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    start: Span,
+) -> Result<usize> {
+    let mut depth = 1;
+    let mut end = start.end;
+    while depth > 0 {
+        match stream.next() {
+            Some((_, '|')) if matches!(stream.peek(), Some((_, '#'))) => {
+                let (i, _) = stream.next().unwrap();
+                depth -= 1;
+                end = i + 1;
+            }
+            Some((_, '#')) if matches!(stream.peek(), Some((_, '|'))) => {
+                stream.next();
+                depth += 1;
+            }
+            Some(_) => {}
+            None => bail_at!(start, "unterminated block comment"),
+        }
+    }
+    Ok(end)
+}
+
+// the opening quote has already been consumed. Only \" and \\ are escaped
+fn read_string(
+    stream: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    start: usize,
+) -> Result<(Token, Span)> {
+    let mut s = String::new();
+    loop {
+        match stream.next() {
+            Some((i, '"')) => break Ok((Token::String(s), Span::new(start, i + 1))),
+            Some((i, '\\')) => match stream.next() {
+                Some((_, d @ ('"' | '\\'))) => s.push(d),
+                Some((j, d)) => {
+                    bail_at!(
+                        Span::new(i, j + d.len_utf8()),
+                        "invalid escape sequence \\{}",
+                        d
+                    )
+                }
+                None => bail_at!(Span::new(start, i + 1), "unterminated string literal"),
+            },
+            Some((_, d)) => s.push(d),
+            None => bail_at!(Span::new(start, start + 1), "unterminated string literal"),
+        }
+    }
+}
+
+// reads a symbol or literal, whose first chars (if any) have already been consumed into s
+fn read_atom(
+    stream: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    start: usize,
+    mut s: String,
+) -> Result<(Token, Span)> {
+    let mut end = start + s.len();
+    while let Some(&(i, d)) = stream.peek() {
+        // character literals like #\( may consist of any single char
+        if s != "#\\" {
+            if TOKEN_ENDS.contains(d) || d.is_whitespace() {
+                break;
+            }
+            if !is_symbol_char(d) {
+                bail_at!(Span::new(i, i + d.len_utf8()), "invalid character {}", d)
+            }
+        }
+        s.push(d);
+        end = i + d.len_utf8();
+        stream.next();
+    }
+    let span = Span::new(start, end);
+    Ok(if let Some(val) = parse_integer(&s, span)? {
+        (Token::Integer(val), span)
+    } else {
+        (Token::Symbol(s), span)
+    })
+}
+
+// returns None if the token is a symbol rather than a numeric or character literal
+fn parse_integer(s: &str, span: Span) -> Result<Option<i64>> {
+    if let Some(name) = s.strip_prefix("#\\") {
+        let mut chars = name.chars();
+        return Ok(Some(match (chars.next(), chars.next()) {
+            (Some(c), None) => c as i64,
+            _ => match name {
+                "space" => ' ' as i64,
+                "newline" => '\n' as i64,
+                "tab" => '\t' as i64,
+                "nul" => 0,
+                _ => bail_at!(span, "invalid character literal {}", s),
+            },
+        }));
+    }
+
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    if !unsigned.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+
+    let (radix, digits) = if let Some(digits) = unsigned.strip_prefix("0x") {
+        (16, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0b") {
+        (2, digits)
+    } else {
+        (10, unsigned)
+    };
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        bail_at!(span, "malformed integer literal {}", s)
+    }
+
+    let val = if negative {
+        i64::from_str_radix(&format!("-{digits}"), radix).ok()
+    } else if radix == 10 {
+        digits.parse().ok()
+    } else {
+        // hex and binary literals may spell out any 64-bit pattern
+        u64::from_str_radix(&digits, radix)
+            .ok()
+            .map(|val| val as i64)
+    };
+    match val {
+        Some(val) => Ok(Some(val)),
+        None => bail_at!(span, "integer literal {} does not fit in 64 bits", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::{tokenize, Token};
+    use crate::frontend::parse;
+
+    fn tokens(source: &str) -> anyhow::Result<Vec<Token>> {
+        let tokens = tokenize(&mut source.char_indices().peekable())?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+
+    fn integer(source: &str) -> i64 {
+        match &tokens(source).unwrap()[..] {
+            [Token::Integer(val)] => *val,
+            _ => panic!("{} is not a single integer", source),
+        }
+    }
+
+    fn error(source: &str) -> String {
+        tokens(source).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn integer_literals() {
+        assert_eq!(integer("1_000"), 1000);
+        assert_eq!(integer("-42"), -42);
+        assert_eq!(integer("0x1F"), 31);
+        assert_eq!(integer("-0x1F"), -31);
+        assert_eq!(integer("0b101"), 5);
+        assert_eq!(integer("0xFFFF_FFFF_FFFF_FFFF"), -1);
+        assert_eq!(integer("-9223372036854775808"), i64::MIN);
+        assert!(matches!(&tokens("-x").unwrap()[..], [Token::Symbol(s)] if s == "-x"));
+        assert!(matches!(&tokens("-").unwrap()[..], [Token::Symbol(s)] if s == "-"));
+    }
+
+    #[test]
+    fn character_literals() {
+        assert_eq!(integer("#\\a"), 'a' as i64);
+        assert_eq!(integer("#\\("), '(' as i64);
+        assert_eq!(integer("#\\λ"), 'λ' as i64);
+        assert_eq!(integer("#\\space"), ' ' as i64);
+        assert_eq!(integer("#\\newline"), '\n' as i64);
+        assert_eq!(integer("#\\tab"), '\t' as i64);
+        assert_eq!(integer("#\\nul"), 0);
+    }
+
+    #[test]
+    fn malformed_literals() {
+        assert_eq!(error("0x"), "malformed integer literal 0x");
+        assert_eq!(error("12ab"), "malformed integer literal 12ab");
+        assert_eq!(error("0b102"), "malformed integer literal 0b102");
+        assert_eq!(error("0x__"), "malformed integer literal 0x__");
+        assert_eq!(
+            error("9223372036854775808"),
+            "integer literal 9223372036854775808 does not fit in 64 bits"
+        );
+        assert_eq!(
+            error("0x1_0000_0000_0000_0000"),
+            "integer literal 0x1_0000_0000_0000_0000 does not fit in 64 bits"
+        );
+        assert_eq!(error("#\\bogus"), "invalid character literal #\\bogus");
+    }
+
+    #[test]
+    fn comments() {
+        let source = "; a line comment\n(a #| outer #| nested |# still |# b) ; trailing";
+        assert!(matches!(
+            &tokens(source).unwrap()[..],
+            [
+                Token::Comment,
+                Token::LeftParen,
+                Token::Symbol(a),
+                Token::Comment,
+                Token::Symbol(b),
+                Token::RightParen,
+                Token::Comment,
+            ] if a == "a" && b == "b"
+        ));
+        assert_eq!(error("(a #| #| |# b)"), "unterminated block comment");
+        // #; removes the whole datum that follows it, comments and all
+        let exprs = parse("(a #; (b #| c |# d) e) #;f g", 0).unwrap();
+        assert_eq!(exprs.iter().join(" "), "(a e) g");
+        assert_eq!(
+            parse("(a #;)", 0).err().unwrap().to_string(),
+            "unexpected right parenthesis"
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let source = "(define λx 変数-1)";
+        assert!(matches!(
+            &tokens(source).unwrap()[..],
+            [
+                Token::LeftParen,
+                Token::Symbol(define),
+                Token::Symbol(lambda),
+                Token::Symbol(var),
+                Token::RightParen,
+            ] if define == "define" && lambda == "λx" && var == "変数-1"
+        ));
+        assert_eq!(error("(a €)"), "invalid character €");
+        assert_eq!(error("a€"), "invalid character €");
+        assert_eq!(error("a→"), "invalid character →");
+    }
+}
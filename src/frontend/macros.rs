@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use super::ParseExpr;
+use crate::diagnostics::{bail_at, Expansion, SourceMap, Span};
+
+struct Macro {
+    params: Box<[String]>,
+    // bound to a list of any arguments after the named params, if declared with &rest
+    rest: Option<String>,
+    // the body of the quasiquoted template, or a lone parameter
+    template: Template,
+    // symbols bound by the template itself, which are renamed on each expansion for hygiene
+    introduced: HashSet<String>,
+}
+
+enum Template {
+    Quasiquote(ParseExpr),
+    Param(String),
+}
+
+fn as_form<'a>(expr: &'a ParseExpr, name: &str) -> Option<&'a ParseExpr> {
+    match expr {
+        ParseExpr::List(lst, _) => match &lst[..] {
+            [ParseExpr::Symbol(operator, _), arg] if operator == name => Some(arg),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_param(name: &str, params: &[String], rest: &Option<String>) -> bool {
+    params.iter().any(|param| param == name) || rest.as_deref() == Some(name)
+}
+
+// the symbol bound by a binder the template spells out itself, rather than unquoting from the caller
+fn introduced_name(binder: &ParseExpr) -> Option<&String> {
+    match binder {
+        ParseExpr::Symbol(name, _) => Some(name),
+        _ => None,
+    }
+}
+
+// checks that only parameters are unquoted, and collects the symbols that the template binds
+fn check_template(
+    expr: &ParseExpr,
+    params: &[String],
+    rest: &Option<String>,
+    introduced: &mut HashSet<String>,
+) -> Result<()> {
+    let ParseExpr::List(lst, _) = expr else {
+        return Ok(());
+    };
+    for form in ["unquote", "unquote-splicing"] {
+        if let Some(arg) = as_form(expr, form) {
+            match arg {
+                ParseExpr::Symbol(name, _) if is_param(name, params, rest) => return Ok(()),
+                _ => bail_at!(arg.span(), "only macro parameters can be unquoted"),
+            }
+        }
+    }
+    let mut operands = lst.iter().skip(1).peekable();
+    match lst.first() {
+        Some(ParseExpr::Symbol(operator, _)) if operator == "define" => {
+            let binder = match operands.peek() {
+                // a typed declaration, (define (name type) value), unless the binder is unquoted
+                Some(binder @ ParseExpr::List(binding, _))
+                    if as_form(binder, "unquote").is_none() =>
+                {
+                    binding.first()
+                }
+                binder => binder.copied(),
+            };
+            if let Some(name) = binder.and_then(introduced_name) {
+                introduced.insert(name.clone());
+            }
+        }
+        Some(ParseExpr::Symbol(operator, _)) if operator == "for" => {
+            // the header may be unquoted as a whole, (for ,header body...)
+            if let Some(header @ ParseExpr::List(header_exprs, _)) =
+                operands.find(|expr| matches!(expr, ParseExpr::List(..)))
+            {
+                if as_form(header, "unquote").is_none() {
+                    if let Some(name) = header_exprs.first().and_then(introduced_name) {
+                        introduced.insert(name.clone());
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    for (expr, next) in lst.iter().tuple_windows() {
+        if let (ParseExpr::Symbol(keyword, _), ParseExpr::Symbol(label, _)) = (expr, next) {
+            if keyword == ":label" {
+                introduced.insert(label.clone());
+            }
+        }
+    }
+    for expr in lst.iter() {
+        check_template(expr, params, rest, introduced)?;
+    }
+    Ok(())
+}
+
+fn analyze_defmacro(operands: &[ParseExpr], span: Span) -> Result<(String, Macro)> {
+    let [ParseExpr::List(signature, _), template] = operands else {
+        bail_at!(span, "macro definitions must be of the form (defmacro (name args...) template)");
+    };
+    let Some((ParseExpr::Symbol(name, _), args)) = signature.split_first() else {
+        bail_at!(span, "macro signatures must begin with the name");
+    };
+
+    let mut params = vec![];
+    let mut rest = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg {
+            ParseExpr::Symbol(keyword, _) if keyword == "&rest" => match (args.next(), args.next())
+            {
+                (Some(ParseExpr::Symbol(name, _)), None) => rest = Some(name.clone()),
+                _ => bail_at!(
+                    arg.span(),
+                    "&rest must be followed by exactly one parameter"
+                ),
+            },
+            ParseExpr::Symbol(name, _) => params.push(name.clone()),
+            _ => bail_at!(arg.span(), "all macro args must be symbols"),
+        }
+    }
+
+    let template = match (as_form(template, "quasiquote"), template) {
+        (Some(body), _) => Template::Quasiquote(body.clone()),
+        (None, ParseExpr::Symbol(name, _)) if is_param(name, &params, &rest) => {
+            Template::Param(name.clone())
+        }
+        _ => bail_at!(
+            template.span(),
+            "macro templates must be quasiquoted expressions or parameters"
+        ),
+    };
+
+    let mut introduced = HashSet::new();
+    if let Template::Quasiquote(body) = &template {
+        check_template(body, &params, &rest, &mut introduced)?;
+    }
+
+    Ok((
+        name.clone(),
+        Macro {
+            params: params.into_boxed_slice(),
+            rest,
+            template,
+            introduced,
+        },
+    ))
+}
+
+struct Expander<'a> {
+    macros: HashMap<String, Rc<Macro>>,
+    // each expansion is recorded here, so that errors in expanded code can name its macros
+    sources: &'a mut SourceMap,
+}
+
+impl Expander<'_> {
+    fn expand(&mut self, expr: ParseExpr, chain: &mut Vec<String>) -> Result<ParseExpr> {
+        let ParseExpr::List(lst, span) = expr else {
+            return Ok(expr);
+        };
+        let lst = lst
+            .into_vec()
+            .into_iter()
+            .map(|expr| self.expand(expr, chain))
+            .collect::<Result<Vec<_>>>()?;
+        let Some((ParseExpr::Symbol(name, _), args)) = lst.split_first() else {
+            return Ok(ParseExpr::List(lst.into_boxed_slice(), span));
+        };
+        let Some(mac) = self.macros.get(name).cloned() else {
+            return Ok(ParseExpr::List(lst.into_boxed_slice(), span));
+        };
+
+        // arguments have already been expanded, so any further use of a macro in the chain
+        // must have come from a template, and would recur forever
+        if let Some(pos) = chain.iter().position(|prev| prev == name) {
+            bail_at!(
+                span,
+                "recursive macro expansion: {} -> {}",
+                chain[pos..].iter().join(" -> "),
+                name
+            );
+        }
+
+        let expansion = self.instantiate(name, &mac, args, span)?;
+        chain.push(name.clone());
+        let expansion = self.expand(expansion, chain)?;
+        chain.pop();
+        Ok(expansion)
+    }
+
+    fn instantiate(
+        &mut self,
+        name: &str,
+        mac: &Macro,
+        args: &[ParseExpr],
+        span: Span,
+    ) -> Result<ParseExpr> {
+        if args.len() < mac.params.len() || (mac.rest.is_none() && args.len() > mac.params.len()) {
+            bail_at!(
+                span,
+                "macro {} takes {}{} arguments, but {} were provided",
+                name,
+                if mac.rest.is_some() { "at least " } else { "" },
+                mac.params.len(),
+                args.len()
+            );
+        }
+
+        let mut bindings = mac
+            .params
+            .iter()
+            .cloned()
+            .zip(args.iter().cloned())
+            .collect::<HashMap<_, _>>();
+        if let Some(rest) = &mac.rest {
+            let rest_args = args[mac.params.len()..].to_vec().into_boxed_slice();
+            bindings.insert(rest.clone(), ParseExpr::List(rest_args, span));
+        }
+
+        let id = self.sources.add_expansion(Expansion {
+            span,
+            name: name.to_string(),
+        });
+        // these names contain a space, so they cannot clash with anything the user writes
+        let renames = mac
+            .introduced
+            .iter()
+            .map(|name| (name.clone(), format!("{name} {id}")))
+            .collect();
+
+        let span = Span {
+            expansion: Some(id),
+            ..span
+        };
+        match &mac.template {
+            Template::Quasiquote(body) => substitute(body, &bindings, &renames, span),
+            Template::Param(name) => Ok(bindings[name].clone()),
+        }
+    }
+}
+
+// everything that comes from the template itself is attributed to the macro call site
+fn substitute(
+    expr: &ParseExpr,
+    bindings: &HashMap<String, ParseExpr>,
+    renames: &HashMap<String, String>,
+    span: Span,
+) -> Result<ParseExpr> {
+    if let Some(ParseExpr::Symbol(param, _)) = as_form(expr, "unquote") {
+        return Ok(bindings[param].clone());
+    }
+    Ok(match expr {
+        ParseExpr::List(lst, _) => {
+            let mut out = vec![];
+            for expr in lst.iter() {
+                if let Some(ParseExpr::Symbol(param, param_span)) =
+                    as_form(expr, "unquote-splicing")
+                {
+                    let ParseExpr::List(spliced, _) = &bindings[param] else {
+                        bail_at!(*param_span, "only lists can be spliced, but {} is not a list", param);
+                    };
+                    out.extend(spliced.iter().cloned());
+                } else {
+                    out.push(substitute(expr, bindings, renames, span)?);
+                }
+            }
+            ParseExpr::List(out.into_boxed_slice(), span)
+        }
+        ParseExpr::Symbol(name, _) => {
+            ParseExpr::Symbol(renames.get(name).unwrap_or(name).clone(), span)
+        }
+        ParseExpr::Integer(val, _) => ParseExpr::Integer(*val, span),
+        ParseExpr::String(val, _) => ParseExpr::String(val.clone(), span),
+    })
+}
+
+// removes all top-level macro definitions, and expands every use of them.
+// Each expansion is recorded in sources, and the spans of what its template produces point to it
+pub fn expand_macros(exprs: Box<[ParseExpr]>, sources: &mut SourceMap) -> Result<Box<[ParseExpr]>> {
+    let mut macros = HashMap::new();
+    let mut out = vec![];
+    for expr in exprs.into_vec() {
+        match &expr {
+            ParseExpr::List(lst, span) if matches!(lst.first(), Some(ParseExpr::Symbol(operator, _)) if operator == "defmacro") =>
+            {
+                let (name, mac) = analyze_defmacro(&lst[1..], *span)?;
+                if macros.insert(name, Rc::new(mac)).is_some() {
+                    bail_at!(*span, "all macros must be uniquely named");
+                }
+            }
+            _ => out.push(expr),
+        }
+    }
+
+    let mut expander = Expander { macros, sources };
+    out.into_iter()
+        .map(|expr| expander.expand(expr, &mut vec![]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::expand_macros;
+    use crate::diagnostics::{render_error, SourceMap};
+    use crate::frontend::parse;
+
+    fn expand(source: &str) -> Result<String, String> {
+        let mut sources = SourceMap::new();
+        let base = sources.add("test.lang".to_string(), source.to_string());
+        parse(source, base)
+            .and_then(|exprs| expand_macros(exprs, &mut sources))
+            .map(|exprs| exprs.iter().join("\n"))
+            .map_err(|err| render_error(&err, &sources))
+    }
+
+    #[test]
+    fn expansion() {
+        let source = "(defmacro (unless c x) `(if ,c 0 ,x)) (defmacro (id x) x) (unless (id 1) 2)";
+        assert_eq!(expand(source).unwrap(), "(if 1 0 2)");
+    }
+
+    #[test]
+    fn splicing() {
+        let source = "(defmacro (call f &rest xs) `(,f 1 ,@xs)) (call g) (call g 2 (h 3))";
+        assert_eq!(expand(source).unwrap(), "(g 1)\n(g 1 2 (h 3))");
+        let err = expand("(defmacro (f &rest xs) `(+ ,@xs)) (defmacro (g x) `(f ,@x)) (g 1)");
+        assert!(err.unwrap_err().contains("only lists can be spliced"));
+    }
+
+    #[test]
+    fn hygiene() {
+        let source = "\
+(defmacro (swap! a b) `(begin (define tmp ,a) (set ,a ,b) (set ,b tmp)))
+(swap! tmp y)";
+        assert_eq!(
+            expand(source).unwrap(),
+            "(begin (define tmp 0 tmp) (set tmp y) (set y tmp 0))"
+        );
+        // binders supplied by the caller are not renamed, and neither is unquote itself
+        let source = "\
+(defmacro (def x v) `(define ,x (+ unquote ,v)))
+(defmacro (loop h &rest body) `(for ,h ,@body))
+(def unquote 1)
+(loop (i 0 (< i 3)) (def x i))";
+        assert_eq!(
+            expand(source).unwrap(),
+            "(define unquote (+ unquote 1))\n(for (i 0 (< i 3)) (define x (+ unquote i)))"
+        );
+        // symbols that the template uses without binding are left alone, so they are captured by
+        // whatever the caller has in scope under that name
+        let source = "\
+(defmacro (scaled x) `(* ,x scale))
+(func (f scale) (return (scaled 3)))";
+        assert_eq!(
+            expand(source).unwrap(),
+            "(func (f scale) (return (* 3 scale)))"
+        );
+    }
+
+    #[test]
+    fn recursion() {
+        let source = "(defmacro (a x) `(b ,x)) (defmacro (b x) `(a ,x)) (a 1)";
+        let err = expand(source).unwrap_err();
+        assert!(err.contains("recursive macro expansion: a -> b -> a"));
+        // arguments are expanded first, so nesting a macro in its own arguments is fine
+        let source = "(defmacro (neg x) `(- 0 ,x)) (neg (neg 1))";
+        assert_eq!(expand(source).unwrap(), "(- 0 (- 0 1))");
+    }
+
+    #[test]
+    fn expansion_notes() {
+        let source = "\
+(defmacro (bad x) `(set y ,x))
+(defmacro (outer x) `(bad ,x))
+(outer 1)";
+        let mut sources = SourceMap::new();
+        let base = sources.add("test.lang".to_string(), source.to_string());
+        let exprs = expand_macros(parse(source, base).unwrap(), &mut sources).unwrap();
+        assert_eq!(
+            sources.expansion_notes(exprs[0].span()),
+            ["in expansion of macro bad", "in expansion of macro outer"]
+        );
+    }
+}
@@ -1,16 +1,28 @@
 use anyhow::Result;
 
+mod format;
 mod lexer;
+mod macros;
 mod parser;
 
-use self::lexer::tokenize;
-use self::parser::read_expr;
+pub use self::format::format_source;
+use self::lexer::{tokenize, Token};
+pub use self::macros::expand_macros;
 pub use self::parser::ParseExpr;
+use self::parser::{read_expr, skip_datum_comments};
 
-pub fn parse(stream: &mut impl Iterator<Item = char>) -> Result<Box<[ParseExpr]>> {
-    let mut tokens = tokenize(&mut stream.peekable())?.into_iter().peekable();
+// base is added to every span, to locate the source within a SourceMap
+pub fn parse(source: &str, base: usize) -> Result<Box<[ParseExpr]>> {
+    let mut tokens = tokenize(&mut source.char_indices().map(|(i, c)| (base + i, c)).peekable())?
+        .into_iter()
+        .filter(|(token, _)| !matches!(token, Token::Comment))
+        .peekable();
     let mut out = vec![];
-    while tokens.peek().is_some() {
+    loop {
+        skip_datum_comments(&mut tokens)?;
+        if tokens.peek().is_none() {
+            break;
+        }
         out.push(read_expr(&mut tokens)?);
     }
     Ok(out.into_boxed_slice())
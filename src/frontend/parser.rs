@@ -1,21 +1,34 @@
 use std::fmt::{Display, Formatter};
 use std::iter::Peekable;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 
 use super::lexer::Token;
+use crate::diagnostics::{bail_at, Span};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ParseExpr {
-    List(Box<[ParseExpr]>),
-    Symbol(String),
-    Integer(i64),
+    List(Box<[ParseExpr]>, Span),
+    Symbol(String, Span),
+    Integer(i64, Span),
+    String(String, Span),
+}
+
+impl ParseExpr {
+    pub const fn span(&self) -> Span {
+        match self {
+            ParseExpr::List(_, span)
+            | ParseExpr::Symbol(_, span)
+            | ParseExpr::Integer(_, span)
+            | ParseExpr::String(_, span) => *span,
+        }
+    }
 }
 
 impl Display for ParseExpr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            ParseExpr::List(exprs) => {
+            ParseExpr::List(exprs, _) => {
                 write!(f, "(")?;
                 let mut exprs = exprs.iter().peekable();
                 while let Some(expr) = exprs.next() {
@@ -27,31 +40,74 @@ impl Display for ParseExpr {
                 write!(f, ")")?;
                 Ok(())
             }
-            ParseExpr::Symbol(val) => val.fmt(f),
-            ParseExpr::Integer(val) => val.fmt(f),
+            ParseExpr::Symbol(val, _) => val.fmt(f),
+            ParseExpr::Integer(val, _) => val.fmt(f),
+            ParseExpr::String(val, _) => {
+                write!(f, "\"{}\"", val.replace('\\', "\\\\").replace('"', "\\\""))
+            }
         }
     }
 }
 
-pub fn read_expr(tokens: &mut Peekable<impl Iterator<Item = Token>>) -> Result<ParseExpr> {
+pub fn read_expr(tokens: &mut Peekable<impl Iterator<Item = (Token, Span)>>) -> Result<ParseExpr> {
     match tokens.next().context("input ended unexpectedly")? {
-        Token::LeftParen => {
+        (Token::LeftParen, start) => {
             // reading tail
             let mut contents = vec![];
             loop {
                 match tokens.peek() {
-                    Some(Token::RightParen) => {
+                    Some((Token::RightParen, end)) => {
+                        let span = start.to(*end);
                         tokens.next();
-                        break Ok(ParseExpr::List(contents.into_boxed_slice()));
+                        break Ok(ParseExpr::List(contents.into_boxed_slice(), span));
                     }
-                    _ => contents.push(read_expr(tokens)?),
+                    Some((Token::DatumComment, _)) => skip_datum_comments(tokens)?,
+                    Some(_) => contents.push(read_expr(tokens)?),
+                    None => bail_at!(start, "unclosed parenthesis"),
                 }
             }
         }
-        Token::RightParen => {
-            bail!("unexpected right parenthesis")
+        (Token::RightParen, span) => {
+            bail_at!(span, "unexpected right parenthesis")
+        }
+        (Token::DatumComment, _) => {
+            // the datum being commented out may itself be a datum comment
+            read_expr(tokens)?;
+            read_expr(tokens)
         }
-        Token::Integer(val) => Ok(ParseExpr::Integer(val)),
-        Token::Symbol(val) => Ok(ParseExpr::Symbol(val)),
+        (Token::Quasiquote, span) => read_prefixed("quasiquote", span, tokens),
+        (Token::Unquote, span) => read_prefixed("unquote", span, tokens),
+        (Token::UnquoteSplicing, span) => read_prefixed("unquote-splicing", span, tokens),
+        (Token::Integer(val), span) => Ok(ParseExpr::Integer(val, span)),
+        (Token::Symbol(val), span) => Ok(ParseExpr::Symbol(val, span)),
+        (Token::String(val), span) => Ok(ParseExpr::String(val, span)),
+        (Token::Comment, _) => unreachable!("comments are removed before parsing"),
+    }
+}
+
+fn read_prefixed(
+    name: &str,
+    span: Span,
+    tokens: &mut Peekable<impl Iterator<Item = (Token, Span)>>,
+) -> Result<ParseExpr> {
+    if tokens.peek().is_none() {
+        bail_at!(span, "expected an expression after {}", name);
+    }
+    let expr = read_expr(tokens)?;
+    let full_span = span.to(expr.span());
+    Ok(ParseExpr::List(
+        Box::new([ParseExpr::Symbol(name.to_string(), span), expr]),
+        full_span,
+    ))
+}
+
+// discards any datum comments, along with the expressions they comment out
+pub fn skip_datum_comments(
+    tokens: &mut Peekable<impl Iterator<Item = (Token, Span)>>,
+) -> Result<()> {
+    while let Some((Token::DatumComment, _)) = tokens.peek() {
+        tokens.next();
+        read_expr(tokens)?;
     }
+    Ok(())
 }
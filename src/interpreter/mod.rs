@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::vec::IntoIter;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::ir::{arity, SSAFunction, SSAInstructionRHS, SSAJumpInstruction, VirtualRegister};
+use crate::semantics::Program;
+use crate::utils::rcequality::RcDereferencable;
+
+// deep enough for any reasonable recursion, but well short of overflowing our own stack
+const MAX_CALL_DEPTH: usize = 2000;
+
+struct Interpreter<'a> {
+    funcs: &'a HashMap<String, SSAFunction>,
+    // values for (input)
+    inputs: IntoIter<i64>,
+    // unwritten addresses read as 0
+    memory: HashMap<i64, i64>,
+    depth: usize,
+    // set once an error has been tagged with the function it happened in
+    unwinding: bool,
+}
+
+// registers holding None were assigned the result of a call that returned without a value,
+// which is only an error if they are then read
+type Registers = HashMap<VirtualRegister, Option<i64>>;
+
+fn lookup(regs: &Registers, reg: &VirtualRegister) -> Result<Option<i64>> {
+    regs.get(reg)
+        .copied()
+        .with_context(|| format!("register {} is read before it is written", reg))
+}
+
+fn read(regs: &Registers, reg: &VirtualRegister) -> Result<i64> {
+    lookup(regs, reg)?.with_context(|| {
+        format!(
+            "register {} is read, but holds the result of a call that returned without a \
+             value",
+            reg
+        )
+    })
+}
+
+impl Interpreter<'_> {
+    fn call(&mut self, name: &str, args: Vec<i64>) -> Result<Option<i64>> {
+        let func = self
+            .funcs
+            .get(name)
+            .with_context(|| format!("function {} is not defined", name))?;
+        let out = if self.depth == MAX_CALL_DEPTH {
+            Err(anyhow!(
+                "calls are nested more than {} deep",
+                MAX_CALL_DEPTH
+            ))
+        } else {
+            self.depth += 1;
+            let out = self.run(func, &args);
+            self.depth -= 1;
+            out
+        };
+        // only the innermost function is named, rather than the whole call stack
+        if out.is_err() && !self.unwinding {
+            self.unwinding = true;
+            return out.with_context(|| format!("in function {}", name));
+        }
+        out
+    }
+
+    fn run(&mut self, func: &SSAFunction, args: &[i64]) -> Result<Option<i64>> {
+        let mut regs = HashMap::new();
+        let mut block = func.start_block.clone();
+        let mut prev = None;
+        loop {
+            let next = {
+                let current = block.borrow();
+
+                // every phi reads its source before any of them are written
+                if let Some(prev) = prev {
+                    let values = current
+                        .phis
+                        .iter()
+                        .map(|phi| {
+                            let src = phi.srcs.get(&prev).with_context(|| {
+                                format!(
+                                    "phi {} in block {} has no source for its predecessor",
+                                    phi.dest.0, current.debug_index
+                                )
+                            })?;
+                            // a phi may pass on the missing result of a call without reading it
+                            Ok((phi.dest.0, lookup(&regs, src)?))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    regs.extend(values);
+                }
+
+                for inst in current.instructions.iter() {
+                    let value = match &inst.rhs {
+                        SSAInstructionRHS::ReadMemory(addr) => {
+                            let addr = read(&regs, addr)?;
+                            Some(self.memory.get(&addr).copied().unwrap_or_default())
+                        }
+                        SSAInstructionRHS::UnaryOperation {
+                            operator,
+                            width,
+                            arg,
+                        } => Some(operator.apply(*width, read(&regs, arg)?)),
+                        SSAInstructionRHS::BinaryOperation {
+                            operator,
+                            width,
+                            arg1,
+                            arg2,
+                        } => Some(operator.apply(*width, read(&regs, arg1)?, read(&regs, arg2)?)),
+                        SSAInstructionRHS::LoadIntegerLiteral { value } => Some(*value),
+                        SSAInstructionRHS::Move { src } => Some(read(&regs, src)?),
+                        SSAInstructionRHS::ReadInput => Some(
+                            self.inputs
+                                .next()
+                                .context("ran out of input values, pass more with --input")?,
+                        ),
+                        SSAInstructionRHS::Param { index } => {
+                            Some(*args.get(*index).with_context(|| {
+                                format!("parameter {} was not passed an argument", index)
+                            })?)
+                        }
+                        SSAInstructionRHS::Call { func, args } => {
+                            let args = args
+                                .iter()
+                                .map(|arg| read(&regs, arg))
+                                .collect::<Result<_>>()?;
+                            self.call(func, args)?
+                        }
+                        SSAInstructionRHS::StoreMemory { addr, data } => {
+                            self.memory.insert(read(&regs, addr)?, read(&regs, data)?);
+                            None
+                        }
+                    };
+                    if let Some(lhs) = &inst.lhs {
+                        regs.insert(lhs.0, value);
+                    }
+                }
+
+                match &current.exit {
+                    SSAJumpInstruction::BranchIfElseZero { pred, conseq, alt } => {
+                        if read(&regs, pred)? == 0 {
+                            conseq.clone()
+                        } else {
+                            alt.clone()
+                        }
+                    }
+                    SSAJumpInstruction::UnconditionalJump { dest } => dest.clone(),
+                    SSAJumpInstruction::Ret(value) => {
+                        return value.as_ref().map(|value| read(&regs, value)).transpose();
+                    }
+                }
+            };
+            prev = Some(block.as_key());
+            block = next;
+        }
+    }
+}
+
+// parses the contents of a memory file: integers separated by whitespace or commas, which are
+// loaded at addresses 0, 1, 2, ...
+pub fn parse_memory(contents: &str) -> Result<HashMap<i64, i64>> {
+    contents
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .enumerate()
+        .map(|(addr, word)| {
+            let value = word
+                .parse()
+                .with_context(|| format!("memory value {} is not an integer", word))?;
+            Ok((addr as i64, value))
+        })
+        .collect()
+}
+
+// runs entry with the given arguments, taking the value of each (input) from inputs in turn, and
+// returns what it returns
+pub fn interpret(
+    program: &Program<SSAFunction>,
+    entry: &str,
+    args: Vec<i64>,
+    inputs: Vec<i64>,
+    memory: HashMap<i64, i64>,
+) -> Result<Option<i64>> {
+    if let Some(func) = program.funcs.get(entry) {
+        if arity(func) != args.len() {
+            bail!(
+                "{} takes {} arguments, but {} were passed with --args",
+                entry,
+                arity(func),
+                args.len()
+            );
+        }
+    }
+    let mut interpreter = Interpreter {
+        funcs: &program.funcs,
+        inputs: inputs.into_iter(),
+        memory,
+        depth: 0,
+        unwinding: false,
+    };
+    interpreter.call(entry, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::read_to_string;
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use super::{interpret, parse_memory};
+    use crate::ir::{gen_test_ir, SSAFunction};
+    use crate::optimizations::optimize;
+    use crate::semantics::Program;
+
+    // compiles source through the optimizer, then runs main
+    fn run(source: &str, inputs: &[i64]) -> Result<Option<i64>> {
+        let mut program = gen_test_ir(source)?;
+        optimize(&mut program, false, true)?;
+        interpret(&program, "main", vec![], inputs.to_vec(), HashMap::new())
+    }
+
+    #[test]
+    fn unused_parameter() {
+        let source = "(func (f a b) (return b)) (func (main) (return (f 1 2)))";
+        assert_eq!(run(source, &[]).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn call_without_value() {
+        let source = "(func (g x) (write x 5)) (func (main) (g 3) (return (read 3)))";
+        assert_eq!(run(source, &[]).unwrap(), Some(5));
+    }
+
+    #[test]
+    fn unused_input() {
+        let source = "(define x (input)) (define y (input)) (return y)";
+        assert_eq!(run(source, &[1, 2]).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn short_circuit() {
+        // the second (input) is only read if the first does not decide the result
+        let source = "(return (and (input) (input)))";
+        assert_eq!(run(source, &[0]).unwrap(), Some(0));
+        assert_eq!(run(source, &[1, 5]).unwrap(), Some(1));
+        assert_eq!(run(source, &[1, 0]).unwrap(), Some(0));
+        let source = "(return (or (input) (input)))";
+        assert_eq!(run(source, &[3]).unwrap(), Some(1));
+        assert_eq!(run(source, &[0, 0]).unwrap(), Some(0));
+        assert!(run(source, &[0]).is_err());
+        // nor is a call made in an operand that is skipped
+        let source = "
+(func (mark x) (write 0 (+ (read 0) x)) (return 1))
+(func (main)
+    (define a (and (input) (mark 1) (mark 10)))
+    (define b (or (input) (mark 100)))
+    (return (+ (read 0) (* 1000 (+ a b)))))";
+        assert_eq!(run(source, &[0, 1]).unwrap(), Some(1000));
+        assert_eq!(run(source, &[1, 0]).unwrap(), Some(2111));
+    }
+
+    #[test]
+    fn for_loops() {
+        let source = "
+(define total 0)
+(for (i (input) (input) (input))
+    (set total (+ (* total 10) i))
+)
+(return total)";
+        // the header is evaluated in order, so the inputs are the start, end and step
+        assert_eq!(run(source, &[1, 5, 1]).unwrap(), Some(1234));
+        assert_eq!(run(source, &[1, 8, 3]).unwrap(), Some(147));
+        assert_eq!(run(source, &[5, 5, 1]).unwrap(), Some(0));
+        assert_eq!(run(source, &[6, 5, 1]).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn for_loop_variable_does_not_leak() {
+        // the loop variable shadows an outer one only inside the loop
+        let source = "
+(define i 7)
+(define total 0)
+(for (i 0 3)
+    (set total (+ total i))
+)
+(return (+ (* i 10) total))";
+        assert_eq!(run(source, &[]).unwrap(), Some(73));
+        let err = run("(for (i 0 3) (break)) (return i)", &[]).unwrap_err();
+        assert!(format!("{:#}", err).contains("variable not found in scope"));
+    }
+
+    #[test]
+    fn cond() {
+        let source = "
+(define x (input))
+(return (cond
+    ((< x 0) -1)
+    ((= x 0) 0)
+    (else 1)
+))";
+        assert_eq!(run(source, &[-5]).unwrap(), Some(-1));
+        assert_eq!(run(source, &[0]).unwrap(), Some(0));
+        assert_eq!(run(source, &[5]).unwrap(), Some(1));
+        // without an else arm, nothing runs if no predicate holds
+        let source = "
+(define x (input))
+(define out 5)
+(cond
+    ((< x 0) (set out -1))
+    ((= x 0) (set out 0))
+)
+(return out)";
+        assert_eq!(run(source, &[-5]).unwrap(), Some(-1));
+        assert_eq!(run(source, &[0]).unwrap(), Some(0));
+        assert_eq!(run(source, &[5]).unwrap(), Some(5));
+    }
+
+    #[test]
+    fn while_loops() {
+        let source = "
+(define x (input))
+(define steps 0)
+(while (< x 100)
+    (set x (* x 2))
+    (set steps (+ steps 1))
+)
+(return (+ (* steps 1000) x))";
+        assert_eq!(run(source, &[3]).unwrap(), Some(6192));
+        // the body never runs if the predicate fails at once
+        assert_eq!(run(source, &[100]).unwrap(), Some(100));
+        assert_eq!(run(source, &[500]).unwrap(), Some(500));
+    }
+
+    #[test]
+    fn loop_values() {
+        // the loop evaluates to the value of whichever break leaves it (recall that if takes its
+        // consequent when the predicate is zero)
+        let source = "
+(define x (input))
+(define y (loop
+    (if (>= x 0) (break -1))
+    (if (> x 100) (break (* x 2)) (break 100))
+))
+(return y)";
+        assert_eq!(run(source, &[-5]).unwrap(), Some(-1));
+        assert_eq!(run(source, &[5]).unwrap(), Some(10));
+        assert_eq!(run(source, &[500]).unwrap(), Some(100));
+        // including breaks out of an enclosing loop
+        let source = "
+(define x (input))
+(return (loop :label outer
+    (loop (if (= x 0) (break :label outer 7) (break :label outer 8)))
+))";
+        assert_eq!(run(source, &[1]).unwrap(), Some(7));
+        assert_eq!(run(source, &[0]).unwrap(), Some(8));
+    }
+
+    #[test]
+    fn mixed_loop_values() {
+        let err = run("(define y (loop (if (input) (break) (break 1))))", &[]).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "all breaks out of a loop must agree on whether they carry a value"
+        );
+        // even when one of them breaks out of an inner loop
+        let source = "(loop :label outer (loop (break :label outer 1)) (break :label outer))";
+        let err = run(source, &[]).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "all breaks out of a loop must agree on whether they carry a value"
+        );
+    }
+
+    // compiles one of the samples in the repository root, only optimizing it if requested
+    fn build_sample(name: &str, optimized: bool) -> Program<SSAFunction> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+        let mut program = gen_test_ir(&read_to_string(path).unwrap()).unwrap();
+        if optimized {
+            optimize(&mut program, false, true).unwrap();
+        }
+        program
+    }
+
+    #[test]
+    fn fib() {
+        for optimized in [false, true] {
+            let program = build_sample("fib.lang", optimized);
+            for (n, expected) in [(1, 1), (2, 1), (5, 5), (10, 55), (20, 6765)] {
+                let out = interpret(&program, "fib", vec![n], vec![], HashMap::new()).unwrap();
+                assert_eq!(out, Some(expected));
+            }
+            // main loops until its first two arguments are equal, then falls off the end
+            let out = interpret(&program, "main", vec![3, 3, 0], vec![], HashMap::new()).unwrap();
+            assert_eq!(out, None);
+            let err = interpret(&program, "main", vec![1], vec![], HashMap::new()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "main takes 3 arguments, but 1 were passed with --args"
+            );
+        }
+    }
+
+    #[test]
+    fn factorial() {
+        // the inner loop only breaks once ctr equals inner-ctr, which counts down from 0 while
+        // ctr counts up from 1, so main never returns. It must read n before anything else
+        for optimized in [false, true] {
+            let program = build_sample("factorial.lang", optimized);
+            let err = interpret(&program, "main", vec![], vec![], HashMap::new()).unwrap_err();
+            assert_eq!(
+                err.root_cause().to_string(),
+                "ran out of input values, pass more with --input"
+            );
+        }
+    }
+
+    #[test]
+    fn memory_file() {
+        let memory = parse_memory("5, 7\n-3\t0x10").unwrap_err();
+        assert_eq!(memory.to_string(), "memory value 0x10 is not an integer");
+        let memory = parse_memory("5, 7\n-3\t").unwrap();
+        assert_eq!(memory, HashMap::from([(0, 5), (1, 7), (2, -3)]));
+        for optimized in [false, true] {
+            let program = build_sample("mem.lang", optimized);
+            let out = interpret(&program, "main", vec![], vec![], memory.clone()).unwrap();
+            assert_eq!(out, Some(5));
+            // unwritten addresses read as 0
+            let out = interpret(&program, "main", vec![], vec![], HashMap::new()).unwrap();
+            assert_eq!(out, Some(0));
+        }
+    }
+}
@@ -3,27 +3,29 @@ use std::rc::Rc;
 
 use itertools::Itertools;
 
-use super::structs::BlockRef;
+use super::structs::{Block, BlockRef, BlockWithSuccessors};
 use crate::utils::graph::explore;
 use crate::utils::rcequality::{RcDereferencable, RcEquality};
 
-pub type BlockDataLookup<T> = HashMap<RcEquality<BlockRef>, T>;
+// these work on the blocks of any stage, but are mostly used on those before SSA
+pub type BlockDataLookup<T, B = Block> = HashMap<RcEquality<BlockRef<B>>, T>;
+type Predecessors<B = Block> = BlockDataLookup<Vec<BlockRef<B>>, B>;
+// the reachable blocks in post-order, the index of each in that order, and their predecessors
+type Postorder<B> = (
+    Box<[BlockRef<B>]>,
+    BlockDataLookup<usize, B>,
+    Predecessors<B>,
+);
 
 /*
 Cooper, Keith D., Timothy J. Harvey, and Ken Kennedy.
 "A simple, fast dominance algorithm." Software Practice & Experience 4.1-10 (2001): 1-8.
 */
 
-pub fn sort_blocks_postorder(
-    root: BlockRef,
-) -> (
-    Box<[BlockRef]>,
-    BlockDataLookup<usize>,
-    BlockDataLookup<Vec<BlockRef>>,
-) {
+pub fn sort_blocks_postorder<B: BlockWithSuccessors>(root: BlockRef<B>) -> Postorder<B> {
     let mut blocks = vec![];
     let mut predecessors = HashMap::new();
-    let mut visited = HashSet::<RcEquality<BlockRef>>::new();
+    let mut visited = HashSet::<RcEquality<BlockRef<B>>>::new();
 
     explore(
         root,
@@ -32,9 +34,8 @@ pub fn sort_blocks_postorder(
                 (
                     (*pos)
                         .borrow()
-                        .exit
-                        .dests()
-                        .into_iter()
+                        .successors()
+                        .iter()
                         .map(|dst| {
                             predecessors
                                 .entry(dst.clone().into())
@@ -68,12 +69,12 @@ pub fn sort_blocks_postorder(
 }
 
 // expect `blocks` to be in post-order
-pub fn find_immediate_dominators(
-    start_block: BlockRef,
-    blocks: &[BlockRef],
-    index_lookup: &BlockDataLookup<usize>,
-    predecessors: &BlockDataLookup<Vec<BlockRef>>,
-) -> BlockDataLookup<BlockRef> {
+pub fn find_immediate_dominators<B>(
+    start_block: BlockRef<B>,
+    blocks: &[BlockRef<B>],
+    index_lookup: &BlockDataLookup<usize, B>,
+    predecessors: &Predecessors<B>,
+) -> BlockDataLookup<BlockRef<B>, B> {
     let mut dominators = BlockDataLookup::new();
     dominators.insert(start_block.clone().into(), start_block);
     let mut changed = true;
@@ -106,12 +107,12 @@ pub fn find_immediate_dominators(
     dominators
 }
 
-fn intersect(
-    mut a: BlockRef,
-    mut b: BlockRef,
-    index_lookup: &BlockDataLookup<usize>,
-    dominators: &BlockDataLookup<BlockRef>,
-) -> BlockRef {
+fn intersect<B>(
+    mut a: BlockRef<B>,
+    mut b: BlockRef<B>,
+    index_lookup: &BlockDataLookup<usize, B>,
+    dominators: &BlockDataLookup<BlockRef<B>, B>,
+) -> BlockRef<B> {
     while !Rc::ptr_eq(&a, &b) {
         let dominator_error = "all blocks should be in dominators while performing intersection";
         let index_error = "all blocks should be in index lookup";
@@ -130,10 +131,10 @@ fn intersect(
     a
 }
 
-pub fn find_immediately_dominated(
-    blocks: &[BlockRef],
-    dominators: &BlockDataLookup<BlockRef>,
-) -> BlockDataLookup<Vec<BlockRef>> {
+pub fn find_immediately_dominated<B>(
+    blocks: &[BlockRef<B>],
+    dominators: &BlockDataLookup<BlockRef<B>, B>,
+) -> BlockDataLookup<Vec<BlockRef<B>>, B> {
     let mut dominated = BlockDataLookup::new();
     for block in blocks {
         let dom = dominators
@@ -151,11 +152,11 @@ pub fn find_immediately_dominated(
     dominated
 }
 
-pub fn dominance_frontiers(
-    blocks: &[BlockRef],
-    predecessors: &BlockDataLookup<Vec<BlockRef>>,
-    dominators: &BlockDataLookup<BlockRef>,
-) -> BlockDataLookup<Vec<BlockRef>> {
+pub fn dominance_frontiers<B>(
+    blocks: &[BlockRef<B>],
+    predecessors: &Predecessors<B>,
+    dominators: &BlockDataLookup<BlockRef<B>, B>,
+) -> BlockDataLookup<Vec<BlockRef<B>>, B> {
     let mut frontiers = BlockDataLookup::new();
     for block in blocks {
         if let Some(preds) = predecessors.get(&block.as_key()) {
@@ -0,0 +1,352 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Write};
+use std::hash::Hash;
+use std::iter::once;
+
+use itertools::Itertools;
+
+use super::dominance::{find_immediate_dominators, sort_blocks_postorder};
+use super::ssa_forms::InitialCfg;
+use super::structs::{Block, BlockRef, BlockWithDebugIndex, BlockWithSuccessors, VirtualVariable};
+use super::{
+    CfgConfig, FullBlock, Function, Instruction, JumpInstruction, RegisterLValue, WithRegisters,
+};
+use crate::utils::rcequality::RcDereferencable;
+
+type BlockKey<B> = *const RefCell<B>;
+// a phi's destination, with its source from each predecessor
+type PhiSources<B, R> = (R, Vec<(BlockKey<B>, R)>);
+// the registers an instruction reads, and the one it writes
+type Step<R> = (Vec<R>, Option<R>);
+// the registers live into and out of a block
+type Live<R> = (HashSet<R>, HashSet<R>);
+
+// what is drawn of a block, so that the blocks of every stage are drawn the same way
+pub trait DotBlock: BlockWithDebugIndex + BlockWithSuccessors {
+    type Reg: Copy + Eq + Hash + Display;
+
+    // the phis and instructions, then the exit, as they are printed
+    fn lines(&self) -> (Vec<String>, String);
+    // each successor, labeled with the outcome of the branch that leads to it
+    fn edges(&self) -> Vec<(BlockRef<Self>, Option<&'static str>)>;
+    fn phis(&self) -> Vec<PhiSources<Self, Self::Reg>>;
+    // each instruction, then the exit, which writes nothing
+    fn steps(&self) -> Vec<Step<Self::Reg>>;
+}
+
+fn edges<Conf: CfgConfig>(
+    exit: &JumpInstruction<Conf>,
+) -> Vec<(BlockRef<Conf::BlockType>, Option<&'static str>)> {
+    match exit {
+        // the consequent is taken when the predicate is zero
+        JumpInstruction::BranchIfElseZero { conseq, alt, .. } => {
+            vec![(conseq.clone(), Some("false")), (alt.clone(), Some("true"))]
+        }
+        JumpInstruction::UnconditionalJump { dest } => vec![(dest.clone(), None)],
+        JumpInstruction::Ret(_) => vec![],
+    }
+}
+
+fn steps<Conf: CfgConfig>(
+    instructions: &[Instruction<Conf>],
+    exit: &JumpInstruction<Conf>,
+) -> Vec<Step<Conf::RValue>> {
+    instructions
+        .iter()
+        .map(|inst| {
+            (
+                inst.rhs.regs().copied().collect(),
+                inst.lhs.as_ref().map(RegisterLValue::rvalue),
+            )
+        })
+        .chain(once((exit.regs().copied().collect(), None)))
+        .collect()
+}
+
+impl DotBlock for Block {
+    type Reg = VirtualVariable;
+
+    fn lines(&self) -> (Vec<String>, String) {
+        (
+            self.instructions.iter().map(ToString::to_string).collect(),
+            self.exit.to_string(),
+        )
+    }
+
+    fn edges(&self) -> Vec<(BlockRef<Self>, Option<&'static str>)> {
+        edges::<InitialCfg>(&self.exit)
+    }
+
+    fn phis(&self) -> Vec<PhiSources<Self, Self::Reg>> {
+        vec![]
+    }
+
+    fn steps(&self) -> Vec<Step<Self::Reg>> {
+        steps(&self.instructions, &self.exit)
+    }
+}
+
+impl<Conf: CfgConfig<BlockType = Self>> DotBlock for FullBlock<Conf>
+where
+    Conf::LValue: Display,
+    Conf::RValue: Hash + Display,
+    Instruction<Conf>: Display,
+{
+    type Reg = Conf::RValue;
+
+    fn lines(&self) -> (Vec<String>, String) {
+        (
+            self.phis
+                .iter()
+                .map(ToString::to_string)
+                .chain(self.instructions.iter().map(ToString::to_string))
+                .collect(),
+            self.exit.to_string(),
+        )
+    }
+
+    fn edges(&self) -> Vec<(BlockRef<Self>, Option<&'static str>)> {
+        edges(&self.exit)
+    }
+
+    fn phis(&self) -> Vec<PhiSources<Self, Self::Reg>> {
+        self.phis
+            .iter()
+            .map(|phi| {
+                let srcs = phi
+                    .srcs
+                    .iter()
+                    .map(|(pred, src)| (pred.get_ref().as_key(), *src))
+                    .collect();
+                (phi.dest.rvalue(), srcs)
+            })
+            .collect()
+    }
+
+    fn steps(&self) -> Vec<Step<Self::Reg>> {
+        steps(&self.instructions, &self.exit)
+    }
+}
+
+// the registers live as control enters each block, before its phis, and as it leaves
+fn liveness<B: DotBlock>(blocks: &[BlockRef<B>]) -> HashMap<BlockKey<B>, Live<B::Reg>> {
+    // the registers each block reads before writing them, and those it writes
+    let mut reads = HashMap::new();
+    let mut writes = HashMap::new();
+    // the phi sources that each block must provide, since it jumps to their phis
+    let mut provides = HashMap::<_, HashSet<_>>::new();
+    for block in blocks {
+        let block_ref = block.borrow();
+        let mut read = HashSet::new();
+        let mut written = HashSet::new();
+        for (dest, srcs) in block_ref.phis() {
+            written.insert(dest);
+            for (pred, src) in srcs {
+                provides.entry(pred).or_default().insert(src);
+            }
+        }
+        for (uses, def) in block_ref.steps() {
+            read.extend(uses.into_iter().filter(|reg| !written.contains(reg)));
+            written.extend(def);
+        }
+        reads.insert(block.as_key(), read);
+        writes.insert(block.as_key(), written);
+    }
+
+    let mut live = blocks
+        .iter()
+        .map(|block| (block.as_key(), (HashSet::new(), HashSet::new())))
+        .collect::<HashMap<_, _>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in blocks.iter().rev() {
+            let key = block.as_key();
+            let mut live_out = provides.get(&key).cloned().unwrap_or_default();
+            for succ in block.borrow().successors() {
+                if let Some((live_in, _)) = live.get(&succ.as_key()) {
+                    live_out.extend(live_in);
+                }
+            }
+            let mut live_in = reads[&key].clone();
+            live_in.extend(live_out.difference(&writes[&key]));
+            let entry = live.get_mut(&key).unwrap();
+            if entry.0 != live_in || entry.1 != live_out {
+                *entry = (live_in, live_out);
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+// record labels give these characters meaning, and \l ends a left-aligned line
+fn escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '{' | '}' | '|' | '<' | '>') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push_str("\\l");
+    out
+}
+
+fn regs<R: Display>(what: &str, regs: &HashSet<R>) -> String {
+    // sorted so that %2 comes before %10, and the same function is always drawn the same way
+    let names = regs
+        .iter()
+        .map(ToString::to_string)
+        .sorted_by_key(|name| (name.len(), name.clone()))
+        .join(", ");
+    escape(&format!("{}: {}", what, names))
+}
+
+// draws func as a Graphviz digraph, optionally with its dominator tree as dashed edges, and the
+// registers live into and out of each block
+pub fn to_dot<Conf: CfgConfig>(
+    name: &str,
+    func: &Function<Conf>,
+    dominators: bool,
+    liveness: bool,
+) -> String
+where
+    Conf::BlockType: DotBlock,
+{
+    let blocks = func.blocks().collect_vec();
+    let ids = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.as_key(), format!("b{}", i)))
+        .collect::<HashMap<_, _>>();
+    let live = if liveness {
+        self::liveness(&blocks)
+    } else {
+        HashMap::new()
+    };
+
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", name.escape_default()).unwrap();
+    writeln!(out, "    node [shape=record, fontname=\"monospace\"];").unwrap();
+    writeln!(out, "    entry [shape=point];").unwrap();
+    writeln!(out, "    entry -> {};", ids[&func.start_block.as_key()]).unwrap();
+    for block in &blocks {
+        let block_ref = block.borrow();
+        let (body, exit) = block_ref.lines();
+        let mut fields = vec![format!("block {}", block_ref.get_debug_index())];
+        let live = live.get(&block.as_key());
+        if let Some((live_in, _)) = live {
+            fields.push(regs("live in", live_in));
+        }
+        if !body.is_empty() {
+            fields.push(body.iter().map(|line| escape(line)).join(""));
+        }
+        fields.push(escape(&exit));
+        if let Some((_, live_out)) = live {
+            fields.push(regs("live out", live_out));
+        }
+        let id = &ids[&block.as_key()];
+        writeln!(out, "    {} [label=\"{{{}}}\"];", id, fields.join("|")).unwrap();
+        for (dest, label) in block_ref.edges() {
+            match label {
+                Some(label) => {
+                    writeln!(
+                        out,
+                        "    {} -> {} [label=\"{}\"];",
+                        id,
+                        ids[&dest.as_key()],
+                        label
+                    )
+                }
+                None => writeln!(out, "    {} -> {};", id, ids[&dest.as_key()]),
+            }
+            .unwrap();
+        }
+    }
+
+    if dominators {
+        let (reachable, index_lookup, predecessors) =
+            sort_blocks_postorder(func.start_block.clone());
+        let idoms = find_immediate_dominators(
+            func.start_block.clone(),
+            &reachable,
+            &index_lookup,
+            &predecessors,
+        );
+        // in a fixed order, so that the output does not depend on hashing
+        for block in reachable.iter().rev() {
+            let idom = &idoms[&block.as_key()];
+            if idom.as_key() != block.as_key() {
+                writeln!(
+                    out,
+                    "    {} -> {} [style=dashed, color=blue, constraint=false];",
+                    ids[&idom.as_key()],
+                    ids[&block.as_key()]
+                )
+                .unwrap();
+            }
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_dot;
+    use crate::ir::parse_ir;
+
+    const DIAMOND: &str = "\
+func main:
+start: 0
+
+block 0 (preds=[])
+%1 = input()
+if %1==0 branchto 1 else 2
+
+block 1 (preds=[0])
+%2 = 1
+jumpto 3
+
+block 2 (preds=[0])
+%3 = 2
+jumpto 3
+
+block 3 (preds=[1, 2])
+%4 = phi(%2 from block 1, %3 from block 2)
+%5 = %4 Add %1
+ret %5
+";
+
+    #[test]
+    fn overlays() {
+        let program = parse_ir(DIAMOND, 0).unwrap();
+        let func = &program.funcs["main"];
+        // phi sources are live out of the predecessors that provide them, but not into the phi's
+        // block, and block 0 immediately dominates every other block
+        assert_eq!(
+            to_dot("main", func, true, true),
+            r#"digraph "main" {
+    node [shape=record, fontname="monospace"];
+    entry [shape=point];
+    entry -> b0;
+    b0 [label="{block 0|live in: \l|%1 = input()\l|if %1==0 branchto 1 else 2\l|live out: %1\l}"];
+    b0 -> b1 [label="false"];
+    b0 -> b2 [label="true"];
+    b1 [label="{block 1|live in: %1\l|%2 = 1\l|jumpto 3\l|live out: %1, %2\l}"];
+    b1 -> b3;
+    b2 [label="{block 2|live in: %1\l|%3 = 2\l|jumpto 3\l|live out: %1, %3\l}"];
+    b2 -> b3;
+    b3 [label="{block 3|live in: %1\l|%4 = phi(%2 from block 1, %3 from block 2)\l%5 = %4 Add %1\l|ret %5\l|live out: \l}"];
+    b0 -> b2 [style=dashed, color=blue, constraint=false];
+    b0 -> b1 [style=dashed, color=blue, constraint=false];
+    b0 -> b3 [style=dashed, color=blue, constraint=false];
+}
+"#
+        );
+        let plain = to_dot("main", func, false, false);
+        assert!(!plain.contains("live") && !plain.contains("dashed"));
+    }
+}
@@ -3,12 +3,30 @@ use anyhow::{bail, Context, Result};
 use super::instructions::{Instruction, InstructionRHS, JumpInstruction};
 use super::ssa_forms::InitialCfg;
 use super::structs::{BlockRef, Function, VirtualVariable};
-use crate::semantics::Expr;
+use crate::diagnostics::{collect_errors, WithSpan};
+use crate::semantics::{
+    BinaryOperator, Expr, ExprKind, IntType, LogicalOperator, Type, UnaryOperator,
+};
 use crate::utils::frame::Frame;
 
 pub struct LoopContext {
+    label: Option<String>,
     loop_start: BlockRef,
     loop_break: BlockRef,
+    // breaks that carry a value move it into this register before jumping to loop_break
+    join: VirtualVariable,
+    // None until we see the first break
+    breaks_with_value: Option<bool>,
+}
+
+fn find_loop<'a>(
+    loops: &'a mut [LoopContext],
+    label: &Option<String>,
+) -> Option<&'a mut LoopContext> {
+    match label {
+        Some(_) => loops.iter_mut().rev().find(|ctx| &ctx.label == label),
+        None => loops.last_mut(),
+    }
 }
 
 pub fn gen_expr(
@@ -16,13 +34,23 @@ pub fn gen_expr(
     func: &mut Function<InitialCfg>,
     frame: &mut Frame<String, VirtualVariable>,
     loops: &mut Vec<LoopContext>,
+    block: BlockRef,
+) -> Result<(Option<VirtualVariable>, BlockRef)> {
+    gen_expr_kind(&expr.kind, func, frame, loops, block).at(expr.span)
+}
+
+fn gen_expr_kind(
+    expr: &ExprKind,
+    func: &mut Function<InitialCfg>,
+    frame: &mut Frame<String, VirtualVariable>,
+    loops: &mut Vec<LoopContext>,
     mut block: BlockRef,
 ) -> Result<(Option<VirtualVariable>, BlockRef)> {
     Ok(match expr {
-        Expr::VarDecl { name, value } => {
-            if frame.lookup(&name).is_some() {
-                // this is a language-level requirement, not a limitation of the codegen
-                bail!("variable shadowing is not permitted")
+        ExprKind::VarDecl { name, value, .. } => {
+            // variables in enclosing scopes may be shadowed, but not ones in the same scope
+            if frame.contains_local(name) {
+                bail!("variable {} is already defined in this scope", name)
             }
             let (src, block) = gen_expr(value, func, frame, loops, block)?;
             let dst = func.new_reg();
@@ -35,11 +63,11 @@ pub fn gen_expr(
             ));
             (Some(dst), block)
         }
-        Expr::VarAccess(name) => (
+        ExprKind::VarAccess(name) => (
             Some(frame.lookup(name).context("variable not found in scope")?),
             block,
         ),
-        Expr::VarAssign { name, value } => {
+        ExprKind::VarAssign { name, value } => {
             let dst = frame
                 .lookup(name)
                 .context("cannot assign to undeclared variable")?;
@@ -52,7 +80,7 @@ pub fn gen_expr(
             ));
             (None, block)
         }
-        Expr::ReadMemory(arg) => {
+        ExprKind::ReadMemory(arg) => {
             let (arg, block) = gen_expr(arg, func, frame, loops, block)?;
             let out = func.new_reg();
             block.borrow_mut().instructions.push(Instruction::new(
@@ -61,20 +89,58 @@ pub fn gen_expr(
             ));
             (Some(out), block)
         }
-        Expr::UnaryOp { operator, arg } => {
+        ExprKind::WriteMemory { addr, value } => {
+            let (addr, block) = gen_expr(addr, func, frame, loops, block)?;
+            let (value, block) = gen_expr(value, func, frame, loops, block)?;
+            block
+                .borrow_mut()
+                .instructions
+                .push(Instruction::new_effect(InstructionRHS::StoreMemory {
+                    addr: addr.context("cannot pass a statement as an argument")?,
+                    data: value.context("cannot pass a statement as an argument")?,
+                }));
+            (None, block)
+        }
+        ExprKind::UnaryOp {
+            operator,
+            width,
+            arg,
+        } => {
             let (arg, block) = gen_expr(arg, func, frame, loops, block)?;
             let out = func.new_reg();
             block.borrow_mut().instructions.push(Instruction::new(
                 out,
                 InstructionRHS::UnaryOperation {
                     operator: *operator,
+                    width: *width,
                     arg: arg.context("cannot pass a statement as an argument")?,
                 },
             ));
             (Some(out), block)
         }
-        Expr::ArithOp {
+        ExprKind::Cast { ty, value } => {
+            let (value, block) = gen_expr(value, func, frame, loops, block)?;
+            let value = value.context("cannot cast a statement")?;
+            match ty {
+                // every other conversion keeps the same 64-bit pattern
+                Type::Int(width) if width.bits < 64 => {
+                    let out = func.new_reg();
+                    block.borrow_mut().instructions.push(Instruction::new(
+                        out,
+                        InstructionRHS::UnaryOperation {
+                            operator: UnaryOperator::Wrap,
+                            width: *width,
+                            arg: value,
+                        },
+                    ));
+                    (Some(out), block)
+                }
+                _ => (Some(value), block),
+            }
+        }
+        ExprKind::ArithOp {
             operator,
+            width,
             arg1,
             arg2,
         } => {
@@ -85,25 +151,94 @@ pub fn gen_expr(
                 out,
                 InstructionRHS::BinaryOperation {
                     operator: *operator,
+                    width: *width,
                     arg1: arg1.context("cannot pass a statement as an argument")?,
                     arg2: arg2.context("cannot pass a statement as an argument")?,
                 },
             ));
             (Some(out), block)
         }
-        Expr::Block(exprs) => {
+        ExprKind::LogicalOp {
+            operator,
+            arg1,
+            arg2,
+        } => {
+            let (arg1, block) = gen_expr(arg1, func, frame, loops, block)?;
+
+            let rhs_block = func.new_block();
+            let mut rhs_frame = frame.new_child();
+
+            // taken when arg1 alone determines the result
+            let short_block = func.new_block();
+
+            let arg1 = arg1.context("cannot pass a statement as an argument")?;
+            block.borrow_mut().exit = match operator {
+                LogicalOperator::And => JumpInstruction::BranchIfElseZero {
+                    pred: arg1,
+                    conseq: short_block.clone(),
+                    alt: rhs_block.clone(),
+                },
+                LogicalOperator::Or => JumpInstruction::BranchIfElseZero {
+                    pred: arg1,
+                    conseq: rhs_block.clone(),
+                    alt: short_block.clone(),
+                },
+            };
+
+            let out = func.new_reg();
+            short_block.borrow_mut().instructions.push(Instruction::new(
+                out,
+                InstructionRHS::LoadIntegerLiteral {
+                    value: match operator {
+                        LogicalOperator::And => 0,
+                        LogicalOperator::Or => 1,
+                    },
+                },
+            ));
+
+            let (arg2, rhs_block) = gen_expr(arg2, func, &mut rhs_frame, loops, rhs_block)?;
+            let zero = func.new_reg();
+            rhs_block.borrow_mut().instructions.extend([
+                Instruction::new(zero, InstructionRHS::LoadIntegerLiteral { value: 0 }),
+                Instruction::new(
+                    out,
+                    InstructionRHS::BinaryOperation {
+                        operator: BinaryOperator::Ne,
+                        width: IntType::I64,
+                        arg1: arg2.context("cannot pass a statement as an argument")?,
+                        arg2: zero,
+                    },
+                ),
+            ]);
+
+            let new_block = func.new_block();
+            short_block.borrow_mut().exit = JumpInstruction::UnconditionalJump {
+                dest: new_block.clone(),
+            };
+            rhs_block.borrow_mut().exit = JumpInstruction::UnconditionalJump {
+                dest: new_block.clone(),
+            };
+            (Some(out), new_block)
+        }
+        ExprKind::Block(exprs) => {
             let mut out = None;
-            for expr in exprs.iter() {
+            // keep generating after a failed statement, so that later errors are reported too
+            collect_errors(exprs.iter().map(|expr| {
                 let out_tmp;
-                (out_tmp, block) = gen_expr(expr, func, frame, loops, block)?;
+                (out_tmp, block) = gen_expr(expr, func, frame, loops, block.clone())?;
                 out = Some(out_tmp);
-            }
+                Ok(())
+            }))?;
             (
                 out.context("expr blocks must have at least one expression")?,
                 block,
             )
         }
-        Expr::IfElse { pred, conseq, alt } => {
+        ExprKind::Scope(body) => {
+            let mut inner_frame = frame.new_child();
+            gen_expr(body, func, &mut inner_frame, loops, block)?
+        }
+        ExprKind::IfElse { pred, conseq, alt } => {
             let (test, block) = gen_expr(pred, func, frame, loops, block)?;
 
             let conseq_block = func.new_block();
@@ -149,7 +284,7 @@ pub fn gen_expr(
             };
             (out, new_block)
         }
-        Expr::IntegerLiteral(value) => {
+        ExprKind::IntegerLiteral(value) => {
             let out = func.new_reg();
             block.borrow_mut().instructions.push(Instruction::new(
                 out,
@@ -157,8 +292,8 @@ pub fn gen_expr(
             ));
             (Some(out), block)
         }
-        Expr::Noop => (None, block),
-        Expr::Loop(body) => {
+        ExprKind::Noop => (None, block),
+        ExprKind::Loop { label, body } => {
             let loop_start_block = func.new_block();
             let mut inner_frame = frame.new_child();
 
@@ -169,43 +304,73 @@ pub fn gen_expr(
             let new_block = func.new_block();
 
             loops.push(LoopContext {
+                label: label.clone(),
                 loop_start: loop_start_block.clone(),
                 loop_break: new_block.clone(),
+                join: func.new_reg(),
+                breaks_with_value: None,
             });
 
-            let (_, loop_final_block) = gen_expr(
+            let body = gen_expr(
                 body,
                 func,
                 &mut inner_frame,
                 loops,
                 loop_start_block.clone(),
-            )?;
+            );
 
-            loops.pop().unwrap();
+            // popped even if the body fails, so that later breaks don't target this loop
+            let LoopContext {
+                join,
+                breaks_with_value,
+                ..
+            } = loops.pop().unwrap();
+            let (_, loop_final_block) = body?;
 
             loop_final_block.borrow_mut().exit = JumpInstruction::UnconditionalJump {
                 dest: loop_start_block,
             };
 
-            (None, new_block)
+            (breaks_with_value.unwrap_or(false).then(|| join), new_block)
         }
-        Expr::Break => {
-            let LoopContext { loop_break, .. } =
-                loops.last().context("cannot break outside a loop")?;
+        ExprKind::Break { label, value } => {
+            let value = match value {
+                Some(value) => {
+                    let value_reg;
+                    (value_reg, block) = gen_expr(value, func, frame, loops, block)?;
+                    Some(value_reg.context("cannot break with a statement as the value")?)
+                }
+                None => None,
+            };
+            let LoopContext {
+                loop_break,
+                join,
+                breaks_with_value,
+                ..
+            } = find_loop(loops, label).context("cannot break outside a loop")?;
+            if *breaks_with_value.get_or_insert(value.is_some()) != value.is_some() {
+                bail!("all breaks out of a loop must agree on whether they carry a value")
+            }
+            if let Some(value) = value {
+                block
+                    .borrow_mut()
+                    .instructions
+                    .push(Instruction::new(*join, InstructionRHS::Move { src: value }));
+            }
             block.borrow_mut().exit = JumpInstruction::UnconditionalJump {
                 dest: loop_break.clone(),
             };
             (None, func.new_block())
         }
-        Expr::Continue => {
+        ExprKind::Continue(label) => {
             let LoopContext { loop_start, .. } =
-                loops.last().context("cannot continue outside a loop")?;
+                find_loop(loops, label).context("cannot continue outside a loop")?;
             block.borrow_mut().exit = JumpInstruction::UnconditionalJump {
                 dest: loop_start.clone(),
             };
             (None, func.new_block())
         }
-        Expr::Return(expr) => {
+        ExprKind::Return(expr) => {
             let ret = match expr {
                 Some(expr) => {
                     let ret;
@@ -217,7 +382,24 @@ pub fn gen_expr(
             block.borrow_mut().exit = JumpInstruction::Ret(ret);
             (None, func.new_block())
         }
-        Expr::Input => {
+        ExprKind::Call { func: callee, args } => {
+            let mut arg_regs = vec![];
+            for arg in args.iter() {
+                let arg_reg;
+                (arg_reg, block) = gen_expr(arg, func, frame, loops, block)?;
+                arg_regs.push(arg_reg.context("cannot pass a statement as an argument")?);
+            }
+            let out = func.new_reg();
+            block.borrow_mut().instructions.push(Instruction::new(
+                out,
+                InstructionRHS::Call {
+                    func: callee.clone(),
+                    args: arg_regs.into_boxed_slice(),
+                },
+            ));
+            (Some(out), block)
+        }
+        ExprKind::Input => {
             let out = func.new_reg();
             block
                 .borrow_mut()
@@ -227,3 +409,98 @@ pub fn gen_expr(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use itertools::Itertools;
+
+    use crate::diagnostics::ErrorList;
+    use crate::interpreter::interpret;
+    use crate::ir::gen_test_ir;
+
+    #[test]
+    fn break_after_failed_loop() {
+        let source = "
+(define x (loop (break 1) (break)))
+(break)";
+        let Err(err) = gen_test_ir(source) else {
+            panic!("expected errors");
+        };
+        let ErrorList(errors) = err.downcast_ref().unwrap();
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect_vec(),
+            [
+                "all breaks out of a loop must agree on whether they carry a value",
+                "cannot break outside a loop"
+            ]
+        );
+    }
+
+    fn run(source: &str) -> Option<i64> {
+        let program = gen_test_ir(source).unwrap();
+        interpret(&program, "main", vec![], vec![], HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn shadowing() {
+        // an if arm is its own scope, so the outer x is untouched once it ends
+        let source = "
+(define x 1)
+(define y (if 0 (begin (define x 10) (set x (+ x 1)) x) 0))
+(return (+ (* 100 x) y))";
+        assert_eq!(run(source), Some(111));
+        // as is each iteration of a loop body, and the later operands of and/or
+        let source = "
+(define x 1)
+(define n 0)
+(loop (define x (+ n 5)) (set n x) (if (>= x 10) (continue) (break)))
+(define b (and 1 (begin (define x 0) (= x 0))))
+(return (+ (* 100 x) (* 10 n) (as i64 b)))";
+        assert_eq!(run(source), Some(201));
+        let Err(err) = gen_test_ir("(define x 1) (define x 2) (return x)") else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            format!("{:#}", err),
+            "variable x is already defined in this scope"
+        );
+    }
+
+    #[test]
+    fn labeled_loops() {
+        // breaking out of the outer loop skips the rest of both
+        let source = "
+(define n 0)
+(loop :label outer
+    (loop
+        (set n (+ n 1))
+        (if (>= n 3) (continue) (break :label outer))
+    )
+    (set n 100)
+)
+(return n)";
+        assert_eq!(run(source), Some(3));
+        // continuing the outer loop skips the rest of its body, except on the last iteration
+        let source = "
+(define total 0)
+(for :label outer (i 0 4)
+    (for (j 0 4)
+        (if (> j i) (set total (+ total 1)) (continue :label outer))
+    )
+    (set total (+ total 100))
+)
+(return total)";
+        assert_eq!(run(source), Some(110));
+        // a variable named like a label is still a value
+        let source = "
+(define outer 5)
+(return (loop :label outer (loop (break :label outer outer))))";
+        assert_eq!(run(source), Some(5));
+        let Err(err) = gen_test_ir("(loop :label outer (loop (break :label inner)))") else {
+            panic!("expected an error");
+        };
+        assert_eq!(format!("{:#}", err), "no enclosing loop is labeled inner");
+    }
+}
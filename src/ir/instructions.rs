@@ -3,20 +3,25 @@ use std::fmt::{self, Debug, Display, Formatter};
 use std::hash::Hash;
 use std::rc::Rc;
 
+use itertools::Itertools;
+
 use super::ssa_forms::CfgConfig;
 use super::structs::{BlockWithDebugIndex, WithRegisters};
-use crate::semantics::{BinaryOperator, UnaryOperator};
+use crate::semantics::{BinaryOperator, IntType, UnaryOperator};
 use crate::utils::frame::Frame;
 
 #[derive(Debug)]
 pub enum InstructionRHS<RegType> {
     ReadMemory(RegType),
+    // the result is wrapped to the width of the operation
     UnaryOperation {
         operator: UnaryOperator,
+        width: IntType,
         arg: RegType,
     },
     BinaryOperation {
         operator: BinaryOperator,
+        width: IntType,
         arg1: RegType,
         arg2: RegType,
     },
@@ -27,6 +32,32 @@ pub enum InstructionRHS<RegType> {
         src: RegType,
     },
     ReadInput,
+    // the index-th argument of the function, which is read in the start block
+    Param {
+        index: usize,
+    },
+    Call {
+        func: String,
+        args: Box<[RegType]>,
+    },
+    StoreMemory {
+        addr: RegType,
+        data: RegType,
+    },
+}
+
+impl<RegType> InstructionRHS<RegType> {
+    // params are kept even if unused, so that every argument of a function is still bound, and
+    // inputs so that each (input) consumes the next value
+    pub const fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            InstructionRHS::ReadInput
+                | InstructionRHS::Param { .. }
+                | InstructionRHS::Call { .. }
+                | InstructionRHS::StoreMemory { .. }
+        )
+    }
 }
 
 impl<RegType: Eq + Hash + Copy> InstructionRHS<RegType> {
@@ -36,16 +67,23 @@ impl<RegType: Eq + Hash + Copy> InstructionRHS<RegType> {
     ) -> Option<InstructionRHS<NewRegType>> {
         Some(match *self {
             InstructionRHS::ReadMemory(arg) => InstructionRHS::ReadMemory(frame.lookup(&arg)?),
-            InstructionRHS::UnaryOperation { operator, arg } => InstructionRHS::UnaryOperation {
+            InstructionRHS::UnaryOperation {
+                operator,
+                width,
+                arg,
+            } => InstructionRHS::UnaryOperation {
                 operator,
+                width,
                 arg: frame.lookup(&arg)?,
             },
             InstructionRHS::BinaryOperation {
                 operator,
+                width,
                 arg1,
                 arg2,
             } => InstructionRHS::BinaryOperation {
                 operator,
+                width,
                 arg1: frame.lookup(&arg1)?,
                 arg2: frame.lookup(&arg2)?,
             },
@@ -56,6 +94,18 @@ impl<RegType: Eq + Hash + Copy> InstructionRHS<RegType> {
                 src: frame.lookup(&src)?,
             },
             InstructionRHS::ReadInput => InstructionRHS::ReadInput,
+            InstructionRHS::Param { index } => InstructionRHS::Param { index },
+            InstructionRHS::Call { ref func, ref args } => InstructionRHS::Call {
+                func: func.clone(),
+                args: args
+                    .iter()
+                    .map(|arg| frame.lookup(arg))
+                    .collect::<Option<_>>()?,
+            },
+            InstructionRHS::StoreMemory { addr, data } => InstructionRHS::StoreMemory {
+                addr: frame.lookup(&addr)?,
+                data: frame.lookup(&data)?,
+            },
         })
     }
 }
@@ -64,15 +114,13 @@ impl<RegType> WithRegisters<RegType> for InstructionRHS<RegType> {
     fn regs(&self) -> <Vec<&RegType> as IntoIterator>::IntoIter {
         (match self {
             InstructionRHS::ReadMemory(arg) => vec![arg],
-            InstructionRHS::UnaryOperation { operator: _, arg } => vec![arg],
-            InstructionRHS::BinaryOperation {
-                operator: _,
-                arg1,
-                arg2,
-            } => vec![arg1, arg2],
+            InstructionRHS::UnaryOperation { arg, .. } => vec![arg],
+            InstructionRHS::BinaryOperation { arg1, arg2, .. } => vec![arg1, arg2],
             InstructionRHS::LoadIntegerLiteral { value: _ } => vec![],
             InstructionRHS::Move { src } => vec![src],
-            InstructionRHS::ReadInput => vec![],
+            InstructionRHS::ReadInput | InstructionRHS::Param { .. } => vec![],
+            InstructionRHS::Call { func: _, args } => args.iter().collect(),
+            InstructionRHS::StoreMemory { addr, data } => vec![addr, data],
         })
         .into_iter()
     }
@@ -80,15 +128,13 @@ impl<RegType> WithRegisters<RegType> for InstructionRHS<RegType> {
     fn regs_mut(&mut self) -> <Vec<&mut RegType> as IntoIterator>::IntoIter {
         (match self {
             InstructionRHS::ReadMemory(arg) => vec![arg],
-            InstructionRHS::UnaryOperation { operator: _, arg } => vec![arg],
-            InstructionRHS::BinaryOperation {
-                operator: _,
-                arg1,
-                arg2,
-            } => vec![arg1, arg2],
+            InstructionRHS::UnaryOperation { arg, .. } => vec![arg],
+            InstructionRHS::BinaryOperation { arg1, arg2, .. } => vec![arg1, arg2],
             InstructionRHS::LoadIntegerLiteral { value: _ } => vec![],
             InstructionRHS::Move { src } => vec![src],
-            InstructionRHS::ReadInput => vec![],
+            InstructionRHS::ReadInput | InstructionRHS::Param { .. } => vec![],
+            InstructionRHS::Call { func: _, args } => args.iter_mut().collect(),
+            InstructionRHS::StoreMemory { addr, data } => vec![addr, data],
         })
         .into_iter()
     }
@@ -96,13 +142,21 @@ impl<RegType> WithRegisters<RegType> for InstructionRHS<RegType> {
 
 #[derive(Debug)]
 pub struct Instruction<Conf: CfgConfig> {
-    pub lhs: Conf::LValue,
+    // instructions run only for their side effects (e.g. stores) do not define a register
+    pub lhs: Option<Conf::LValue>,
     pub rhs: Conf::RHSType,
 }
 
 impl<Conf: CfgConfig> Instruction<Conf> {
     pub fn new(lhs: Conf::LValue, rhs: Conf::RHSType) -> Self {
-        Self { lhs, rhs }
+        Self {
+            lhs: Some(lhs),
+            rhs,
+        }
+    }
+
+    pub fn new_effect(rhs: Conf::RHSType) -> Self {
+        Self { lhs: None, rhs }
     }
 }
 
@@ -112,7 +166,10 @@ where
     Conf::RHSType: Display,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{} = {}", self.lhs, self.rhs)
+        match &self.lhs {
+            Some(lhs) => write!(f, "{} = {}", lhs, self.rhs),
+            None => write!(f, "{}", self.rhs),
+        }
     }
 }
 
@@ -122,15 +179,20 @@ impl<T: Display> Display for InstructionRHS<T> {
             InstructionRHS::ReadMemory(arg) => {
                 write!(f, "read {arg}")
             }
-            InstructionRHS::UnaryOperation { operator, arg } => {
-                write!(f, "{operator:?} {arg}")
+            InstructionRHS::UnaryOperation {
+                operator,
+                width,
+                arg,
+            } => {
+                write!(f, "{operator:?}{} {arg}", WidthSuffix(*width))
             }
             InstructionRHS::BinaryOperation {
                 operator,
+                width,
                 arg1,
                 arg2,
             } => {
-                write!(f, "{arg1} {operator:?} {arg2}")
+                write!(f, "{arg1} {operator:?}{} {arg2}", WidthSuffix(*width))
             }
             InstructionRHS::LoadIntegerLiteral { value } => {
                 write!(f, "{value}")
@@ -141,6 +203,27 @@ impl<T: Display> Display for InstructionRHS<T> {
             InstructionRHS::ReadInput => {
                 write!(f, "input()")
             }
+            InstructionRHS::Param { index } => {
+                write!(f, "param {index}")
+            }
+            InstructionRHS::Call { func, args } => {
+                write!(f, "call {func}({})", args.iter().join(", "))
+            }
+            InstructionRHS::StoreMemory { addr, data } => {
+                write!(f, "write {addr} {data}")
+            }
+        }
+    }
+}
+
+// most operations are on i64s, so only other widths are printed
+struct WidthSuffix(IntType);
+
+impl Display for WidthSuffix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            IntType::I64 => Ok(()),
+            width => write!(f, ".{width}"),
         }
     }
 }
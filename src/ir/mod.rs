@@ -1,12 +1,15 @@
 use anyhow::Result;
+use itertools::Itertools;
 
 use self::dominance::{
     dominance_frontiers, find_immediate_dominators, find_immediately_dominated,
     sort_blocks_postorder,
 };
+pub use self::dot::{to_dot, DotBlock};
 use self::gen::gen_expr;
 use self::instructions::InstructionRHS;
 pub use self::instructions::{Instruction, JumpInstruction};
+pub use self::parse::parse_ir;
 pub use self::ssa_forms::CfgConfig;
 use self::ssa_forms::{InitialCfg, SSAConfig};
 use self::ssa_transform::{
@@ -16,15 +19,20 @@ use self::ssa_transform::{
 pub use self::structs::{
     FullBlock, Function, Phi, RegisterLValue, VirtualRegister, VirtualRegisterLValue, WithRegisters,
 };
+pub use self::verify::verify;
+use crate::diagnostics::collect_errors;
 use crate::semantics::{FuncDefinition, Program};
 use crate::utils::frame::Frame;
 
 mod dominance;
+mod dot;
 mod gen;
 mod instructions;
+mod parse;
 mod ssa_forms;
 mod ssa_transform;
 mod structs;
+mod verify;
 
 pub type SSABlock = FullBlock<SSAConfig>;
 pub type SSAPhi = Phi<SSAConfig>;
@@ -33,34 +41,78 @@ pub type SSAInstruction = Instruction<SSAConfig>;
 pub type SSAInstructionRHS = InstructionRHS<VirtualRegister>;
 pub type SSAJumpInstruction = JumpInstruction<SSAConfig>;
 
+// the control-flow graph of a function, before conversion to SSA
+pub type InitialFunction = Function<InitialCfg>;
+
+fn gen_cfg(func_def: &FuncDefinition) -> Result<InitialFunction> {
+    let mut frame = Frame::new();
+    let mut func: Function<InitialCfg> = Function::new();
+
+    let start_block = func.new_block();
+
+    for (index, arg) in func_def.args.iter().enumerate() {
+        let reg = func.new_reg();
+        frame.assoc(arg.clone(), reg);
+        start_block
+            .borrow_mut()
+            .instructions
+            .push(Instruction::new(reg, InstructionRHS::Param { index }))
+    }
+
+    gen_expr(
+        &func_def.body,
+        &mut func,
+        &mut frame,
+        &mut vec![],
+        start_block,
+    )?;
+
+    Ok(func)
+}
+
+// the number of arguments func takes, which is known since params are never removed
+pub fn arity(func: &SSAFunction) -> usize {
+    func.blocks()
+        .flat_map(|block| {
+            block
+                .borrow()
+                .instructions
+                .iter()
+                .filter_map(|inst| match inst.rhs {
+                    InstructionRHS::Param { index } => Some(index + 1),
+                    _ => None,
+                })
+                .collect_vec()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// the control-flow graph of every function, as it is before gen_ir converts it to SSA
+pub fn gen_cfgs(program: &Program<FuncDefinition>) -> Result<Program<InitialFunction>> {
+    let funcs = program
+        .funcs
+        .iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(func_name, func_def)| Ok((func_name.to_string(), gen_cfg(func_def)?)));
+
+    Ok(Program {
+        funcs: collect_errors(funcs)?.into_iter().collect(),
+    })
+}
+
 pub fn gen_ir(program: &Program<FuncDefinition>) -> Result<Program<SSAFunction>> {
+    // each function is generated even if an earlier one fails, to report all of their errors
     let funcs = program
         .funcs
         .iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
         .map(|(func_name, func_def)| {
-            let mut frame = Frame::new();
-            let mut func: Function<InitialCfg> = Function::new();
-
-            let start_block = func.new_block();
-
-            for arg in func_def.args.iter() {
-                let reg = func.new_reg();
-                frame.assoc(arg.clone(), reg);
-                start_block
-                    .borrow_mut()
-                    .instructions
-                    .push(Instruction::new(reg, InstructionRHS::ReadInput))
-            }
-
-            gen_expr(
-                &func_def.body,
-                &mut func,
-                &mut frame,
-                &mut vec![],
-                start_block.clone(),
-            )?;
+            let cfg = gen_cfg(func_def)?;
 
-            // println!("{}", func);
+            // println!("{}", cfg);
+
+            let start_block = cfg.start_block;
 
             let (sorted_blocks, index_lookup, predecessors) =
                 sort_blocks_postorder(start_block.clone());
@@ -146,8 +198,15 @@ pub fn gen_ir(program: &Program<FuncDefinition>) -> Result<Program<SSAFunction>>
             backfill_ssa_phis(&blocks, &ssa_blocks, &ssa_frames, &ssa_phi_vars);
 
             Ok((func_name.to_string(), func))
-        })
-        .collect::<Result<_>>()?;
+        });
+
+    Ok(Program {
+        funcs: collect_errors(funcs)?.into_iter().collect(),
+    })
+}
 
-    Ok(Program { funcs })
+// generates the unoptimized SSA IR for source, as load_test_program loads it, for tests
+#[cfg(test)]
+pub fn gen_test_ir(source: &str) -> Result<Program<SSAFunction>> {
+    gen_ir(&crate::semantics::load_test_program(source)?)
 }
@@ -0,0 +1,516 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    Instruction, JumpInstruction, Phi, RegisterLValue, SSABlock, SSAFunction, SSAInstructionRHS,
+    SSAJumpInstruction, SSAPhi, VirtualRegister, VirtualRegisterLValue,
+};
+use crate::diagnostics::{bail_at, Span, WithSpan};
+use crate::semantics::{BinaryOperator, IntType, Program, Type, UnaryOperator};
+
+const UNARY_OPERATORS: &[UnaryOperator] = &[UnaryOperator::Not, UnaryOperator::Wrap];
+
+const BINARY_OPERATORS: &[BinaryOperator] = &[
+    BinaryOperator::Add,
+    BinaryOperator::Mul,
+    BinaryOperator::Sub,
+    BinaryOperator::Div,
+    BinaryOperator::Xor,
+    BinaryOperator::And,
+    BinaryOperator::Or,
+    BinaryOperator::Shl,
+    BinaryOperator::Shr,
+    BinaryOperator::Shru,
+    BinaryOperator::Rem,
+    BinaryOperator::Divu,
+    BinaryOperator::Remu,
+    BinaryOperator::Eq,
+    BinaryOperator::Ne,
+    BinaryOperator::Lt,
+    BinaryOperator::Le,
+    BinaryOperator::Gt,
+    BinaryOperator::Ge,
+    BinaryOperator::Ltu,
+    BinaryOperator::Leu,
+    BinaryOperator::Gtu,
+    BinaryOperator::Geu,
+];
+
+// operators are printed with their Debug names, followed by their width unless it is i64
+fn operator<T: Copy + Debug>(text: &str, operators: &[T]) -> Result<(T, IntType)> {
+    let (name, width) = match text.split_once('.') {
+        Some((name, width)) => match Type::parse(width) {
+            Some(Type::Int(width)) => (name, width),
+            _ => bail!("unknown operation width {}", width),
+        },
+        None => (text, IntType::I64),
+    };
+    let operator = operators
+        .iter()
+        .find(|operator| format!("{:?}", operator) == name)
+        .with_context(|| format!("unknown operator {}", name))?;
+    Ok((*operator, width))
+}
+
+fn block_index(text: &str) -> Result<u16> {
+    text.parse()
+        .with_context(|| format!("expected a block number, but found {}", text))
+}
+
+struct FuncParser {
+    func: SSAFunction,
+    blocks: HashMap<u16, Rc<RefCell<SSABlock>>>,
+    // the highest register index in the text
+    max_reg: u16,
+}
+
+impl FuncParser {
+    fn reg(&mut self, text: &str) -> Result<VirtualRegister> {
+        let index = text
+            .strip_prefix('%')
+            .and_then(|index| index.parse().ok())
+            .with_context(|| format!("expected a register, but found {}", text))?;
+        self.max_reg = self.max_reg.max(index);
+        Ok(VirtualRegisterLValue::new(index).0)
+    }
+
+    fn block(&self, text: &str) -> Result<Rc<RefCell<SSABlock>>> {
+        let index = block_index(text)?;
+        self.blocks
+            .get(&index)
+            .cloned()
+            .with_context(|| format!("block {} is not defined", index))
+    }
+
+    // "block <n> (preds=[<n>, ...])"
+    fn header(&mut self, text: &str) -> Result<Rc<RefCell<SSABlock>>> {
+        let (index, preds) = text
+            .strip_prefix("block ")
+            .and_then(|rest| rest.strip_suffix("])"))
+            .and_then(|rest| rest.split_once(" (preds=["))
+            .context("expected a block header, like block 1 (preds=[0])")?;
+        let block = self.block(index)?;
+        for pred in preds.split(", ").filter(|pred| !pred.is_empty()) {
+            let pred = self.block(pred)?;
+            block.borrow_mut().preds.insert(Rc::downgrade(&pred).into());
+        }
+        Ok(block)
+    }
+
+    // "%<n> = phi(%<n> from block <n>, ...)"
+    fn phi(&mut self, dest: &str, srcs: &str) -> Result<SSAPhi> {
+        let dest = VirtualRegisterLValue(self.reg(dest)?);
+        let mut phi = Phi {
+            srcs: HashMap::new(),
+            dest,
+        };
+        for src in srcs.split(", ").filter(|src| !src.is_empty()) {
+            let (reg, block) = src
+                .split_once(" from block ")
+                .context("expected a phi source, like %1 from block 0")?;
+            let reg = self.reg(reg)?;
+            let block = self.block(block)?;
+            phi.srcs.insert(Rc::downgrade(&block).into(), reg);
+        }
+        Ok(phi)
+    }
+
+    fn rhs(&mut self, text: &str) -> Result<SSAInstructionRHS> {
+        if text == "input()" {
+            return Ok(SSAInstructionRHS::ReadInput);
+        }
+        if let Some(index) = text.strip_prefix("param ") {
+            let index = index
+                .parse()
+                .with_context(|| format!("expected a parameter number, but found {}", index))?;
+            return Ok(SSAInstructionRHS::Param { index });
+        }
+        if let Some(addr) = text.strip_prefix("read ") {
+            return Ok(SSAInstructionRHS::ReadMemory(self.reg(addr)?));
+        }
+        if let Some(call) = text.strip_prefix("call ") {
+            let (func, args) = call
+                .strip_suffix(')')
+                .and_then(|call| call.split_once('('))
+                .context("expected a call, like call f(%1, %2)")?;
+            let args = args
+                .split(", ")
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| self.reg(arg))
+                .collect::<Result<_>>()?;
+            return Ok(SSAInstructionRHS::Call {
+                func: func.to_string(),
+                args,
+            });
+        }
+        Ok(match *text.split(' ').collect::<Vec<_>>() {
+            ["write", addr, data] => SSAInstructionRHS::StoreMemory {
+                addr: self.reg(addr)?,
+                data: self.reg(data)?,
+            },
+            [src] if src.starts_with('%') => SSAInstructionRHS::Move {
+                src: self.reg(src)?,
+            },
+            [value] => SSAInstructionRHS::LoadIntegerLiteral {
+                value: value
+                    .parse()
+                    .with_context(|| format!("unknown instruction {}", value))?,
+            },
+            [operator, arg] => {
+                let (operator, width) = self::operator(operator, UNARY_OPERATORS)?;
+                SSAInstructionRHS::UnaryOperation {
+                    operator,
+                    width,
+                    arg: self.reg(arg)?,
+                }
+            }
+            [arg1, operator, arg2] => {
+                let (operator, width) = self::operator(operator, BINARY_OPERATORS)?;
+                SSAInstructionRHS::BinaryOperation {
+                    operator,
+                    width,
+                    arg1: self.reg(arg1)?,
+                    arg2: self.reg(arg2)?,
+                }
+            }
+            _ => bail!("unknown instruction {}", text),
+        })
+    }
+
+    // returns None if the line is not a jump or return
+    fn exit(&mut self, text: &str) -> Result<Option<SSAJumpInstruction>> {
+        Ok(Some(if let Some(rest) = text.strip_prefix("if ") {
+            let (pred, rest) = rest
+                .split_once("==0 branchto ")
+                .context("expected a branch, like if %1==0 branchto 1 else 2")?;
+            let (conseq, alt) = rest
+                .split_once(" else ")
+                .context("expected a branch, like if %1==0 branchto 1 else 2")?;
+            JumpInstruction::BranchIfElseZero {
+                pred: self.reg(pred)?,
+                conseq: self.block(conseq)?,
+                alt: self.block(alt)?,
+            }
+        } else if let Some(dest) = text.strip_prefix("jumpto ") {
+            JumpInstruction::UnconditionalJump {
+                dest: self.block(dest)?,
+            }
+        } else if text == "ret" {
+            JumpInstruction::Ret(None)
+        } else if let Some(value) = text.strip_prefix("ret ") {
+            JumpInstruction::Ret(Some(self.reg(value)?))
+        } else {
+            return Ok(None);
+        }))
+    }
+
+    // fills in the blocks from the lines of one function, which all start with a block header
+    fn body(&mut self, lines: &[(Span, &str)]) -> Result<()> {
+        let mut block: Option<Rc<RefCell<SSABlock>>> = None;
+        // whether the current block has seen its exit, and so must be followed by a new one
+        let mut ended = true;
+        for &(span, line) in lines {
+            if line.starts_with("block ") {
+                if !ended {
+                    bail_at!(span, "the previous block must end with a jump or ret");
+                }
+                block = Some(self.header(line).at(span)?);
+                ended = false;
+                continue;
+            }
+            let Some(block) = block.as_ref().filter(|_| !ended) else {
+                bail_at!(span, "expected a block header");
+            };
+            if let Some(exit) = self.exit(line).at(span)? {
+                block.borrow_mut().exit = exit;
+                ended = true;
+            } else if let Some((lhs, rhs)) = line.split_once(" = ") {
+                if let Some(srcs) = rhs.strip_prefix("phi(").and_then(|s| s.strip_suffix(')')) {
+                    if !block.borrow().instructions.is_empty() {
+                        bail_at!(span, "phis must come before any other instructions");
+                    }
+                    let phi = self.phi(lhs, srcs).at(span)?;
+                    block.borrow_mut().phis.push(phi);
+                } else {
+                    let lhs = VirtualRegisterLValue(self.reg(lhs).at(span)?);
+                    let rhs = self.rhs(rhs).at(span)?;
+                    block
+                        .borrow_mut()
+                        .instructions
+                        .push(Instruction::new(lhs, rhs));
+                }
+            } else {
+                let rhs = self.rhs(line).at(span)?;
+                block
+                    .borrow_mut()
+                    .instructions
+                    .push(Instruction::new_effect(rhs));
+            }
+        }
+        if !ended {
+            bail!("the last block must end with a jump or ret");
+        }
+        Ok(())
+    }
+}
+
+// parses a function, which starts with "start: <n>", from its lines
+fn parse_func(lines: &[(Span, &str)]) -> Result<SSAFunction> {
+    let Some(((start_span, start), lines)) = lines.split_first() else {
+        bail!("expected start: <block number>");
+    };
+    let Some(start) = start.strip_prefix("start: ") else {
+        bail_at!(*start_span, "expected start: <block number>");
+    };
+
+    let mut parser = FuncParser {
+        func: SSAFunction::new(),
+        blocks: HashMap::new(),
+        max_reg: 0,
+    };
+    // blocks are created up front, in order, since jumps and phis can refer to later blocks
+    for (span, line) in lines {
+        if let Some(rest) = line.strip_prefix("block ") {
+            let index = block_index(rest.split(' ').next().unwrap()).at(*span)?;
+            let block = parser.func.new_block();
+            block.borrow_mut().debug_index = index;
+            if parser.blocks.insert(index, block).is_some() {
+                bail_at!(*span, "block {} is defined twice", index);
+            }
+        }
+    }
+    parser.func.start_block = parser.block(start).at(*start_span)?;
+    parser.body(lines)?;
+
+    // blocks are only kept alive by jumps to them, so any others would silently vanish
+    for (index, block) in parser.blocks.iter() {
+        let jumped_to = parser.blocks.values().any(|other| {
+            other
+                .borrow()
+                .exit
+                .dests()
+                .any(|dest| Rc::ptr_eq(dest, block))
+        });
+        if !jumped_to && !Rc::ptr_eq(block, &parser.func.start_block) {
+            bail!(
+                "block {} is not the start block, and is never jumped to",
+                index
+            );
+        }
+    }
+    parser.func.clear_dead_blocks();
+    // so that registers made by later passes do not clash with those in the text
+    for _ in 0..parser.max_reg {
+        parser.func.new_reg();
+    }
+    Ok(parser.func)
+}
+
+// parses functions in the format they are printed in, each preceded by "func <name>:".
+// Comments run from ; to the end of the line. base locates the source within a SourceMap
+pub fn parse_ir(source: &str, base: usize) -> Result<Program<SSAFunction>> {
+    let mut lines = vec![];
+    let mut offset = base;
+    for line in source.split_inclusive('\n') {
+        let code = line.split(';').next().unwrap();
+        let start = offset + code.len() - code.trim_start().len();
+        let code = code.trim();
+        if !code.is_empty() {
+            lines.push((Span::new(start, start + code.len()), code));
+        }
+        offset += line.len();
+    }
+
+    let mut funcs = HashMap::new();
+    let mut rest = &lines[..];
+    while let Some(((span, header), body)) = rest.split_first() {
+        let Some(name) = header
+            .strip_prefix("func ")
+            .and_then(|name| name.strip_suffix(':'))
+        else {
+            bail_at!(*span, "expected func <name>:");
+        };
+        let len = body
+            .iter()
+            .position(|(_, line)| line.starts_with("func "))
+            .unwrap_or(body.len());
+        let func = parse_func(&body[..len]).at(*span)?;
+        if funcs.insert(name.to_string(), func).is_some() {
+            bail_at!(*span, "function {} is defined twice", name);
+        }
+        rest = &body[len..];
+    }
+    Ok(Program { funcs })
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::parse_ir;
+    use crate::ir::{gen_test_ir, SSAFunction};
+    use crate::optimizations::optimize;
+    use crate::semantics::Program;
+
+    // as printed by --emit-ir
+    const PROGRAM: &str = "\
+func g:
+start: 0
+
+block 0 (preds=[])
+%3 = param 0
+%4 = param 1
+%5 = %3 Eq.u64 %4
+if %5==0 branchto 3 else 1
+
+block 1 (preds=[0])
+%6 = 0
+%7 = 1
+write %6 %7
+jumpto 3
+
+block 3 (preds=[0, 1])
+ret
+
+
+func h:
+start: 2
+
+block 2 (preds=[])
+%1 = input()
+%2 = Not.u8 %1
+call g(%1, %2)
+%3 = read %2
+%4 = call f()
+ret %3
+
+
+func main:
+start: 0
+
+block 0 (preds=[])
+%10 = param 0
+%11 = 0
+jumpto 1
+
+block 1 (preds=[0, 5])
+%7 = phi(%11 from block 0, %17 from block 5)
+%13 = %7 Lt %10
+%14 = 0
+%15 = %13 Eq.u64 %14
+if %15==0 branchto 5 else 3
+
+block 3 (preds=[1])
+%19 = 3
+%20 = call g(%7, %19)
+ret %7
+
+block 5 (preds=[1])
+%16 = input()
+%17 = %16 Add %7
+jumpto 1
+
+
+";
+
+    fn print(program: &Program<SSAFunction>) -> String {
+        program
+            .funcs
+            .iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(name, func)| format!("func {name}:\n{func}\n"))
+            .collect()
+    }
+
+    fn round_trip(source: &str) -> String {
+        print(&parse_ir(source, 0).unwrap())
+    }
+
+    fn error(source: &str) -> String {
+        match parse_ir(source, 0) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => format!("{err:#}"),
+        }
+    }
+
+    #[test]
+    fn prints_what_it_parses() {
+        assert_eq!(round_trip(PROGRAM), PROGRAM);
+    }
+
+    #[test]
+    fn parses_what_the_compiler_prints() {
+        let source = "
+(func (g (p u64) (q u64)) (if (= p q) (write 0 1)))
+(func (main n)
+    (define i 0)
+    (while (< i n) (set i (+ i (input))))
+    (g (as u64 i) 3)
+    (return i))";
+        let mut program = gen_test_ir(source).unwrap();
+        optimize(&mut program, false, true).unwrap();
+        let printed = print(&program);
+        assert_eq!(round_trip(&printed), printed);
+    }
+
+    #[test]
+    fn ignores_comments() {
+        let commented = PROGRAM.replace("block 1 (preds=[0])\n", "block 1 (preds=[0]) ; then\n");
+        assert_eq!(round_trip(&commented), PROGRAM);
+    }
+
+    #[test]
+    fn undefined_block() {
+        let source = "func main:\nstart: 0\nblock 0 (preds=[])\njumpto 4\n";
+        assert_eq!(error(source), "block 4 is not defined");
+    }
+
+    #[test]
+    fn duplicate_block() {
+        let source = "func main:\nstart: 0\nblock 0 (preds=[])\nret\nblock 0 (preds=[])\nret\n";
+        assert_eq!(error(source), "block 0 is defined twice");
+    }
+
+    #[test]
+    fn phi_after_instruction() {
+        let source = "\
+func main:
+start: 0
+block 0 (preds=[])
+%1 = 1
+jumpto 1
+block 1 (preds=[0])
+%2 = 2
+%3 = phi(%1 from block 0)
+ret %3
+";
+        assert_eq!(
+            error(source),
+            "phis must come before any other instructions"
+        );
+    }
+
+    #[test]
+    fn missing_exit() {
+        let source = "func main:\nstart: 0\nblock 0 (preds=[])\n%1 = 1\n";
+        assert_eq!(error(source), "the last block must end with a jump or ret");
+        let source = "func main:\nstart: 0\nblock 0 (preds=[])\n%1 = 1\nblock 1 (preds=[])\nret\n";
+        assert_eq!(
+            error(source),
+            "the previous block must end with a jump or ret"
+        );
+    }
+
+    #[test]
+    fn unreachable_block() {
+        let source = "func main:\nstart: 0\nblock 0 (preds=[])\nret\nblock 1 (preds=[])\nret\n";
+        assert_eq!(
+            error(source),
+            "block 1 is not the start block, and is never jumped to"
+        );
+    }
+}
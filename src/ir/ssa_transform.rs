@@ -33,9 +33,11 @@ pub fn defining_blocks_for_variables(
     let mut out = HashMap::new();
     for block in blocks.iter() {
         for inst in &block.borrow().instructions {
-            out.entry(inst.lhs)
-                .or_insert_with(HashSet::new)
-                .insert(block.clone().into());
+            if let Some(lhs) = inst.lhs {
+                out.entry(lhs)
+                    .or_insert_with(HashSet::new)
+                    .insert(block.clone().into());
+            }
         }
     }
     out
@@ -118,12 +120,15 @@ pub fn populate_ssa_blocks(
                     .rhs
                     .map_reg_types(frame)
                     .expect("all RHS registers should be defined in a dominating or phi block");
-                let reg @ VirtualRegisterLValue(reg_ref) = func.new_reg();
-                frame.assoc(inst.lhs, reg_ref);
+                let lhs = inst.lhs.map(|lhs| {
+                    let reg @ VirtualRegisterLValue(reg_ref) = func.new_reg();
+                    frame.assoc(lhs, reg_ref);
+                    reg
+                });
                 ssa_block
                     .borrow_mut()
                     .instructions
-                    .push(Instruction::new(reg, rhs));
+                    .push(Instruction { lhs, rhs });
             }
 
             ssa_block.borrow_mut().exit = block
@@ -159,6 +164,8 @@ pub fn backfill_ssa_phis(
     frames: &VirtualRegisterFrameLookup,
     phi_vars: &PhiVariableReverseLookup,
 ) {
+    // phis for variables that are out of scope in some predecessor, which are dropped
+    let mut dropped = HashSet::new();
     for block in blocks {
         let src_ssa_block = ssa_blocks
             .get(&block.as_key())
@@ -183,12 +190,34 @@ pub fn backfill_ssa_phis(
                     let var = dest_phi_vars
                         .get(dest)
                         .expect("all phi blocks must have a reverse var mapping");
-                    src_frame.lookup(var).map_or(true, |src_reg| {
-                        srcs.insert(Rc::downgrade(src_ssa_block).into(), src_reg);
-                        false
-                    })
+                    src_frame.lookup(var).map_or_else(
+                        || {
+                            dropped.insert(*dest);
+                            true
+                        },
+                        |src_reg| {
+                            srcs.insert(Rc::downgrade(src_ssa_block).into(), src_reg);
+                            false
+                        },
+                    )
                 });
             }
         }
     }
+
+    // the variable of a dropped phi is out of scope wherever it flows, so any phi merging it into
+    // another block is dropped too
+    while !dropped.is_empty() {
+        let mut next = HashSet::new();
+        for ssa_block in ssa_blocks.values() {
+            ssa_block
+                .borrow_mut()
+                .phis
+                .drain_filter(|phi| phi.srcs.values().any(|src| dropped.contains(src)))
+                .for_each(|phi| {
+                    next.insert(phi.dest.0);
+                });
+        }
+        dropped = next;
+    }
 }
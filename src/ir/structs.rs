@@ -98,6 +98,10 @@ pub trait BlockWithDebugIndex {
     fn get_debug_index(&self) -> u16;
 }
 
+pub trait BlockWithSuccessors: Sized {
+    fn successors(&self) -> Vec<Rc<RefCell<Self>>>;
+}
+
 pub trait RegisterLValue {
     type RValue;
     fn new(index: u16) -> Self;
@@ -116,7 +120,13 @@ pub struct Block {
     pub exit: JumpInstruction<InitialCfg>,
 }
 
-pub type BlockRef = Rc<RefCell<Block>>;
+pub type BlockRef<B = Block> = Rc<RefCell<B>>;
+
+impl BlockWithSuccessors for Block {
+    fn successors(&self) -> Vec<Rc<RefCell<Self>>> {
+        self.exit.dests().cloned().collect()
+    }
+}
 
 impl BlockWithDebugIndex for Block {
     fn new_with_index(debug_index: u16) -> Self {
@@ -158,6 +168,29 @@ impl<Conf: CfgConfig> FullBlock<Conf> {
             .iter()
             .filter_map(|pred| pred.get_ref().upgrade())
     }
+
+    // forgets a predecessor that no longer jumps to this block
+    pub fn remove_pred(&mut self, pred: *const RefCell<Self>) {
+        self.preds.remove(&pred);
+        for phi in &mut self.phis {
+            phi.srcs.remove(&pred);
+        }
+    }
+
+    // forgets predecessors that have been dropped, since they were unreachable
+    pub fn remove_dead_preds(&mut self) {
+        self.preds.retain(|pred| pred.get_ref().upgrade().is_some());
+        for phi in &mut self.phis {
+            phi.srcs
+                .retain(|pred, _| pred.get_ref().upgrade().is_some());
+        }
+    }
+}
+
+impl<Conf: CfgConfig<BlockType = Self>> BlockWithSuccessors for FullBlock<Conf> {
+    fn successors(&self) -> Vec<Rc<RefCell<Self>>> {
+        self.exit.dests().cloned().collect()
+    }
 }
 
 impl<Conf: CfgConfig> BlockWithDebugIndex for FullBlock<Conf> {
@@ -199,8 +232,10 @@ where
             f,
             "block {} (preds=[{}])",
             self.debug_index,
+            // sorted, so that the same block always prints the same way
             self.preds()
-                .map(|pred| format!("{}", pred.borrow().debug_index))
+                .map(|pred| pred.borrow().debug_index)
+                .sorted()
                 .join(", ")
         )?;
         for phi in &self.phis {
@@ -287,17 +322,12 @@ where
             self.srcs
                 .iter()
                 .map(|(block, reg)| {
-                    format!(
-                        "{} from block {}",
-                        reg,
-                        block
-                            .get_ref()
-                            .upgrade()
-                            .unwrap()
-                            .borrow()
-                            .get_debug_index()
-                    )
+                    let block = block.get_ref().upgrade().unwrap();
+                    let index = block.borrow().get_debug_index();
+                    (index, reg)
                 })
+                .sorted_by_key(|(index, _)| *index)
+                .map(|(index, reg)| format!("{} from block {}", reg, index))
                 .join(", ")
         )
     }
@@ -0,0 +1,237 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+
+use super::dominance::{find_immediate_dominators, sort_blocks_postorder, BlockDataLookup};
+use super::{SSABlock, SSAFunction, VirtualRegister, WithRegisters};
+use crate::diagnostics::collect_errors;
+use crate::utils::rcequality::RcDereferencable;
+
+type BlockKey = *const RefCell<SSABlock>;
+
+struct Verifier {
+    // the debug index of every block in the function
+    names: HashMap<BlockKey, u16>,
+    // where each register is assigned: its block, and 0 for phis or 1 + the instruction index
+    defs: HashMap<VirtualRegister, (BlockKey, usize)>,
+    dominators: BlockDataLookup<Rc<RefCell<SSABlock>>, SSABlock>,
+    errors: Vec<String>,
+}
+
+impl Verifier {
+    fn names(&self, blocks: &HashSet<BlockKey>) -> String {
+        blocks
+            .iter()
+            .map(|block| self.names[block])
+            .sorted()
+            .join(", ")
+    }
+
+    // whether a strictly dominates b
+    fn dominates(&self, a: BlockKey, b: BlockKey) -> bool {
+        let mut pos = b;
+        loop {
+            let idom = self.dominators[&pos].as_key();
+            if idom == pos {
+                return false;
+            }
+            if idom == a {
+                return true;
+            }
+            pos = idom;
+        }
+    }
+
+    // checks a use of reg at position pos in block, numbered as in defs
+    fn check_use(&mut self, reg: &VirtualRegister, block: BlockKey, pos: usize, what: &str) {
+        let Some(&(def_block, def_pos)) = self.defs.get(reg) else {
+            self.errors.push(format!(
+                "register {} is used by {} in block {}, but never assigned",
+                reg, what, self.names[&block]
+            ));
+            return;
+        };
+        // the dominance of unreachable blocks is meaningless
+        if !self.dominators.contains_key(&block) {
+            return;
+        }
+        let available = if def_block == block {
+            def_pos < pos
+        } else {
+            self.dominates(def_block, block)
+        };
+        if !available {
+            self.errors.push(format!(
+                "register {} is used by {} in block {}, but its assignment in block {} does not \
+                 dominate that use",
+                reg, what, self.names[&block], self.names[&def_block]
+            ));
+        }
+    }
+}
+
+// checks that func is in SSA form, and that its blocks are consistently linked.
+// Errors say which function was being checked, and after which stage of compilation
+pub fn verify(name: &str, func: &SSAFunction, stage: &str) -> Result<()> {
+    let blocks = func.blocks().collect_vec();
+    let live = blocks
+        .iter()
+        .map(|block| block.as_key())
+        .collect::<HashSet<_>>();
+
+    let (reachable, index_lookup, predecessors) = sort_blocks_postorder(func.start_block.clone());
+    let mut verifier = Verifier {
+        names: blocks
+            .iter()
+            .chain(reachable.iter())
+            .map(|block| (block.as_key(), block.borrow().debug_index))
+            .collect(),
+        defs: HashMap::new(),
+        dominators: find_immediate_dominators(
+            func.start_block.clone(),
+            &reachable,
+            &index_lookup,
+            &predecessors,
+        ),
+        errors: vec![],
+    };
+    for block in reachable.iter() {
+        if !live.contains(&block.as_key()) {
+            verifier.errors.push(format!(
+                "block {} is reachable, but missing from the function's blocks",
+                block.borrow().debug_index
+            ));
+        }
+    }
+
+    // every edge should be recorded at both of its ends
+    let mut jumps_from = HashMap::<BlockKey, HashSet<BlockKey>>::new();
+    for block in &blocks {
+        jumps_from.entry(block.as_key()).or_default();
+        for dest in block.borrow().exit.dests() {
+            jumps_from
+                .entry(dest.as_key())
+                .or_default()
+                .insert(block.as_key());
+        }
+    }
+    for block in &blocks {
+        let block_ref = block.borrow();
+        let debug_index = block_ref.debug_index;
+        let mut preds = HashSet::new();
+        for pred in block_ref.preds.iter() {
+            if pred.get_ref().upgrade().is_some() {
+                preds.insert(pred.get_ref().as_key());
+            } else {
+                verifier.errors.push(format!(
+                    "block {} has a predecessor that no longer exists",
+                    debug_index
+                ));
+            }
+        }
+        let actual = &jumps_from[&block.as_key()];
+        // a dangling pred has already been reported, and has no name to print
+        if live.is_superset(&preds) && &preds != actual {
+            verifier.errors.push(format!(
+                "block {} has preds=[{}], but is jumped to by [{}]",
+                debug_index,
+                verifier.names(&preds),
+                verifier.names(actual)
+            ));
+        }
+        for phi in &block_ref.phis {
+            let mut srcs = HashSet::new();
+            for pred in phi.srcs.keys() {
+                if pred.get_ref().upgrade().is_some() {
+                    srcs.insert(pred.get_ref().as_key());
+                } else {
+                    verifier.errors.push(format!(
+                        "phi {} in block {} has a source from a block that no longer exists",
+                        phi.dest, debug_index
+                    ));
+                }
+            }
+            if live.is_superset(&srcs) && live.is_superset(&preds) && srcs != preds {
+                verifier.errors.push(format!(
+                    "phi {} in block {} has sources from [{}], but its block has preds=[{}]",
+                    phi.dest,
+                    debug_index,
+                    verifier.names(&srcs),
+                    verifier.names(&preds)
+                ));
+            }
+        }
+    }
+
+    for block in &blocks {
+        let block_ref = block.borrow();
+        let phi_dests = block_ref.phis.iter().map(|phi| (&phi.dest, 0));
+        let inst_dests = block_ref
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, inst)| Some((inst.lhs.as_ref()?, i + 1)));
+        for (lhs, pos) in phi_dests.chain(inst_dests) {
+            if verifier.defs.insert(lhs.0, (block.as_key(), pos)).is_some() {
+                verifier
+                    .errors
+                    .push(format!("register {} is assigned more than once", lhs));
+            }
+        }
+    }
+    for block in &blocks {
+        let block_ref = block.borrow();
+        for phi in &block_ref.phis {
+            // a phi reads each source as control leaves the corresponding predecessor
+            for (pred, src) in phi.srcs.iter() {
+                if live.contains(&pred.get_ref().as_key()) {
+                    let what = format!("phi {}", phi.dest);
+                    verifier.check_use(src, pred.get_ref().as_key(), usize::MAX, &what);
+                }
+            }
+        }
+        for (i, inst) in block_ref.instructions.iter().enumerate() {
+            for reg in inst.rhs.regs() {
+                verifier.check_use(reg, block.as_key(), i + 1, &format!("{}", inst));
+            }
+        }
+        let end = block_ref.instructions.len() + 1;
+        for reg in block_ref.exit.regs() {
+            verifier.check_use(reg, block.as_key(), end, &format!("{}", block_ref.exit));
+        }
+    }
+
+    collect_errors(
+        verifier
+            .errors
+            .into_iter()
+            .map(|err| Err::<(), _>(anyhow!("in function {}, after {}: {}", name, stage, err))),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use crate::ir::gen_test_ir;
+
+    #[test]
+    fn ssa_construction_drops_out_of_scope_phis() {
+        // inner-ctr is out of scope at the head of the outer loop, but is merged at the inner one
+        let source = "
+(define ctr 0)
+(loop
+    (set ctr (+ ctr 1))
+    (define inner-ctr 0)
+    (loop
+        (set inner-ctr (- inner-ctr 1))
+        (if (- ctr inner-ctr) (break)))
+    (if (- ctr 10) (break)))
+(return ctr)";
+        let program = gen_test_ir(source).unwrap();
+        verify("main", &program.funcs["main"], "SSA construction").unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::diagnostics::{ErrorList, SourceMap, Span, SpannedError};
+use crate::ir::gen_ir;
+use crate::semantics::{
+    lint, load_program_from_source, typecheck, Expr, ExprKind, FuncDefinition, Program, Type,
+};
+use crate::utils::frame::Frame;
+
+// numbered as in the LSP spec
+#[derive(Copy, Clone)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    // None if the error is not tied to any source
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+// everything known about a document, from compiling it as far as IR generation
+pub struct Analysis {
+    // the document itself is always the first file, at offset 0
+    pub sources: SourceMap,
+    pub diagnostics: Vec<Diagnostic>,
+    program: Option<Program<FuncDefinition>>,
+    // empty unless the whole program type checks
+    types: Vec<(Span, Option<Type>)>,
+}
+
+fn add_errors(diagnostics: &mut Vec<Diagnostic>, err: anyhow::Error, sources: &SourceMap) {
+    let err = match err.downcast::<ErrorList>() {
+        Ok(ErrorList(errors)) => {
+            for err in errors {
+                add_errors(diagnostics, err, sources);
+            }
+            return;
+        }
+        Err(err) => err,
+    };
+    diagnostics.push(match err.downcast::<SpannedError>() {
+        Ok(err) => Diagnostic::new(Severity::Error, err, sources),
+        Err(err) => Diagnostic {
+            severity: Severity::Error,
+            span: None,
+            message: format!("{err:#}"),
+        },
+    });
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, err: SpannedError, sources: &SourceMap) -> Self {
+        let mut message = err.message;
+        for note in err
+            .notes
+            .into_iter()
+            .chain(sources.expansion_notes(err.span))
+        {
+            message.push_str(&format!("\nnote: {note}"));
+        }
+        Self {
+            severity,
+            span: Some(err.span),
+            message,
+        }
+    }
+}
+
+impl Analysis {
+    pub fn new(path: &Path, text: &str) -> Self {
+        let mut analysis = Analysis {
+            sources: SourceMap::new(),
+            diagnostics: vec![],
+            program: None,
+            types: vec![],
+        };
+        if let Err(err) = analysis.check(path, text) {
+            add_errors(&mut analysis.diagnostics, err, &analysis.sources);
+        }
+        analysis
+    }
+
+    fn check(&mut self, path: &Path, text: &str) -> Result<()> {
+        let program = load_program_from_source(path, text.to_string(), &mut self.sources)?;
+        let program = self.program.insert(program);
+        self.types = typecheck(program)?;
+        for warning in lint(program, false) {
+            self.diagnostics
+                .push(Diagnostic::new(Severity::Warning, warning, &self.sources));
+        }
+        gen_ir(program)?;
+        Ok(())
+    }
+
+    // finds where the variable or function at offset is defined
+    pub fn definition(&self, offset: usize) -> Option<Span> {
+        let program = self.program.as_ref()?;
+        let mut resolver = Resolver {
+            offset,
+            funcs: &program.funcs,
+            found: None,
+        };
+        for func in program.funcs.values() {
+            let mut frame = Frame::new();
+            for (arg, span) in func.args.iter().zip(func.arg_spans.iter()) {
+                frame.assoc(arg.clone(), *span);
+            }
+            resolver.visit(&func.body, &mut frame);
+        }
+        resolver.found
+    }
+
+    // describes the innermost expression at offset, and returns its span
+    pub fn hover(&self, offset: usize) -> Option<(Span, String)> {
+        let (span, ty) = self
+            .types
+            .iter()
+            .filter(|(span, _)| contains(*span, offset))
+            .min_by_key(|(span, _)| span.end - span.start)?;
+        let description = match ty {
+            Some(ty) => format!("value of type {}", ty),
+            None => "statement, which has no value".to_string(),
+        };
+        Some((*span, description))
+    }
+}
+
+// spans include their end, so that a cursor just after a name still refers to it
+fn contains(span: Span, offset: usize) -> bool {
+    span.start <= offset && offset <= span.end
+}
+
+// walks a function with the same frames as ir::gen, to find what the name at offset refers to
+struct Resolver<'a> {
+    offset: usize,
+    funcs: &'a HashMap<String, FuncDefinition>,
+    found: Option<Span>,
+}
+
+impl Resolver<'_> {
+    // whether the cursor is in expr, but not in any of the given subexpressions
+    fn directly_in(&self, expr: &Expr, children: &[&Expr]) -> bool {
+        contains(expr.span, self.offset)
+            && !children
+                .iter()
+                .any(|child| contains(child.span, self.offset))
+    }
+
+    fn visit(&mut self, expr: &Expr, frame: &mut Frame<String, Span>) {
+        match &expr.kind {
+            ExprKind::VarDecl { name, value, .. } => {
+                self.visit(value, frame);
+                if self.directly_in(expr, &[value]) {
+                    self.found = Some(expr.span);
+                }
+                frame.assoc(name.clone(), expr.span);
+            }
+            ExprKind::VarAccess(name) => {
+                if contains(expr.span, self.offset) {
+                    self.found = frame.lookup(name);
+                }
+            }
+            ExprKind::VarAssign { name, value } => {
+                self.visit(value, frame);
+                if self.directly_in(expr, &[value]) {
+                    self.found = frame.lookup(name);
+                }
+            }
+            ExprKind::Call { func, args } => {
+                for arg in args.iter() {
+                    self.visit(arg, frame);
+                }
+                if self.directly_in(expr, &args.iter().collect::<Vec<_>>()) {
+                    self.found = self.funcs.get(func).map(|func| func.body.span);
+                }
+            }
+            ExprKind::ArithOp { arg1, arg2, .. } => {
+                self.visit(arg1, frame);
+                self.visit(arg2, frame);
+            }
+            ExprKind::LogicalOp { arg1, arg2, .. } => {
+                self.visit(arg1, frame);
+                self.visit(arg2, &mut frame.new_child());
+            }
+            ExprKind::Block(exprs) => {
+                for expr in exprs.iter() {
+                    self.visit(expr, frame);
+                }
+            }
+            ExprKind::Scope(body) | ExprKind::Loop { body, .. } => {
+                self.visit(body, &mut frame.new_child());
+            }
+            ExprKind::IfElse { pred, conseq, alt } => {
+                self.visit(pred, frame);
+                self.visit(conseq, &mut frame.new_child());
+                self.visit(alt, &mut frame.new_child());
+            }
+            ExprKind::Break {
+                value: Some(value), ..
+            }
+            | ExprKind::Return(Some(value))
+            | ExprKind::UnaryOp { arg: value, .. }
+            | ExprKind::Cast { value, .. }
+            | ExprKind::ReadMemory(value) => self.visit(value, frame),
+            ExprKind::WriteMemory { addr, value } => {
+                self.visit(addr, frame);
+                self.visit(value, frame);
+            }
+            ExprKind::Break { value: None, .. }
+            | ExprKind::Return(None)
+            | ExprKind::Continue(_)
+            | ExprKind::IntegerLiteral(_)
+            | ExprKind::Noop
+            | ExprKind::Input => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Analysis;
+
+    fn analyze(text: &str) -> Analysis {
+        Analysis::new(Path::new("test.lang"), text)
+    }
+
+    // the text of the definition of the name just after the first occurrence of prefix
+    fn definition<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+        let span = analyze(text).definition(text.find(prefix)? + prefix.len())?;
+        Some(&text[span.start..span.end])
+    }
+
+    #[test]
+    fn definitions() {
+        let text = "
+(func (f (a u8)) (return a))
+(func (main b)
+    (define c (f 1))
+    (if b (begin (define c 2) (return c)))
+    (return (+ b c)))";
+        assert_eq!(definition(text, "(return "), Some("(a u8)"));
+        assert_eq!(
+            definition(text, "(define c ("),
+            Some("(func (f (a u8)) (return a))")
+        );
+        assert_eq!(definition(text, "(+ "), Some("b"));
+        // the inner c shadows the outer one, but only within the if
+        assert_eq!(definition(text, "(return c"), Some("(define c 2)"));
+        assert_eq!(definition(text, "(+ b "), Some("(define c (f 1))"));
+        // keywords are not defined anywhere
+        assert_eq!(definition(text, "\n("), None);
+    }
+
+    #[test]
+    fn hover() {
+        let text = "(func (main (x u8)) (define y (+ x 1)) (write 0 y) (return (< x 2)))";
+        let analysis = analyze(text);
+        let describe = |pattern: &str| {
+            let (span, description) = analysis.hover(text.find(pattern).unwrap() + 1)?;
+            Some((&text[span.start..span.end], description))
+        };
+        assert_eq!(
+            describe("(+ x"),
+            Some(("(+ x 1)", "value of type u8".to_string()))
+        );
+        assert_eq!(
+            describe("(write"),
+            Some(("(write 0 y)", "statement, which has no value".to_string()))
+        );
+        assert_eq!(describe("x 2"), Some(("x", "value of type u8".to_string())));
+        assert_eq!(
+            describe("(< x"),
+            Some(("(< x 2)", "value of type bool".to_string()))
+        );
+        // types are only known once the whole program type checks
+        assert_eq!(analyze("(func (main) (return (+ 1 (g))))").hover(22), None);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let analysis = analyze("(defmacro (bad) `(set y 1)) (func (main x) (bad) (return 0))");
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            ["cannot assign to undeclared variable\nnote: in expansion of macro bad"]
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use self::analysis::Analysis;
+use crate::diagnostics::Span;
+use crate::semantics::BUILTIN_OPERATORS;
+
+mod analysis;
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+// returns None once the client closes the connection
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(len) = header.strip_prefix("Content-Length:") {
+            content_length = Some(len.trim().parse::<usize>()?);
+        }
+    }
+    let content_length = content_length.context("message is missing a Content-Length header")?;
+    let mut content = vec![0; content_length];
+    input.read_exact(&mut content)?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+    let content = message.to_string();
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    output.flush()?;
+    Ok(())
+}
+
+fn uri_to_path(uri: &str) -> Result<PathBuf> {
+    let Some(path) = uri.strip_prefix("file://") else {
+        bail!("only file URIs are supported, but got {}", uri);
+    };
+    // undo percent-encoding
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail.get(..2)) {
+            (b'%', Some(hex)) => {
+                let hex = std::str::from_utf8(hex)?;
+                bytes.push(u8::from_str_radix(hex, 16)?);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    Ok(PathBuf::from(String::from_utf8(bytes)?))
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in path.display().to_string().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+// LSP positions count UTF-16 code units within a line
+fn offset_at(text: &str, position: &Value) -> Result<usize> {
+    let line = position["line"].as_u64().context("position has no line")? as usize;
+    let character = position["character"]
+        .as_u64()
+        .context("position has no character")? as usize;
+    let line_start = text
+        .split_inclusive('\n')
+        .take(line)
+        .map(str::len)
+        .sum::<usize>();
+    let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (i, c) in line_text.char_indices() {
+        if units >= character {
+            return Ok(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Ok(line_start + line_text.len())
+}
+
+fn position_of(text: &str, offset: usize) -> Value {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": text[..offset].matches('\n').count(),
+        "character": text[line_start..offset].encode_utf16().count(),
+    })
+}
+
+fn range_of(text: &str, span: Span) -> Value {
+    json!({
+        "start": position_of(text, span.start),
+        "end": position_of(text, span.end),
+    })
+}
+
+struct Server {
+    // the open documents, by URI
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    fn document(&self, params: &Value) -> Result<(&Document, usize)> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .context("request has no document")?;
+        let document = self
+            .documents
+            .get(uri)
+            .with_context(|| format!("{} is not open", uri))?;
+        let offset = offset_at(&document.text, &params["position"])?;
+        Ok((document, offset))
+    }
+
+    // returns the result, or None if the method is not supported
+    fn handle_request(&mut self, method: &str, params: &Value) -> Result<Option<Value>> {
+        Ok(Some(match method {
+            "initialize" => json!({
+                "capabilities": {
+                    // the whole document is sent on every change
+                    "textDocumentSync": 1_u8,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["("] },
+                },
+                "serverInfo": { "name": "mylang" },
+            }),
+            "shutdown" => Value::Null,
+            "textDocument/definition" => {
+                let (document, offset) = self.document(params)?;
+                match document.analysis.definition(offset) {
+                    Some(span) => {
+                        let file = document.analysis.sources.lookup(span.start).unwrap();
+                        let span = Span::new(span.start - file.base, span.end - file.base);
+                        json!({
+                            "uri": path_to_uri(Path::new(&file.name)),
+                            "range": range_of(&file.contents, span),
+                        })
+                    }
+                    None => Value::Null,
+                }
+            }
+            "textDocument/hover" => {
+                let (document, offset) = self.document(params)?;
+                match document.analysis.hover(offset) {
+                    Some((span, description)) => json!({
+                        "contents": { "kind": "plaintext", "value": description },
+                        "range": range_of(&document.text, span),
+                    }),
+                    None => Value::Null,
+                }
+            }
+            "textDocument/completion" => BUILTIN_OPERATORS
+                .iter()
+                .map(|operator| {
+                    // keyword or operator
+                    let kind: u8 = if operator.starts_with(char::is_alphabetic) {
+                        14
+                    } else {
+                        24
+                    };
+                    json!({ "label": operator, "kind": kind })
+                })
+                .collect(),
+            _ => return Ok(None),
+        }))
+    }
+
+    // returns any notifications to send back
+    fn handle_notification(&mut self, method: &str, params: &Value) -> Result<Vec<Value>> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // only full updates are requested, so the last change holds the whole text
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return Ok(vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })]);
+            }
+            _ => return Ok(vec![]),
+        };
+        let text = text.context("notification has no document text")?;
+
+        let analysis = Analysis::new(&uri_to_path(uri)?, text);
+        // errors in imported files are shown at the start of the document
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let (span, message) = match (
+                    diagnostic.span,
+                    diagnostic
+                        .span
+                        .and_then(|span| analysis.sources.lookup(span.start)),
+                ) {
+                    (Some(span), Some(file)) if file.base == 0 => {
+                        (span, diagnostic.message.clone())
+                    }
+                    (_, Some(file)) => (
+                        Span::default(),
+                        format!("in {}: {}", file.name, diagnostic.message),
+                    ),
+                    (_, None) => (Span::default(), diagnostic.message.clone()),
+                };
+                json!({
+                    "range": range_of(text, span),
+                    "severity": diagnostic.severity as u8,
+                    "source": "mylang",
+                    "message": message,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.documents.insert(
+            uri.to_string(),
+            Document {
+                text: text.to_string(),
+                analysis,
+            },
+        );
+        Ok(vec![json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })])
+    }
+}
+
+// serves the language server protocol over stdin and stdout, until the client exits
+pub fn run_server() -> Result<()> {
+    let stdin = stdin();
+    let mut input = stdin.lock();
+    let stdout = stdout();
+    let mut output = stdout.lock();
+    let mut server = Server {
+        documents: HashMap::new(),
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.handle_request(method, params) {
+                    Ok(Some(result)) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Ok(None) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32601, "message": format!("unknown method {}", method) },
+                    }),
+                    Err(err) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32603, "message": format!("{err:#}") },
+                    }),
+                };
+                write_message(&mut output, &response)?;
+            }
+            None if method == "exit" => break,
+            // a bad notification cannot be answered, so it is only logged
+            None => match server.handle_notification(method, params) {
+                Ok(notifications) => {
+                    for notification in notifications {
+                        write_message(&mut output, &notification)?;
+                    }
+                }
+                Err(err) => eprintln!("error handling {}: {:#}", method, err),
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{offset_at, position_of};
+
+    #[test]
+    fn utf16_positions() {
+        // é is 2 bytes and 1 UTF-16 unit, while 😀 is 4 bytes and 2 units
+        let text = "(a é)\n(b 😀 c)\n";
+        let c = text.find('c').unwrap();
+        assert_eq!(position_of(text, c), json!({"line": 1, "character": 6}));
+        assert_eq!(
+            offset_at(text, &json!({"line": 1, "character": 6})).unwrap(),
+            c
+        );
+        assert_eq!(position_of(text, 5), json!({"line": 0, "character": 4}));
+        assert_eq!(
+            offset_at(text, &json!({"line": 0, "character": 3})).unwrap(),
+            3
+        );
+        for offset in text.char_indices().map(|(i, _)| i) {
+            assert_eq!(offset_at(text, &position_of(text, offset)).unwrap(), offset);
+        }
+        // past the end of a line, or of the text
+        assert_eq!(
+            offset_at(text, &json!({"line": 0, "character": 50})).unwrap(),
+            6
+        );
+        assert_eq!(
+            offset_at(text, &json!({"line": 5, "character": 0})).unwrap(),
+            text.len()
+        );
+        assert_eq!(position_of(text, 100), json!({"line": 2, "character": 0}));
+        assert!(offset_at(text, &json!({"line": 0})).is_err());
+    }
+}
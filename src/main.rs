@@ -1,52 +1,404 @@
 #![feature(drain_filter)]
 #![feature(let_else)]
 
-use std::fs::read_to_string;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use backend::microcode::lower_to_microcode;
-use clap::Parser;
+use clap::{ArgEnum, Parser, Subcommand};
+use itertools::Itertools;
 
-use crate::frontend::parse;
-use crate::ir::gen_ir;
+use crate::diagnostics::{collect_errors, render_error, render_warning, SourceMap};
+use crate::frontend::format_source;
+use crate::interpreter::{interpret, parse_memory};
+use crate::ir::{
+    gen_cfgs, gen_ir, parse_ir, to_dot, verify, CfgConfig, DotBlock, Function, SSAFunction,
+};
 use crate::optimizations::optimize;
-use crate::semantics::analyze;
+use crate::semantics::{lint, load_program, typecheck, warnings_as_errors, Program};
 
 mod backend;
+mod diagnostics;
 mod frontend;
+mod interpreter;
 mod ir;
+mod lsp;
 mod optimizations;
 mod semantics;
 mod utils;
 
 #[derive(Parser)]
-#[clap(about, version, author)]
+#[clap(about, version, author, subcommand_negates_reqs = true)]
 struct Args {
-    /// The file to compile
+    /// The file to compile, either source code or SSA IR with a .ir extension
     #[clap(short, long, required = true)]
-    target: PathBuf,
-    #[clap(short, long)]
+    target: Option<PathBuf>,
+    /// Print the optimized SSA IR instead of microcode, in the format read from .ir files
+    #[clap(long)]
+    emit_ir: bool,
+    /// Check that the SSA IR is well formed after it is generated and after every optimization
+    #[clap(long, global = true)]
+    verify_each: bool,
+    #[clap(short, long, global = true)]
     fold_constants: bool,
+    /// The function to start execution from
+    #[clap(long, default_value = "main", global = true)]
+    entry: String,
+    /// Optional warnings to enable, or error to treat all warnings as errors
+    #[clap(short = 'W', arg_enum, global = true)]
+    warnings: Vec<Warning>,
+    /// Write a Graphviz file of each function at this stage, named <function>.<stage>.dot
+    #[clap(long, arg_enum, global = true)]
+    emit_dot: Vec<DotStage>,
+    /// Extra information to draw in the Graphviz files
+    #[clap(long, arg_enum, global = true)]
+    dot_overlay: Vec<DotOverlay>,
+    /// The directory to write Graphviz files to
+    #[clap(long, default_value = ".", global = true)]
+    dot_dir: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<()> {
+#[derive(Subcommand)]
+enum Command {
+    /// Reformat source files in place
+    Fmt {
+        /// The files to format
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+        /// Fail if any file is not formatted, instead of changing it
+        #[clap(long)]
+        check: bool,
+    },
+    /// Run a program with the IR interpreter, and print what it returns
+    Run {
+        /// The file to run
+        target: PathBuf,
+        /// Values for the arguments of the entry function
+        #[clap(long, use_value_delimiter = true, allow_hyphen_values = true)]
+        args: Vec<i64>,
+        /// Values for each (input) to read in turn
+        #[clap(long, use_value_delimiter = true, allow_hyphen_values = true)]
+        input: Vec<i64>,
+        /// A file of integers to load into memory, starting at address 0
+        #[clap(long)]
+        memory: Option<PathBuf>,
+        /// Run the SSA IR as constructed, or as parsed from a .ir file, without optimizing it
+        #[clap(long)]
+        no_optimize: bool,
+    },
+    /// Run a language server over stdin and stdout, for editor integration
+    Lsp,
+}
+
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+enum Warning {
+    // a define that hides a variable from an enclosing scope
+    Shadowing,
+    // fail compilation if there are any warnings
+    Error,
+}
+
+#[derive(ArgEnum, Clone, Copy, PartialEq, Eq)]
+enum DotStage {
+    // the control-flow graph before conversion to SSA, which assigns to variables
+    Initial,
+    // straight after conversion to SSA, or as parsed from a .ir file
+    Ssa,
+    // after the optimization passes
+    Optimized,
+    // after register allocation, only when compiling to microcode
+    Microcode,
+}
+
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+enum DotOverlay {
+    // dashed edges from each block's immediate dominator
+    Dominators,
+    // the registers live into and out of each block
+    Liveness,
+}
+
+fn main() {
     let args = Args::parse();
 
-    let contents = read_to_string(args.target).expect("unable to open source file");
-    let exprs = parse(&mut contents.chars())?;
-    let program = analyze(&exprs)?;
+    let mut sources = SourceMap::new();
+    let result = match &args.command {
+        Some(Command::Fmt { files, check }) => format_files(files, *check, &mut sources),
+        Some(Command::Run {
+            target,
+            args: entry_args,
+            input,
+            memory,
+            no_optimize,
+        }) => run(
+            target,
+            entry_args,
+            input,
+            memory.as_deref(),
+            !no_optimize,
+            &args,
+            &mut sources,
+        ),
+        Some(Command::Lsp) => lsp::run_server(),
+        // clap requires a target unless there is a subcommand
+        None => compile(args.target.as_ref().unwrap(), &args, &mut sources),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", render_error(&err, &sources));
+        exit(1);
+    }
+}
+
+// compiles target as far as SSA, which is then optimized if requested
+fn build(
+    target: &Path,
+    optimized: bool,
+    args: &Args,
+    sources: &mut SourceMap,
+) -> Result<Program<SSAFunction>> {
+    // hand-written IR skips the frontend, to test the optimizations on exactly that input
+    if target.extension().map_or(false, |ext| ext == "ir") {
+        let name = target.display().to_string();
+        if args.emit_dot.contains(&DotStage::Initial) {
+            bail!(
+                "{} is already in SSA form, so has no initial control-flow graph",
+                name
+            );
+        }
+        let contents = read_to_string(target)
+            .with_context(|| format!("unable to open source file {}", name))?;
+        let base = sources.add(name, contents.clone());
+        let mut program = parse_ir(&contents, base)?;
+        check_ir(&program, args, "parsing")?;
+        emit_dot(&program, DotStage::Ssa, args)?;
+        if optimized {
+            optimize(&mut program, args.fold_constants, args.verify_each)?;
+            emit_dot(&program, DotStage::Optimized, args)?;
+        }
+        return Ok(program);
+    }
+
+    // each phase reports all of its own errors, but needs every function from the one before
+    let mut program = load_program(target, sources)?;
+    typecheck(&mut program)?;
+
+    let warnings = lint(&program, args.warnings.contains(&Warning::Shadowing));
+    if args.warnings.contains(&Warning::Error) {
+        warnings_as_errors(warnings)?;
+    } else {
+        for warning in warnings {
+            eprintln!("{}\n", render_warning(&warning, sources));
+        }
+    }
+
+    if args.emit_dot.contains(&DotStage::Initial) {
+        emit_dot(&gen_cfgs(&program)?, DotStage::Initial, args)?;
+    }
 
     let mut program = gen_ir(&program)?;
-    // don't do constant folding for microcode output, since constants are expensive
-    optimize(&mut program, args.fold_constants);
+    check_ir(&program, args, "SSA construction")?;
+    emit_dot(&program, DotStage::Ssa, args)?;
+    if optimized {
+        // don't do constant folding for microcode output, since constants are expensive
+        optimize(&mut program, args.fold_constants, args.verify_each)?;
+        emit_dot(&program, DotStage::Optimized, args)?;
+    }
+    Ok(program)
+}
+
+fn check_ir(program: &Program<SSAFunction>, args: &Args, stage: &str) -> Result<()> {
+    if args.verify_each {
+        let funcs = program.funcs.iter().sorted_by(|(a, _), (b, _)| a.cmp(b));
+        collect_errors(funcs.map(|(name, func)| verify(name, func, stage)))?;
+    }
+    Ok(())
+}
+
+// writes each function to <dot_dir>/<function>.<stage>.dot, if --emit-dot asks for this stage
+fn emit_dot<Conf: CfgConfig>(
+    program: &Program<Function<Conf>>,
+    stage: DotStage,
+    args: &Args,
+) -> Result<()>
+where
+    Conf::BlockType: DotBlock,
+{
+    if !args.emit_dot.contains(&stage) {
+        return Ok(());
+    }
+    let stage = stage.to_possible_value().unwrap();
+    for (name, func) in program.funcs.iter() {
+        let dot = to_dot(
+            name,
+            func,
+            args.dot_overlay.contains(&DotOverlay::Dominators),
+            args.dot_overlay.contains(&DotOverlay::Liveness),
+        );
+        let path = args
+            .dot_dir
+            .join(format!("{}.{}.dot", name, stage.get_name()));
+        write(&path, dot).with_context(|| format!("unable to write to {}", path.display()))?;
+    }
+    Ok(())
+}
 
-    lower_to_microcode(
+fn compile(target: &Path, args: &Args, sources: &mut SourceMap) -> Result<()> {
+    let mut program = build(target, true, args, sources)?;
+
+    if args.emit_ir {
+        for (name, func) in program.funcs.iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+            println!("func {name}:\n{func}");
+        }
+        return Ok(());
+    }
+
+    let entry_func = program
+        .funcs
+        .remove(&args.entry)
+        .with_context(|| format!("entry function {} is not defined", args.entry))?;
+    let mut funcs = vec![(args.entry.clone(), entry_func)];
+    funcs.extend(
         program
             .funcs
-            .remove("main")
-            .expect("main() function must be defined"),
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b)),
     );
 
+    let mut lowered = Program {
+        funcs: HashMap::new(),
+    };
+    for (name, func) in funcs {
+        let func = lower_to_microcode(func);
+        println!("func {name}:");
+        for block in func.blocks() {
+            println!("{}", block.borrow());
+        }
+        lowered.funcs.insert(name, func);
+    }
+    emit_dot(&lowered, DotStage::Microcode, args)?;
+
+    Ok(())
+}
+
+fn run(
+    target: &Path,
+    entry_args: &[i64],
+    inputs: &[i64],
+    memory: Option<&Path>,
+    optimized: bool,
+    args: &Args,
+    sources: &mut SourceMap,
+) -> Result<()> {
+    let memory = match memory {
+        Some(path) => {
+            let contents = read_to_string(path)
+                .with_context(|| format!("unable to open memory file {}", path.display()))?;
+            parse_memory(&contents).with_context(|| format!("in {}", path.display()))?
+        }
+        None => HashMap::new(),
+    };
+    let program = build(target, optimized, args, sources)?;
+    match interpret(
+        &program,
+        &args.entry,
+        entry_args.to_vec(),
+        inputs.to_vec(),
+        memory,
+    )? {
+        Some(value) => println!("{}", value),
+        None => eprintln!("{} returned without a value", args.entry),
+    }
     Ok(())
 }
+
+fn format_files(files: &[PathBuf], check: bool, sources: &mut SourceMap) -> Result<()> {
+    // every file is formatted, even if an earlier one is malformed
+    collect_errors(files.iter().map(|path| format_file(path, check, sources)))?;
+    Ok(())
+}
+
+fn format_file(path: &Path, check: bool, sources: &mut SourceMap) -> Result<()> {
+    let name = path.display().to_string();
+    let contents =
+        read_to_string(path).with_context(|| format!("unable to open source file {}", name))?;
+    let base = sources.add(name.clone(), contents.clone());
+    let formatted = format_source(&contents, base)?;
+    if formatted != contents {
+        if check {
+            bail!("{} is not formatted", name);
+        }
+        write(path, formatted).with_context(|| format!("unable to write to {}", name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, write};
+    use std::path::Path;
+
+    use clap::Parser;
+
+    use super::{build, compile, Args};
+    use crate::diagnostics::SourceMap;
+    use crate::utils::temp_dir::TempDir;
+
+    #[test]
+    fn missing_entry() {
+        let target = Path::new(env!("CARGO_MANIFEST_DIR")).join("fib.lang");
+        let args = Args::parse_from([
+            "mylang",
+            "--target",
+            target.to_str().unwrap(),
+            "--entry",
+            "start",
+        ]);
+        let err = compile(&target, &args, &mut SourceMap::new()).unwrap_err();
+        assert_eq!(format!("{:#}", err), "entry function start is not defined");
+    }
+
+    #[test]
+    fn initial_dot_of_ir() {
+        // rejected before the file is read, so it need not exist
+        let args = Args::parse_from(["mylang", "--target", "missing.ir", "--emit-dot", "initial"]);
+        let Err(err) = build(Path::new("missing.ir"), true, &args, &mut SourceMap::new()) else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            format!("{:#}", err),
+            "missing.ir is already in SSA form, so has no initial control-flow graph"
+        );
+    }
+
+    #[test]
+    fn emit_dot() {
+        let dir = TempDir::new("emit-dot");
+        let target = dir.path().join("main.ir");
+        write(
+            &target,
+            "func main:\nstart: 0\n\nblock 0 (preds=[])\n%1 = 1\nret %1\n",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "mylang",
+            "--target",
+            target.to_str().unwrap(),
+            "--emit-dot",
+            "ssa",
+            "--dot-overlay",
+            "liveness",
+            "--dot-dir",
+            dir.path().to_str().unwrap(),
+        ]);
+        build(&target, false, &args, &mut SourceMap::new()).unwrap();
+        // only the requested stage is written, and with the requested overlay
+        let dot = read_to_string(dir.path().join("main.ssa.dot")).unwrap();
+        assert!(dot.contains("live in") && !dot.contains("dashed"));
+        assert!(!dir.path().join("main.optimized.dot").exists());
+    }
+}
@@ -55,8 +55,11 @@ pub fn remove_empty_blocks(func: &mut SSAFunction) {
                     dest.borrow_mut().preds.insert(Rc::downgrade(&pred).into());
                 }
             }
-            if block_to_remove.as_key() == func.start_block.as_key() {
-                // make dest the new start block
+            // make dest the new start block, unless its phis need to know where control came from
+            if block_to_remove.as_key() == new_start_block.as_key()
+                && block_to_remove.as_key() != dest.as_key()
+                && dest.borrow().phis.is_empty()
+            {
                 new_start_block = dest.clone();
             }
         } else {
@@ -64,5 +67,10 @@ pub fn remove_empty_blocks(func: &mut SSAFunction) {
         }
     }
     func.start_block = new_start_block;
+    // so that the removed blocks are actually dropped
+    drop(visited);
     func.clear_dead_blocks();
+    for block in func.blocks() {
+        block.borrow_mut().remove_dead_preds();
+    }
 }
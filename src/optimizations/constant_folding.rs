@@ -4,7 +4,6 @@ use std::mem::take;
 use crate::ir::{
     Phi, SSAFunction, SSAInstruction, SSAInstructionRHS, SSAJumpInstruction, VirtualRegister,
 };
-use crate::semantics::{BinaryOperator, UnaryOperator};
 use crate::utils::rcequality::RcDereferencable;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -40,43 +39,23 @@ fn evaluate(
     };
     Some(match rhs {
         SSAInstructionRHS::UnaryOperation {
-            operator: UnaryOperator::Not,
+            operator,
+            width,
             arg,
-        } => !get_reg(arg)?,
+        } => operator.apply(*width, get_reg(arg)?),
         SSAInstructionRHS::BinaryOperation {
-            operator: BinaryOperator::Xor,
+            operator,
+            width,
             arg1,
             arg2,
-        } => get_reg(arg1)? ^ get_reg(arg2)?,
-        SSAInstructionRHS::BinaryOperation {
-            operator: BinaryOperator::And,
-            arg1,
-            arg2,
-        } => get_reg(arg1)? & get_reg(arg2)?,
-        SSAInstructionRHS::BinaryOperation {
-            operator: BinaryOperator::Add,
-            arg1,
-            arg2,
-        } => get_reg(arg1)? + get_reg(arg2)?,
-        SSAInstructionRHS::BinaryOperation {
-            operator: BinaryOperator::Sub,
-            arg1,
-            arg2,
-        } => get_reg(arg1)? - get_reg(arg2)?,
-        SSAInstructionRHS::BinaryOperation {
-            operator: BinaryOperator::Mul,
-            arg1,
-            arg2,
-        } => get_reg(arg1)? * get_reg(arg2)?,
-        SSAInstructionRHS::BinaryOperation {
-            operator: BinaryOperator::Div,
-            arg1: _,
-            arg2: _,
-        } => return None,
+        } => operator.apply(*width, get_reg(arg1)?, get_reg(arg2)?),
         SSAInstructionRHS::LoadIntegerLiteral { value } => *value,
         SSAInstructionRHS::Move { src } => get_reg(src)?,
         SSAInstructionRHS::ReadInput => return None,
+        SSAInstructionRHS::Param { .. } => return None,
         SSAInstructionRHS::ReadMemory(_) => return None,
+        SSAInstructionRHS::Call { .. } => return None,
+        SSAInstructionRHS::StoreMemory { .. } => return None,
     })
 }
 
@@ -102,9 +81,12 @@ pub fn constant_folding(func: &mut SSAFunction) {
         }
 
         for inst in &block.instructions {
+            let Some(lhs) = &inst.lhs else {
+                continue;
+            };
             let val = evaluate(&inst.rhs, &known_values)
                 .map_or(RegisterValue::Variable, RegisterValue::Constant);
-            if known_values.insert(inst.lhs.0, val) != Some(val) {
+            if known_values.insert(lhs.0, val) != Some(val) {
                 changed = true;
             }
         }
@@ -143,8 +125,9 @@ pub fn constant_folding(func: &mut SSAFunction) {
     }
 
     // now, replace constants!
-    for block in func.blocks() {
-        let mut block = block.borrow_mut();
+    let mut removed_edges = vec![];
+    for block_ref in func.blocks() {
+        let mut block = block_ref.borrow_mut();
         let mut phi_assigns = vec![];
         block
             .phis
@@ -164,7 +147,10 @@ pub fn constant_folding(func: &mut SSAFunction) {
                 }
             });
         for inst in &mut block.instructions {
-            if let Some(RegisterValue::Constant(value)) = known_values.get(&inst.lhs.0).copied() {
+            let Some(lhs) = &inst.lhs else {
+                continue;
+            };
+            if let Some(RegisterValue::Constant(value)) = known_values.get(&lhs.0).copied() {
                 inst.rhs = SSAInstructionRHS::LoadIntegerLiteral { value }
             }
         }
@@ -172,14 +158,24 @@ pub fn constant_folding(func: &mut SSAFunction) {
         block.instructions = phi_assigns;
         if let SSAJumpInstruction::BranchIfElseZero { pred, conseq, alt } = &block.exit {
             if let Some(RegisterValue::Constant(val)) = known_values.get(pred).copied() {
-                if val == 0 {
-                    block.exit = SSAJumpInstruction::UnconditionalJump {
-                        dest: conseq.clone(),
-                    };
+                let (taken, untaken) = if val == 0 {
+                    (conseq.clone(), alt.clone())
                 } else {
-                    block.exit = SSAJumpInstruction::UnconditionalJump { dest: alt.clone() };
+                    (alt.clone(), conseq.clone())
+                };
+                if taken.as_key() != untaken.as_key() {
+                    removed_edges.push((untaken, block_ref.as_key()));
                 }
+                block.exit = SSAJumpInstruction::UnconditionalJump { dest: taken };
             }
         }
     }
+    // done afterwards, since a block may branch to itself
+    for (dest, pred) in removed_edges {
+        dest.borrow_mut().remove_pred(pred);
+    }
+    // blocks that are no longer jumped to at all are gone, so must be forgotten by their successors
+    for block in func.blocks() {
+        block.borrow_mut().remove_dead_preds();
+    }
 }
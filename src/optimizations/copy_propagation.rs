@@ -15,8 +15,8 @@ pub fn copy_propagation(func: &mut SSAFunction) {
     let mut regs = UnionFind::new();
     for block in func.blocks() {
         for inst in &block.borrow().instructions {
-            if let SSAInstructionRHS::Move { src } = inst.rhs {
-                regs.directed_union(src, inst.lhs.0);
+            if let (SSAInstructionRHS::Move { src }, Some(lhs)) = (&inst.rhs, &inst.lhs) {
+                regs.directed_union(*src, lhs.0);
             }
         }
     }
@@ -33,7 +33,13 @@ pub fn remove_dead_statements(func: &mut SSAFunction) {
             }
         }
         for inst in &block.instructions {
-            register_definers.insert(inst.lhs.0, RegisterDefinition::Assignment(inst));
+            if let Some(lhs) = &inst.lhs {
+                register_definers.insert(lhs.0, RegisterDefinition::Assignment(inst));
+            }
+            if inst.rhs.has_side_effects() {
+                // e.g. stores and function calls must run even if their result is unused
+                initially_live_registers.extend(inst.rhs.regs());
+            }
             for reg in inst.rhs.regs() {
                 register_users
                     .entry(*reg)
@@ -76,9 +82,12 @@ pub fn remove_dead_statements(func: &mut SSAFunction) {
             .borrow_mut()
             .phis
             .retain(|phi| processed_registers.contains(&phi.dest.0));
-        block
-            .borrow_mut()
-            .instructions
-            .retain(|inst| processed_registers.contains(&inst.lhs.0));
+        block.borrow_mut().instructions.retain(|inst| {
+            inst.rhs.has_side_effects()
+                || inst
+                    .lhs
+                    .as_ref()
+                    .map_or(false, |lhs| processed_registers.contains(&lhs.0))
+        });
     }
 }
@@ -1,8 +1,10 @@
+use anyhow::Result;
+
 use self::block_merging::remove_empty_blocks;
 use self::copy_propagation::copy_propagation;
 use self::dead_code_elimination::remove_dead_statements;
 use self::simplify_jumps::simplify_jumps;
-use crate::ir::SSAFunction;
+use crate::ir::{verify, SSAFunction};
 use crate::optimizations::constant_folding::constant_folding;
 use crate::semantics::Program;
 
@@ -12,21 +14,35 @@ mod copy_propagation;
 mod dead_code_elimination;
 mod simplify_jumps;
 
-pub fn optimize(program: &mut Program<SSAFunction>, fold_constants: bool) {
+// with verify_each, the IR is checked after every pass, to catch the pass that breaks it
+pub fn optimize(
+    program: &mut Program<SSAFunction>,
+    fold_constants: bool,
+    verify_each: bool,
+) -> Result<()> {
     // inter-procedural optimizations
-    for func in program.funcs.values_mut() {
+    for (name, func) in program.funcs.iter_mut() {
+        let mut run = |pass: fn(&mut SSAFunction), pass_name: &str| {
+            pass(func);
+            if verify_each {
+                verify(name, func, pass_name)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
         // note: this MUST run first to remove optimistic but invalid phis
-        remove_dead_statements(func);
+        run(remove_dead_statements, "dead code elimination")?;
 
         for _ in 0..5 {
-            remove_dead_statements(func);
-            remove_empty_blocks(func);
-            simplify_jumps(func);
+            run(remove_dead_statements, "dead code elimination")?;
+            run(remove_empty_blocks, "block merging")?;
+            run(simplify_jumps, "jump simplification")?;
             if fold_constants {
-                constant_folding(func);
+                run(constant_folding, "constant folding")?;
             }
-            copy_propagation(func);
-            func.clear_dead_blocks();
+            run(copy_propagation, "copy propagation")?;
+            run(SSAFunction::clear_dead_blocks, "clearing dead blocks")?;
         }
     }
+    Ok(())
 }
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use super::{analyze, FuncDefinition, Program};
+use crate::diagnostics::{bail_at, SourceMap, Span, WithSpan};
+use crate::frontend::{expand_macros, parse, ParseExpr};
+
+// (import "path" [:only (f g ...)]), where the path is relative to the importing file
+struct Import {
+    path: String,
+    // if present, only these functions are made visible to the importing file
+    only: Option<Vec<(String, Span)>>,
+    span: Span,
+}
+
+fn analyze_import(operands: &[ParseExpr], span: Span) -> Result<Import> {
+    let (path, only) = match operands {
+        [ParseExpr::String(path, _)] => (path, None),
+        [ParseExpr::String(path, _), ParseExpr::Symbol(keyword, _), ParseExpr::List(names, _)]
+            if keyword == ":only" =>
+        {
+            let names = names
+                .iter()
+                .map(|name| match name {
+                    ParseExpr::Symbol(name, span) => Ok((name.clone(), *span)),
+                    _ => bail_at!(name.span(), "only function names can be imported"),
+                })
+                .collect::<Result<_>>()?;
+            (path, Some(names))
+        }
+        _ => bail_at!(
+            span,
+            "imports must be of the form (import \"path\" [:only (name1 ...)])"
+        ),
+    };
+    Ok(Import {
+        path: path.clone(),
+        only,
+        span,
+    })
+}
+
+fn find_imports(exprs: &[ParseExpr]) -> Result<Vec<Import>> {
+    let mut out = vec![];
+    for expr in exprs {
+        if let ParseExpr::List(lst, span) = expr {
+            if let Some((ParseExpr::Symbol(operator, _), operands)) = lst.split_first() {
+                if operator == "import" {
+                    out.push(analyze_import(operands, *span)?);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+struct Loader<'a> {
+    sources: &'a mut SourceMap,
+    // the functions defined by each file that has finished loading, and their arities
+    loaded: HashMap<PathBuf, HashMap<String, usize>>,
+    // the files currently being loaded, outermost first, by canonical path and display name
+    stack: Vec<(PathBuf, String)>,
+    funcs: HashMap<String, FuncDefinition>,
+    // the display name of the file defining each function
+    defined_in: HashMap<String, String>,
+}
+
+impl Loader<'_> {
+    // returns the functions defined by the file, which may already have been loaded.
+    // If contents is given, it is used instead of what is on disk
+    fn load(&mut self, path: &Path, contents: Option<String>) -> Result<HashMap<String, usize>> {
+        let name = path.display().to_string();
+        let canonical = match (path.canonicalize(), &contents) {
+            (Ok(canonical), _) => canonical,
+            // the file may not have been saved yet
+            (Err(_), Some(_)) => path.to_path_buf(),
+            (Err(err), None) => {
+                return Err(err).with_context(|| format!("unable to open source file {}", name))
+            }
+        };
+        if let Some(pos) = self.stack.iter().position(|(path, _)| path == &canonical) {
+            bail!(
+                "import cycle: {} -> {}",
+                self.stack[pos..].iter().map(|(_, name)| name).join(" -> "),
+                name
+            );
+        }
+        if let Some(exports) = self.loaded.get(&canonical) {
+            return Ok(exports.clone());
+        }
+
+        let contents = match contents {
+            Some(contents) => contents,
+            None => read_to_string(&canonical)
+                .with_context(|| format!("unable to open source file {}", name))?,
+        };
+        let base = self.sources.add(name.clone(), contents.clone());
+        // macros are local to the file that defines them
+        let exprs = expand_macros(parse(&contents, base)?, self.sources)?;
+
+        // only the file being compiled may have top-level expressions, forming main
+        let script = self.stack.is_empty();
+        self.stack.push((canonical.clone(), name.clone()));
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut visible = HashMap::new();
+        for import in find_imports(&exprs)? {
+            let exports = self.load(&dir.join(&import.path), None).at(import.span)?;
+            match import.only {
+                Some(names) => {
+                    for (func, span) in names {
+                        let Some(arity) = exports.get(&func) else {
+                            bail_at!(span, "{} does not define a function named {}", import.path, func);
+                        };
+                        visible.insert(func, *arity);
+                    }
+                }
+                None => visible.extend(exports),
+            }
+        }
+        self.stack.pop();
+
+        let program = analyze(&exprs, &visible, script)?;
+        let mut exports = HashMap::new();
+        for (func_name, func) in program
+            .funcs
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        {
+            // every function ends up in one Program, so names must be unique across all files
+            if let Some(prev) = self.defined_in.get(&func_name) {
+                bail_at!(
+                    func.body.span,
+                    "function {} is defined in both {} and {}",
+                    func_name,
+                    prev,
+                    name
+                );
+            }
+            exports.insert(func_name.clone(), func.args.len());
+            self.defined_in.insert(func_name.clone(), name.clone());
+            self.funcs.insert(func_name, func);
+        }
+        self.loaded.insert(canonical, exports.clone());
+        Ok(exports)
+    }
+}
+
+// loads the file at path, along with everything it imports, into a single Program
+pub fn load_program(path: &Path, sources: &mut SourceMap) -> Result<Program<FuncDefinition>> {
+    load(path, None, sources)
+}
+
+// like load_program, but with the contents of the file at path given directly, as when it is
+// being edited. Imports are still read from disk
+pub fn load_program_from_source(
+    path: &Path,
+    contents: String,
+    sources: &mut SourceMap,
+) -> Result<Program<FuncDefinition>> {
+    load(path, Some(contents), sources)
+}
+
+fn load(
+    path: &Path,
+    contents: Option<String>,
+    sources: &mut SourceMap,
+) -> Result<Program<FuncDefinition>> {
+    let mut loader = Loader {
+        sources,
+        loaded: HashMap::new(),
+        stack: vec![],
+        funcs: HashMap::new(),
+        defined_in: HashMap::new(),
+    };
+    loader.load(path, contents)?;
+    Ok(Program {
+        funcs: loader.funcs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use itertools::Itertools;
+
+    use super::load_program;
+    use crate::diagnostics::SourceMap;
+    use crate::utils::temp_dir::TempDir;
+
+    // writes files to a fresh directory, removed again afterwards, and loads the first of them.
+    // Errors are given with the directory left out of any paths
+    fn load(test: &str, files: &[(&str, &str)]) -> Result<Vec<String>, String> {
+        let dir = TempDir::new(test);
+        for (name, contents) in files {
+            write(dir.path().join(name), contents).unwrap();
+        }
+        match load_program(&dir.path().join(files[0].0), &mut SourceMap::new()) {
+            Ok(program) => Ok(program.funcs.into_keys().sorted().collect()),
+            Err(err) => {
+                Err(format!("{:#}", err).replace(&format!("{}/", dir.path().display()), ""))
+            }
+        }
+    }
+
+    fn error(test: &str, files: &[(&str, &str)]) -> String {
+        load(test, files).unwrap_err()
+    }
+
+    #[test]
+    fn imports() {
+        let files = [
+            (
+                "main.lang",
+                "(import \"a.lang\") (import \"b.lang\" :only (g)) (return (+ (f) (g)))",
+            ),
+            ("a.lang", "(import \"c.lang\") (func (f) (return (h)))"),
+            (
+                "b.lang",
+                "(import \"c.lang\") (func (g) (return (h))) (func (unused) (return 0))",
+            ),
+            ("c.lang", "(func (h) (return 1))"),
+        ];
+        // c is loaded once, though two files import it
+        assert_eq!(
+            load("imports", &files).unwrap(),
+            ["f", "g", "h", "main", "unused"]
+        );
+    }
+
+    #[test]
+    fn import_cycle() {
+        let files = [
+            ("main.lang", "(import \"a.lang\") (return 0)"),
+            ("a.lang", "(import \"b.lang\") (func (f) (return 0))"),
+            ("b.lang", "(import \"a.lang\") (func (g) (return 0))"),
+        ];
+        assert_eq!(
+            error("import-cycle", &files),
+            "import cycle: a.lang -> b.lang -> a.lang"
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        let files = [("main.lang", "(import \"missing.lang\") (return 0)")];
+        let err = error("missing-file", &files);
+        assert!(
+            err.starts_with("unable to open source file missing.lang: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn invisible_functions() {
+        let files = [
+            ("main.lang", "(import \"a.lang\" :only (f)) (return (g))"),
+            ("a.lang", "(func (f) (return 0)) (func (g) (return 1))"),
+        ];
+        assert_eq!(
+            error("invisible-functions", &files),
+            "invalid operator in call expression: g"
+        );
+        let files = [
+            ("main.lang", "(import \"a.lang\" :only (h)) (return 0)"),
+            ("a.lang", "(func (f) (return 0))"),
+        ];
+        assert_eq!(
+            error("only-missing-function", &files),
+            "a.lang does not define a function named h"
+        );
+    }
+}
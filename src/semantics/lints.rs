@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use super::{Expr, ExprKind, FuncDefinition, Program};
+use crate::diagnostics::{collect_errors, Span, SpannedError};
+
+struct Binding {
+    span: Span,
+    param: bool,
+    read: bool,
+}
+
+struct Linter {
+    // mirrors the frames used in ir::gen, innermost last
+    scopes: Vec<HashMap<String, Binding>>,
+    // the label of each enclosing loop, and whether anything breaks out of it
+    loops: Vec<(Option<String>, bool)>,
+    shadowing: bool,
+    warnings: Vec<SpannedError>,
+}
+
+impl Linter {
+    fn in_scope<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.scopes.push(HashMap::new());
+        let out = f(self);
+        self.pop_scope();
+        out
+    }
+
+    fn pop_scope(&mut self) {
+        for (name, binding) in self.scopes.pop().unwrap() {
+            // names containing spaces are generated by for loops and macros, not the user,
+            // and a leading underscore marks a variable as deliberately unused
+            if binding.read || name.contains(' ') || name.starts_with('_') {
+                continue;
+            }
+            let kind = if binding.param {
+                "parameter"
+            } else {
+                "variable"
+            };
+            self.warnings.push(SpannedError::new(
+                binding.span,
+                format!("{} {} is never read", kind, name),
+            ));
+        }
+    }
+
+    // returns whether control can reach the end of expr
+    fn visit(&mut self, expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::VarDecl { name, value, .. } => {
+                let completes = self.visit(value);
+                let (scope, enclosing) = self.scopes.split_last_mut().unwrap();
+                if self.shadowing
+                    && !name.contains(' ')
+                    && enclosing.iter().any(|outer| outer.contains_key(name))
+                {
+                    self.warnings.push(SpannedError::new(
+                        expr.span,
+                        format!("variable {} shadows a variable in an enclosing scope", name),
+                    ));
+                }
+                scope.insert(
+                    name.clone(),
+                    Binding {
+                        span: expr.span,
+                        param: false,
+                        read: false,
+                    },
+                );
+                completes
+            }
+            ExprKind::VarAccess(name) => {
+                if let Some(binding) = self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(name))
+                {
+                    binding.read = true;
+                }
+                true
+            }
+            ExprKind::VarAssign { value, .. } => self.visit(value),
+            ExprKind::ArithOp { arg1, arg2, .. } => self.visit(arg1) & self.visit(arg2),
+            ExprKind::LogicalOp { arg1, arg2, .. } => {
+                // arg2 may be skipped, so only arg1 decides whether the end is reachable
+                let completes = self.visit(arg1);
+                self.in_scope(|linter| linter.visit(arg2));
+                completes
+            }
+            ExprKind::Block(exprs) => {
+                let mut completes = true;
+                for (i, expr) in exprs.iter().enumerate() {
+                    if !completes {
+                        self.warnings.push(SpannedError::new(
+                            expr.span.to(exprs.last().unwrap().span),
+                            "unreachable code",
+                        ));
+                        // the rest is still linted, but only reported as unreachable once
+                        for expr in exprs[i..].iter() {
+                            self.visit(expr);
+                        }
+                        break;
+                    }
+                    completes = self.visit(expr);
+                }
+                completes
+            }
+            ExprKind::Scope(body) => self.in_scope(|linter| linter.visit(body)),
+            ExprKind::Loop { label, body } => {
+                self.loops.push((label.clone(), false));
+                self.in_scope(|linter| linter.visit(body));
+                // the only way out of a loop is to break out of it
+                let (_, broken) = self.loops.pop().unwrap();
+                broken
+            }
+            ExprKind::IfElse { pred, conseq, alt } => {
+                let pred_completes = self.visit(pred);
+                let conseq_completes = self.in_scope(|linter| linter.visit(conseq));
+                let alt_completes = self.in_scope(|linter| linter.visit(alt));
+                pred_completes && (conseq_completes || alt_completes)
+            }
+            ExprKind::Break { label, value } => {
+                if let Some(value) = value {
+                    self.visit(value);
+                }
+                let target = match label {
+                    Some(_) => self.loops.iter_mut().rev().find(|(l, _)| l == label),
+                    None => self.loops.last_mut(),
+                };
+                if let Some((_, broken)) = target {
+                    *broken = true;
+                }
+                false
+            }
+            ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    self.visit(value);
+                }
+                false
+            }
+            ExprKind::Continue(_) => false,
+            ExprKind::UnaryOp { arg: value, .. }
+            | ExprKind::Cast { value, .. }
+            | ExprKind::ReadMemory(value) => self.visit(value),
+            ExprKind::WriteMemory { addr, value } => self.visit(addr) & self.visit(value),
+            ExprKind::Call { args, .. } => args
+                .iter()
+                .fold(true, |completes, arg| self.visit(arg) && completes),
+            ExprKind::IntegerLiteral(_) | ExprKind::Noop | ExprKind::Input => true,
+        }
+    }
+}
+
+// returns warnings for unused variables and parameters, unreachable code, and functions whose
+// end can be reached. Shadowing is only reported if requested
+pub fn lint(program: &Program<FuncDefinition>, shadowing: bool) -> Vec<SpannedError> {
+    let mut linter = Linter {
+        scopes: vec![],
+        loops: vec![],
+        shadowing,
+        warnings: vec![],
+    };
+    for (name, func) in program.funcs.iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+        // gen_ir evaluates the body in the same frame as the args
+        linter.scopes = vec![func
+            .args
+            .iter()
+            .zip(func.arg_spans.iter())
+            .map(|(arg, span)| {
+                let binding = Binding {
+                    span: *span,
+                    param: true,
+                    read: false,
+                };
+                (arg.clone(), binding)
+            })
+            .collect()];
+        let completes = linter.visit(&func.body);
+        linter.pop_scope();
+        // falling off the end silently returns nothing, though every function returns an i64.
+        // A script is run for its effects, so its implicit main may end without a return
+        if completes && !func.implicit {
+            linter.warnings.push(SpannedError::new(
+                func.body.span,
+                format!(
+                    "function {} can reach its end without returning a value",
+                    name
+                ),
+            ));
+        }
+    }
+    // so that warnings come out in source order
+    linter.warnings.sort_by_key(|warning| warning.span.start);
+    linter.warnings
+}
+
+// for -W error, fails with every warning as an error
+pub fn warnings_as_errors(warnings: Vec<SpannedError>) -> Result<()> {
+    collect_errors(warnings.into_iter().map(|mut warning| {
+        warning
+            .notes
+            .push("warnings are treated as errors because of -W error".to_string());
+        Err::<(), _>(warning.into())
+    }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::{lint, warnings_as_errors};
+    use crate::diagnostics::{ErrorList, SpannedError};
+    use crate::semantics::load_test_program;
+
+    fn warnings(source: &str, shadowing: bool) -> Vec<SpannedError> {
+        lint(&load_test_program(source).unwrap(), shadowing)
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        warnings(source, false)
+            .into_iter()
+            .map(|warning| warning.message)
+            .collect()
+    }
+
+    #[test]
+    fn unused_variables() {
+        let source = "(func (main a _b) (define c 1) (define _d 2) (define e 3) (return e))";
+        assert_eq!(
+            messages(source),
+            ["parameter a is never read", "variable c is never read"]
+        );
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let source = "\
+(func (main x)
+    (loop (if x (break) (continue)) (set x 1) (set x 2))
+    (return x)
+    (set x 3))";
+        assert_eq!(messages(source), ["unreachable code", "unreachable code"]);
+        let warnings = warnings(source, false);
+        assert_eq!(warnings[0].span.start, source.find("(set x 1)").unwrap());
+        assert_eq!(warnings[0].span.end, source.find(")\n    (return").unwrap());
+    }
+
+    #[test]
+    fn missing_return() {
+        let source = "\
+(func (returns x) (if x (return 1) (return 2)))
+(func (loops x) (loop (if x (return 1))))
+(func (breaks x) (loop :label outer (loop (break :label outer))) (return x))
+(func (some-paths x) (if x (return 1)))
+(func (escapes x) (while x (return 1)))
+(func (never x) (write x 1))
+(func (main) (return (+ (returns 1) (loops 1) (breaks 1) (some-paths 1) (escapes 1) (never 1))))";
+        assert_eq!(
+            messages(source),
+            [
+                "function some-paths can reach its end without returning a value",
+                "function escapes can reach its end without returning a value",
+                "function never can reach its end without returning a value"
+            ]
+        );
+        // except for the implicit main of a script
+        assert_eq!(messages("(define x 1) (write x 2)"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn shadowing() {
+        let source = "(func (main x) (if x (begin (define x 2) (return x))) (return 0))";
+        assert_eq!(warnings(source, false).len(), 0);
+        assert_eq!(
+            warnings(source, true)
+                .into_iter()
+                .map(|warning| warning.message)
+                .collect_vec(),
+            ["variable x shadows a variable in an enclosing scope"]
+        );
+    }
+
+    #[test]
+    fn warnings_are_errors() {
+        assert!(warnings_as_errors(warnings("(func (main) (return 1))", false)).is_ok());
+        let source = "(func (main a b) (return 1))";
+        let err = warnings_as_errors(warnings(source, false)).unwrap_err();
+        let ErrorList(errors) = err.downcast_ref().unwrap();
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect_vec(),
+            ["parameter a is never read", "parameter b is never read"]
+        );
+        let error: &SpannedError = errors[0].downcast_ref().unwrap();
+        assert_eq!(
+            error.notes,
+            ["warnings are treated as errors because of -W error"]
+        );
+    }
+}
@@ -2,9 +2,20 @@ use std::collections::HashMap;
 use std::fmt::Display;
 
 use anyhow::{bail, Context, Result};
+use itertools::Itertools;
 
+pub use self::imports::{load_program, load_program_from_source};
+pub use self::lints::{lint, warnings_as_errors};
+pub use self::typecheck::typecheck;
+pub use self::types::{IntType, Type};
+use crate::diagnostics::{bail_at, collect_errors, Span, WithSpan};
 use crate::frontend::ParseExpr;
 
+mod imports;
+mod lints;
+mod typecheck;
+mod types;
+
 pub struct Program<FuncType> {
     pub funcs: HashMap<String, FuncType>,
 }
@@ -20,13 +31,71 @@ impl<FuncType: Display> Display for Program<FuncType> {
 pub struct FuncDefinition {
     pub name: String,
     pub args: Box<[String]>,
+    pub arg_types: Box<[Type]>,
+    pub arg_spans: Box<[Span]>,
     pub body: Expr,
+    // whether this is the main function formed from a script's top-level expressions
+    pub implicit: bool,
+}
+
+struct FuncSignature<'a> {
+    name: String,
+    args: Box<[String]>,
+    arg_types: Box<[Type]>,
+    arg_spans: Box<[Span]>,
+    body: &'a [ParseExpr],
+    span: Span,
+    implicit: bool,
+}
+
+// memory is addressed in units of one integer, so each field takes up one address
+struct StructLayout {
+    field_offsets: HashMap<String, i64>,
+}
+
+struct Env<'a> {
+    // maps each function name to the number of args it takes
+    funcs: &'a HashMap<String, usize>,
+    structs: &'a HashMap<String, StructLayout>,
+    // labels of the enclosing loops
+    loop_labels: Vec<String>,
+}
+
+impl Env<'_> {
+    fn with_loop_label(&self, label: &Option<String>) -> Result<Self> {
+        let mut loop_labels = self.loop_labels.clone();
+        if let Some(label) = label {
+            if loop_labels.contains(label) {
+                bail!("loop label {} is already used by an enclosing loop", label);
+            }
+            loop_labels.push(label.clone());
+        }
+        Ok(Env {
+            funcs: self.funcs,
+            structs: self.structs,
+            loop_labels,
+        })
+    }
 }
 
 #[derive(Debug)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub const fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
     VarDecl {
         name: String,
+        // if absent, the variable takes the type of its initial value
+        ty: Option<Type>,
         value: Box<Expr>,
     },
     VarAssign {
@@ -36,32 +105,81 @@ pub enum Expr {
     VarAccess(String),
     ArithOp {
         operator: BinaryOperator,
+        // filled in by the type checker
+        width: IntType,
+        arg1: Box<Expr>,
+        arg2: Box<Expr>,
+    },
+    // short-circuiting, so arg2 is only evaluated when needed
+    LogicalOp {
+        operator: LogicalOperator,
         arg1: Box<Expr>,
         arg2: Box<Expr>,
     },
     Block(Box<[Expr]>),
+    // like a block, but declarations inside it are not visible afterwards
+    Scope(Box<Expr>),
     IfElse {
         pred: Box<Expr>,
         conseq: Box<Expr>,
         alt: Box<Expr>,
     },
-    Loop(Box<Expr>),
-    Break,
-    Continue,
+    Loop {
+        label: Option<String>,
+        body: Box<Expr>,
+    },
+    // with no label, these target the innermost loop
+    Break {
+        label: Option<String>,
+        // if present, the loop evaluates to this value
+        value: Option<Box<Expr>>,
+    },
+    Continue(Option<String>),
     IntegerLiteral(i64),
     Noop,
     Return(Option<Box<Expr>>),
     Input,
     UnaryOp {
         operator: UnaryOperator,
+        width: IntType,
         arg: Box<Expr>,
     },
+    // explicit conversions, which may truncate the value
+    Cast {
+        ty: Type,
+        value: Box<Expr>,
+    },
     ReadMemory(Box<Expr>),
+    WriteMemory {
+        addr: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Call {
+        func: String,
+        args: Box<[Expr]>,
+    },
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum UnaryOperator {
     Not,
+    // truncates its argument to the operation width
+    Wrap,
+}
+
+impl UnaryOperator {
+    pub const fn apply(self, width: IntType, arg: i64) -> i64 {
+        width.wrap(match self {
+            UnaryOperator::Not => !arg,
+            UnaryOperator::Wrap => arg,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum LogicalOperator {
+    And,
+    Or,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -72,6 +190,28 @@ pub enum BinaryOperator {
     Div,
     Xor,
     And,
+    Or,
+    // shift amounts only use their low 6 bits
+    Shl,
+    // arithmetic (sign-extending) and logical right shifts
+    Shr,
+    Shru,
+    Rem,
+    // unsigned division and remainder
+    Divu,
+    Remu,
+    // comparisons evaluate to 1 if true and 0 otherwise
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // unsigned comparisons
+    Ltu,
+    Leu,
+    Gtu,
+    Geu,
 }
 
 impl BinaryOperator {
@@ -80,10 +220,114 @@ impl BinaryOperator {
             BinaryOperator::Add
             | BinaryOperator::Mul
             | BinaryOperator::And
-            | BinaryOperator::Xor => true,
-            BinaryOperator::Sub | BinaryOperator::Div => false,
+            | BinaryOperator::Xor
+            | BinaryOperator::Or => true,
+            BinaryOperator::Sub
+            | BinaryOperator::Div
+            | BinaryOperator::Rem
+            | BinaryOperator::Divu
+            | BinaryOperator::Remu
+            | BinaryOperator::Shl
+            | BinaryOperator::Shr
+            | BinaryOperator::Shru
+            | BinaryOperator::Eq
+            | BinaryOperator::Ne
+            | BinaryOperator::Lt
+            | BinaryOperator::Le
+            | BinaryOperator::Gt
+            | BinaryOperator::Ge
+            | BinaryOperator::Ltu
+            | BinaryOperator::Leu
+            | BinaryOperator::Gtu
+            | BinaryOperator::Geu => false,
         }
     }
+
+    pub const fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Eq
+                | BinaryOperator::Ne
+                | BinaryOperator::Lt
+                | BinaryOperator::Le
+                | BinaryOperator::Gt
+                | BinaryOperator::Ge
+                | BinaryOperator::Ltu
+                | BinaryOperator::Leu
+                | BinaryOperator::Gtu
+                | BinaryOperator::Geu
+        )
+    }
+
+    // whether the result of this operation on two values of some width always fits in that width
+    pub const fn preserves_width(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Xor | BinaryOperator::And | BinaryOperator::Or | BinaryOperator::Shr
+        ) || self.is_comparison()
+    }
+
+    // the equivalent operator for unsigned operands
+    pub const fn to_unsigned(self) -> Self {
+        match self {
+            BinaryOperator::Div => BinaryOperator::Divu,
+            BinaryOperator::Rem => BinaryOperator::Remu,
+            BinaryOperator::Shr => BinaryOperator::Shru,
+            BinaryOperator::Lt => BinaryOperator::Ltu,
+            BinaryOperator::Le => BinaryOperator::Leu,
+            BinaryOperator::Gt => BinaryOperator::Gtu,
+            BinaryOperator::Ge => BinaryOperator::Geu,
+            _ => self,
+        }
+    }
+
+    // all arithmetic wraps on overflow, at the given width. Division truncates towards zero,
+    // dividing by zero yields -1, and the remainder of dividing by zero is the dividend
+    pub fn apply(self, width: IntType, arg1: i64, arg2: i64) -> i64 {
+        // unsigned operations reinterpret the same bits
+        let (uarg1, uarg2) = (arg1 as u64, arg2 as u64);
+        let out = match self {
+            BinaryOperator::Add => arg1.wrapping_add(arg2),
+            BinaryOperator::Mul => arg1.wrapping_mul(arg2),
+            BinaryOperator::Sub => arg1.wrapping_sub(arg2),
+            BinaryOperator::Div => match arg2 {
+                0 => -1,
+                _ => arg1.wrapping_div(arg2),
+            },
+            BinaryOperator::Rem => match arg2 {
+                0 => arg1,
+                _ => arg1.wrapping_rem(arg2),
+            },
+            BinaryOperator::Divu => match uarg2 {
+                0 => -1,
+                _ => (uarg1 / uarg2) as i64,
+            },
+            BinaryOperator::Remu => match uarg2 {
+                0 => arg1,
+                _ => (uarg1 % uarg2) as i64,
+            },
+            BinaryOperator::Xor => arg1 ^ arg2,
+            BinaryOperator::And => arg1 & arg2,
+            BinaryOperator::Or => arg1 | arg2,
+            BinaryOperator::Shl => arg1.wrapping_shl(arg2 as u32),
+            BinaryOperator::Shr => arg1.wrapping_shr(arg2 as u32),
+            // the vacated high bits are zero at the operation width, not at 64 bits
+            BinaryOperator::Shru => {
+                ((uarg1 & width.mask() as u64).wrapping_shr(arg2 as u32)) as i64
+            }
+            BinaryOperator::Eq => return i64::from(arg1 == arg2),
+            BinaryOperator::Ne => return i64::from(arg1 != arg2),
+            BinaryOperator::Lt => return i64::from(arg1 < arg2),
+            BinaryOperator::Le => return i64::from(arg1 <= arg2),
+            BinaryOperator::Gt => return i64::from(arg1 > arg2),
+            BinaryOperator::Ge => return i64::from(arg1 >= arg2),
+            BinaryOperator::Ltu => return i64::from(uarg1 < uarg2),
+            BinaryOperator::Leu => return i64::from(uarg1 <= uarg2),
+            BinaryOperator::Gtu => return i64::from(uarg1 > uarg2),
+            BinaryOperator::Geu => return i64::from(uarg1 >= uarg2),
+        };
+        width.wrap(out)
+    }
 }
 
 fn nest_varargs(operator: BinaryOperator, mut args: Vec<Expr>) -> Result<Expr> {
@@ -93,21 +337,32 @@ fn nest_varargs(operator: BinaryOperator, mut args: Vec<Expr>) -> Result<Expr> {
     Ok(if args.is_empty() {
         first
     } else {
-        Expr::ArithOp {
-            operator,
-            arg1: Box::new(first),
-            arg2: Box::new(nest_varargs(operator, args)?),
-        }
+        let rest = nest_varargs(operator, args)?;
+        let span = first.span.to(rest.span);
+        Expr::new(
+            ExprKind::ArithOp {
+                operator,
+                width: IntType::I64,
+                arg1: Box::new(first),
+                arg2: Box::new(rest),
+            },
+            span,
+        )
     })
 }
 
-fn analyze_arithop(operator: BinaryOperator, operands: &[ParseExpr]) -> Result<Expr> {
-    let mut operands = operands.iter().map(analyze_expr).collect::<Result<_>>()?;
+fn analyze_arithop(
+    operator: BinaryOperator,
+    operands: &[ParseExpr],
+    env: &Env,
+) -> Result<ExprKind> {
+    let mut operands = collect_errors(operands.iter().map(|expr| analyze_expr(expr, env)))?;
     Ok(if operator.is_variadic() {
-        nest_varargs(operator, operands)?
+        nest_varargs(operator, operands)?.kind
     } else if operands.len() == 2 {
-        Expr::ArithOp {
+        ExprKind::ArithOp {
             operator,
+            width: IntType::I64,
             arg2: Box::new(operands.pop().unwrap()),
             arg1: Box::new(operands.pop().unwrap()),
         }
@@ -116,182 +371,822 @@ fn analyze_arithop(operator: BinaryOperator, operands: &[ParseExpr]) -> Result<E
     })
 }
 
-fn analyze_if(operands: &[ParseExpr]) -> Result<Expr> {
+fn compare_with_zero(operator: BinaryOperator, expr: Expr) -> Expr {
+    let span = expr.span;
+    Expr::new(
+        ExprKind::ArithOp {
+            operator,
+            width: IntType::I64,
+            arg1: Box::new(expr),
+            arg2: Box::new(Expr::new(ExprKind::IntegerLiteral(0), span)),
+        },
+        span,
+    )
+}
+
+fn analyze_logical_op(
+    operator: LogicalOperator,
+    operands: &[ParseExpr],
+    env: &Env,
+) -> Result<ExprKind> {
+    let operands = collect_errors(operands.iter().map(|expr| analyze_expr(expr, env)))?;
+    // nest to the right, so that operands are still evaluated left to right
+    let nested = operands
+        .into_iter()
+        .rev()
+        .reduce(|rest, first| {
+            let span = first.span.to(rest.span);
+            Expr::new(
+                ExprKind::LogicalOp {
+                    operator,
+                    arg1: Box::new(first),
+                    arg2: Box::new(rest),
+                },
+                span,
+            )
+        })
+        .context("logical operations require at least one argument")?;
+    Ok(match nested.kind {
+        ExprKind::LogicalOp { .. } => nested.kind,
+        // a lone operand still has to be normalized to 0 or 1
+        _ => compare_with_zero(BinaryOperator::Ne, nested).kind,
+    })
+}
+
+fn analyze_logical_not(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    Ok(match operands {
+        [expr] => compare_with_zero(BinaryOperator::Eq, analyze_expr(expr, env)?).kind,
+        _ => bail!("logical not statements have one argument"),
+    })
+}
+
+fn analyze_if(operands: &[ParseExpr], span: Span, env: &Env) -> Result<ExprKind> {
     Ok(match operands {
-        [pred, conseq] => Expr::IfElse {
-            pred: Box::new(analyze_expr(pred)?),
-            conseq: Box::new(analyze_expr(conseq)?),
-            alt: Box::new(Expr::Noop),
+        [pred, conseq] => ExprKind::IfElse {
+            pred: Box::new(analyze_expr(pred, env)?),
+            conseq: Box::new(analyze_expr(conseq, env)?),
+            alt: Box::new(Expr::new(ExprKind::Noop, span)),
         },
-        [pred, conseq, alt] => Expr::IfElse {
-            pred: Box::new(analyze_expr(pred)?),
-            conseq: Box::new(analyze_expr(conseq)?),
-            alt: Box::new(analyze_expr(alt)?),
+        [pred, conseq, alt] => ExprKind::IfElse {
+            pred: Box::new(analyze_expr(pred, env)?),
+            conseq: Box::new(analyze_expr(conseq, env)?),
+            alt: Box::new(analyze_expr(alt, env)?),
         },
         _ => bail!("if statements must have either two or three arguments"),
     })
 }
 
-fn analyze_define(operands: &[ParseExpr]) -> Result<Expr> {
+fn analyze_type(expr: &ParseExpr) -> Result<Type> {
+    let ParseExpr::Symbol(name, span) = expr else {
+        bail_at!(expr.span(), "types must be symbols");
+    };
+    match Type::parse(name) {
+        Some(ty) => Ok(ty),
+        None => bail_at!(*span, "unknown type {}", name),
+    }
+}
+
+// a variable or parameter name, optionally annotated as (name type)
+fn analyze_binding(expr: &ParseExpr) -> Result<Option<(String, Option<Type>)>> {
+    Ok(match expr {
+        ParseExpr::Symbol(name, _) => Some((name.to_string(), None)),
+        ParseExpr::List(lst, _) => match &lst[..] {
+            [ParseExpr::Symbol(name, _), ty] => Some((name.to_string(), Some(analyze_type(ty)?))),
+            _ => None,
+        },
+        ParseExpr::Integer(..) | ParseExpr::String(..) => None,
+    })
+}
+
+fn analyze_define(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    let [binding, expr] = operands else {
+        bail!("variable declarations must have two arguments");
+    };
+    let Some((name, ty)) = analyze_binding(binding)? else {
+        bail_at!(binding.span(), "variable names must be symbols or (name type) pairs");
+    };
+    Ok(ExprKind::VarDecl {
+        name,
+        ty,
+        value: Box::new(analyze_expr(expr, env)?),
+    })
+}
+
+fn analyze_cast(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    let [ty, expr] = operands else {
+        bail!("casts must be of the form (as type expr)");
+    };
+    Ok(ExprKind::Cast {
+        ty: analyze_type(ty)?,
+        value: Box::new(analyze_expr(expr, env)?),
+    })
+}
+
+fn analyze_assign(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
     Ok(match operands {
-        [ParseExpr::Symbol(name), expr] => Expr::VarDecl {
+        [ParseExpr::Symbol(name, _), expr] => ExprKind::VarAssign {
             name: name.to_string(),
-            value: Box::new(analyze_expr(expr)?),
+            value: Box::new(analyze_expr(expr, env)?),
         },
         _ => bail!("variable declarations must have two arguments, the first being a symbol"),
     })
 }
 
-fn analyze_assign(operands: &[ParseExpr]) -> Result<Expr> {
+// loops can be labeled as (loop :label name body...)
+fn split_loop_label(operands: &[ParseExpr]) -> Result<(Option<String>, &[ParseExpr])> {
     Ok(match operands {
-        [ParseExpr::Symbol(name), expr] => Expr::VarAssign {
-            name: name.to_string(),
-            value: Box::new(analyze_expr(expr)?),
+        [ParseExpr::Symbol(keyword, _), rest @ ..] if keyword == ":label" => match rest {
+            [ParseExpr::Symbol(label, _), body @ ..] => (Some(label.clone()), body),
+            _ => bail!("loop labels must be symbols"),
         },
-        _ => bail!("variable declarations must have two arguments, the first being a symbol"),
+        _ => (None, operands),
     })
 }
 
-fn analyze_loop(operands: &[ParseExpr]) -> Result<Expr> {
-    Ok(Expr::Loop(Box::new(analyze_block(operands)?)))
+fn analyze_loop(operands: &[ParseExpr], span: Span, env: &Env) -> Result<ExprKind> {
+    let (label, body) = split_loop_label(operands)?;
+    let env = env.with_loop_label(&label)?;
+    Ok(ExprKind::Loop {
+        body: Box::new(Expr::new(analyze_block(body, &env)?, span)),
+        label,
+    })
+}
+
+// (while pred body...) is sugar for (loop (if pred (break)) body...), since if takes its
+// consequent when the predicate is zero
+fn analyze_while(operands: &[ParseExpr], span: Span, env: &Env) -> Result<ExprKind> {
+    let (label, operands) = split_loop_label(operands)?;
+    let (pred, body) = operands
+        .split_first()
+        .context("while loops must have a predicate")?;
+    let pred = analyze_expr(pred, env)?;
+    let pred_span = pred.span;
+    let exit = Expr::new(
+        ExprKind::IfElse {
+            pred: Box::new(pred),
+            conseq: Box::new(Expr::new(
+                ExprKind::Break {
+                    label: None,
+                    value: None,
+                },
+                pred_span,
+            )),
+            alt: Box::new(Expr::new(ExprKind::Noop, pred_span)),
+        },
+        pred_span,
+    );
+    let env = env.with_loop_label(&label)?;
+    let body = collect_errors(body.iter().map(|expr| analyze_expr(expr, &env)))?;
+    Ok(ExprKind::Loop {
+        label,
+        body: Box::new(Expr::new(
+            ExprKind::Block(std::iter::once(exit).chain(body).collect()),
+            span,
+        )),
+    })
+}
+
+// (for (i start end [step]) body...) counts i upwards from start while i < end. It is sugar for
+//     (define i (- start step)) (loop (set i (+ i step)) (if (< i end) (break)) body...)
+// so that continuing still increments i. end and step are only evaluated once.
+fn analyze_for(operands: &[ParseExpr], span: Span, env: &Env) -> Result<ExprKind> {
+    let (label, operands) = split_loop_label(operands)?;
+    let Some((ParseExpr::List(header, header_span), body)) = operands.split_first() else {
+        bail!("for loops must begin with a (var start end [step]) header");
+    };
+    let (name, start, end, step) = match &header[..] {
+        [ParseExpr::Symbol(name, _), start, end] => (name, start, end, None),
+        [ParseExpr::Symbol(name, _), start, end, step] => (name, start, end, Some(step)),
+        _ => bail_at!(
+            *header_span,
+            "for loop headers must be (var start end [step]), where var is a symbol"
+        ),
+    };
+
+    let start = analyze_expr(start, env)?;
+    let end = analyze_expr(end, env)?;
+    let step = match step {
+        Some(step) => analyze_expr(step, env)?,
+        None => Expr::new(ExprKind::IntegerLiteral(1), *header_span),
+    };
+
+    // these names contain a space, so they cannot clash with any user-defined variable
+    let start_name = format!("{name} start");
+    let end_name = format!("{name} end");
+    let step_name = format!("{name} step");
+
+    let var = |name: &String, span| Expr::new(ExprKind::VarAccess(name.clone()), span);
+    let arith = |operator, arg1, arg2, span| {
+        Expr::new(
+            ExprKind::ArithOp {
+                operator,
+                width: IntType::I64,
+                arg1: Box::new(arg1),
+                arg2: Box::new(arg2),
+            },
+            span,
+        )
+    };
+    let define = |name: &String, value: Expr| {
+        let span = value.span;
+        Expr::new(
+            ExprKind::VarDecl {
+                name: name.clone(),
+                ty: None,
+                value: Box::new(value),
+            },
+            span,
+        )
+    };
+
+    let step_span = step.span;
+    let end_span = end.span;
+    let mut loop_body = vec![
+        Expr::new(
+            ExprKind::VarAssign {
+                name: name.clone(),
+                value: Box::new(arith(
+                    BinaryOperator::Add,
+                    var(name, step_span),
+                    var(&step_name, step_span),
+                    step_span,
+                )),
+            },
+            step_span,
+        ),
+        Expr::new(
+            ExprKind::IfElse {
+                pred: Box::new(arith(
+                    BinaryOperator::Lt,
+                    var(name, end_span),
+                    var(&end_name, end_span),
+                    end_span,
+                )),
+                conseq: Box::new(Expr::new(
+                    ExprKind::Break {
+                        label: None,
+                        value: None,
+                    },
+                    end_span,
+                )),
+                alt: Box::new(Expr::new(ExprKind::Noop, end_span)),
+            },
+            end_span,
+        ),
+    ];
+    let body_env = env.with_loop_label(&label)?;
+    loop_body.extend(collect_errors(
+        body.iter().map(|expr| analyze_expr(expr, &body_env)),
+    )?);
+
+    let start_span = start.span;
+    // the header is evaluated in order, before the loop variable comes into scope
+    let block = ExprKind::Block(Box::new([
+        define(&start_name, start),
+        define(&end_name, end),
+        define(&step_name, step),
+        define(
+            name,
+            arith(
+                BinaryOperator::Sub,
+                var(&start_name, start_span),
+                var(&step_name, start_span),
+                start_span,
+            ),
+        ),
+        Expr::new(
+            ExprKind::Loop {
+                label,
+                body: Box::new(Expr::new(
+                    ExprKind::Block(loop_body.into_boxed_slice()),
+                    span,
+                )),
+            },
+            span,
+        ),
+    ]));
+    // so that sequential loops can reuse the same variable name
+    Ok(ExprKind::Scope(Box::new(Expr::new(block, span))))
 }
 
-fn analyze_break(operands: &[ParseExpr]) -> Result<Expr> {
+// (cond (pred body...) ... [(else body...)]) takes the first arm whose predicate is nonzero. It is
+// sugar for nested ifs, each of the form (if pred rest body), since if takes its consequent when the
+// predicate is zero
+fn analyze_cond(operands: &[ParseExpr], span: Span, env: &Env) -> Result<ExprKind> {
     if operands.is_empty() {
-        Ok(Expr::Break)
-    } else {
-        bail!("break expressions take no arguments")
+        bail!("cond expressions must have at least one arm");
     }
+    let mut out = Expr::new(ExprKind::Noop, span);
+    for (i, arm) in operands.iter().enumerate().rev() {
+        let ParseExpr::List(arm_exprs, arm_span) = arm else {
+            bail_at!(arm.span(), "cond arms must be lists");
+        };
+        let Some((pred, body)) = arm_exprs.split_first() else {
+            bail_at!(*arm_span, "cond arms must have a predicate followed by a body");
+        };
+        if body.is_empty() {
+            bail_at!(
+                *arm_span,
+                "cond arms must have a predicate followed by a body"
+            );
+        }
+        let body = Expr::new(analyze_block(body, env)?, *arm_span);
+        out = match pred {
+            ParseExpr::Symbol(name, _) if name == "else" => {
+                if i != operands.len() - 1 {
+                    bail_at!(*arm_span, "else must be the final arm of a cond expression");
+                }
+                body
+            }
+            _ => Expr::new(
+                ExprKind::IfElse {
+                    pred: Box::new(analyze_expr(pred, env)?),
+                    conseq: Box::new(out),
+                    alt: Box::new(body),
+                },
+                arm_span.to(span),
+            ),
+        };
+    }
+    Ok(out.kind)
 }
 
-fn analyze_continue(operands: &[ParseExpr]) -> Result<Expr> {
-    if operands.is_empty() {
-        Ok(Expr::Continue)
-    } else {
-        bail!("continue expressions take no arguments")
+// breaks and continues target an enclosing labeled loop as (break :label name ...), so that a label
+// can never be mistaken for a value
+fn split_target_label<'a>(
+    operands: &'a [ParseExpr],
+    env: &Env,
+) -> Result<(Option<String>, &'a [ParseExpr])> {
+    Ok(match operands {
+        [ParseExpr::Symbol(keyword, _), rest @ ..] if keyword == ":label" => match rest {
+            [ParseExpr::Symbol(label, span), rest @ ..] => {
+                if !env.loop_labels.contains(label) {
+                    bail_at!(*span, "no enclosing loop is labeled {}", label);
+                }
+                (Some(label.clone()), rest)
+            }
+            _ => bail!("loop labels must be symbols"),
+        },
+        _ => (None, operands),
+    })
+}
+
+fn analyze_break(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    let (label, operands) = split_target_label(operands, env)?;
+    let value = match operands {
+        [] => None,
+        [value] => Some(Box::new(analyze_expr(value, env)?)),
+        _ => bail!("break expressions take an optional :label and an optional value"),
+    };
+    Ok(ExprKind::Break { label, value })
+}
+
+fn analyze_continue(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    match split_target_label(operands, env)? {
+        (label, []) => Ok(ExprKind::Continue(label)),
+        _ => bail!("continue expressions take an optional :label"),
     }
 }
 
-fn analyze_return(operands: &[ParseExpr]) -> Result<Expr> {
+fn analyze_return(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
     Ok(match operands {
-        [] => Expr::Return(None),
-        [expr] => Expr::Return(Some(Box::new(analyze_expr(expr)?))),
+        [] => ExprKind::Return(None),
+        [expr] => ExprKind::Return(Some(Box::new(analyze_expr(expr, env)?))),
         _ => bail!("return statements have one optional argument"),
     })
 }
 
-fn analyze_input(operands: &[ParseExpr]) -> Result<Expr> {
+fn analyze_input(operands: &[ParseExpr]) -> Result<ExprKind> {
     if operands.is_empty() {
-        Ok(Expr::Input)
+        Ok(ExprKind::Input)
     } else {
         bail!("input expressions take no arguments")
     }
 }
 
-fn analyze_read_memory(operands: &[ParseExpr]) -> Result<Expr> {
+fn analyze_read_memory(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
     Ok(match operands {
-        [expr] => Expr::ReadMemory(Box::new(analyze_expr(expr)?)),
+        [expr] => ExprKind::ReadMemory(Box::new(analyze_expr(expr, env)?)),
         _ => bail!("memory read instructions have one argument"),
     })
 }
 
-fn analyze_unary_operator(operator: UnaryOperator, operands: &[ParseExpr]) -> Result<Expr> {
+fn analyze_write_memory(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    Ok(match operands {
+        [addr, value] => ExprKind::WriteMemory {
+            addr: Box::new(analyze_expr(addr, env)?),
+            value: Box::new(analyze_expr(value, env)?),
+        },
+        _ => bail!("memory write instructions have two arguments"),
+    })
+}
+
+fn analyze_field_addr(
+    ptr: &ParseExpr,
+    struct_name: &ParseExpr,
+    field: &ParseExpr,
+    env: &Env,
+) -> Result<Expr> {
+    let ParseExpr::Symbol(struct_name, struct_span) = struct_name else {
+        bail_at!(struct_name.span(), "struct names must be symbols");
+    };
+    let ParseExpr::Symbol(field, field_span) = field else {
+        bail_at!(field.span(), "struct fields must be symbols");
+    };
+    let layout = match env.structs.get(struct_name) {
+        Some(layout) => layout,
+        None => bail_at!(*struct_span, "unknown struct {}", struct_name),
+    };
+    let offset = match layout.field_offsets.get(field) {
+        Some(offset) => *offset,
+        None => bail_at!(*field_span, "struct {} has no field {}", struct_name, field),
+    };
+    let ptr = analyze_expr(ptr, env)?;
+    Ok(if offset == 0 {
+        ptr
+    } else {
+        let span = ptr.span.to(*field_span);
+        Expr::new(
+            ExprKind::ArithOp {
+                operator: BinaryOperator::Add,
+                width: IntType::I64,
+                arg1: Box::new(ptr),
+                arg2: Box::new(Expr::new(ExprKind::IntegerLiteral(offset), *field_span)),
+            },
+            span,
+        )
+    })
+}
+
+// (get ptr Name field) is sugar for (read (+ ptr offset))
+fn analyze_get(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    Ok(match operands {
+        [ptr, struct_name, field] => {
+            ExprKind::ReadMemory(Box::new(analyze_field_addr(ptr, struct_name, field, env)?))
+        }
+        _ => bail!("field reads must be of the form (get ptr struct field)"),
+    })
+}
+
+// (put ptr Name field value) is sugar for (write (+ ptr offset) value)
+fn analyze_put(operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    Ok(match operands {
+        [ptr, struct_name, field, value] => ExprKind::WriteMemory {
+            addr: Box::new(analyze_field_addr(ptr, struct_name, field, env)?),
+            value: Box::new(analyze_expr(value, env)?),
+        },
+        _ => bail!("field writes must be of the form (put ptr struct field value)"),
+    })
+}
+
+fn analyze_unary_operator(
+    operator: UnaryOperator,
+    operands: &[ParseExpr],
+    env: &Env,
+) -> Result<ExprKind> {
     Ok(match operands {
-        [expr] => Expr::UnaryOp {
+        [expr] => ExprKind::UnaryOp {
             operator,
-            arg: Box::new(analyze_expr(expr)?),
+            width: IntType::I64,
+            arg: Box::new(analyze_expr(expr, env)?),
         },
-        _ => bail!("logical not statements have one argument"),
+        _ => bail!("bitwise not statements have one argument"),
+    })
+}
+
+fn analyze_call(func: &str, operands: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    let arity = *env
+        .funcs
+        .get(func)
+        .with_context(|| format!("invalid operator in call expression: {}", func))?;
+    if operands.len() != arity {
+        bail!(
+            "function {} takes {} arguments, but {} were provided",
+            func,
+            arity,
+            operands.len()
+        );
+    }
+    Ok(ExprKind::Call {
+        func: func.to_string(),
+        args: collect_errors(operands.iter().map(|expr| analyze_expr(expr, env)))?
+            .into_boxed_slice(),
     })
 }
 
-fn analyze_block(exprs: &[ParseExpr]) -> Result<Expr> {
-    Ok(Expr::Block(
-        exprs.iter().map(analyze_expr).collect::<Result<_>>()?,
+fn analyze_block(exprs: &[ParseExpr], env: &Env) -> Result<ExprKind> {
+    Ok(ExprKind::Block(
+        collect_errors(exprs.iter().map(|expr| analyze_expr(expr, env)))?.into_boxed_slice(),
     ))
 }
 
-fn analyze_expr(expr: &ParseExpr) -> Result<Expr> {
+fn analyze_expr(expr: &ParseExpr, env: &Env) -> Result<Expr> {
+    let kind = analyze_expr_kind(expr, env).at(expr.span())?;
+    Ok(Expr::new(kind, expr.span()))
+}
+
+// every operator recognized by analyze_expr_kind, for editor completion
+pub const BUILTIN_OPERATORS: &[&str] = &[
+    "+", "*", "-", "/", "/u", "^", "&", "|", "%", "%u", "<<", ">>", ">>u", "=", "!=", "<", "<=",
+    ">", ">=", "<u", "<=u", ">u", ">=u", "~", "and", "or", "not", "as", "read", "write", "get",
+    "put", "if", "define", "set", "loop", "while", "for", "cond", "break", "continue", "begin",
+    "return", "input",
+];
+
+fn analyze_expr_kind(expr: &ParseExpr, env: &Env) -> Result<ExprKind> {
     Ok(match expr {
-        ParseExpr::Integer(val) => Expr::IntegerLiteral(*val),
-        ParseExpr::List(call_expr) => {
-            if let Some((ParseExpr::Symbol(operator), operands)) = call_expr.split_first() {
+        ParseExpr::Integer(val, _) => ExprKind::IntegerLiteral(*val),
+        ParseExpr::List(call_expr, span) => {
+            if let Some((ParseExpr::Symbol(operator, _), operands)) = call_expr.split_first() {
                 match operator.as_str() {
-                    "+" => analyze_arithop(BinaryOperator::Add, operands)?,
-                    "*" => analyze_arithop(BinaryOperator::Mul, operands)?,
-                    "-" => analyze_arithop(BinaryOperator::Sub, operands)?,
-                    "/" => analyze_arithop(BinaryOperator::Div, operands)?,
-                    "^" => analyze_arithop(BinaryOperator::Xor, operands)?,
-                    "&" => analyze_arithop(BinaryOperator::And, operands)?,
-                    "~" => analyze_unary_operator(UnaryOperator::Not, operands)?,
-                    "read" => analyze_read_memory(operands)?,
-                    "if" => analyze_if(operands)?,
-                    "define" => analyze_define(operands)?,
-                    "set" => analyze_assign(operands)?,
-                    "loop" => analyze_loop(operands)?,
-                    "break" => analyze_break(operands)?,
-                    "continue" => analyze_continue(operands)?,
-                    "begin" => analyze_block(operands)?,
-                    "return" => analyze_return(operands)?,
+                    "+" => analyze_arithop(BinaryOperator::Add, operands, env)?,
+                    "*" => analyze_arithop(BinaryOperator::Mul, operands, env)?,
+                    "-" => analyze_arithop(BinaryOperator::Sub, operands, env)?,
+                    "/" => analyze_arithop(BinaryOperator::Div, operands, env)?,
+                    "/u" => analyze_arithop(BinaryOperator::Divu, operands, env)?,
+                    "^" => analyze_arithop(BinaryOperator::Xor, operands, env)?,
+                    "&" => analyze_arithop(BinaryOperator::And, operands, env)?,
+                    "|" => analyze_arithop(BinaryOperator::Or, operands, env)?,
+                    "%" => analyze_arithop(BinaryOperator::Rem, operands, env)?,
+                    "%u" => analyze_arithop(BinaryOperator::Remu, operands, env)?,
+                    "<<" => analyze_arithop(BinaryOperator::Shl, operands, env)?,
+                    ">>" => analyze_arithop(BinaryOperator::Shr, operands, env)?,
+                    ">>u" => analyze_arithop(BinaryOperator::Shru, operands, env)?,
+                    "=" => analyze_arithop(BinaryOperator::Eq, operands, env)?,
+                    "!=" => analyze_arithop(BinaryOperator::Ne, operands, env)?,
+                    "<" => analyze_arithop(BinaryOperator::Lt, operands, env)?,
+                    "<=" => analyze_arithop(BinaryOperator::Le, operands, env)?,
+                    ">" => analyze_arithop(BinaryOperator::Gt, operands, env)?,
+                    ">=" => analyze_arithop(BinaryOperator::Ge, operands, env)?,
+                    "<u" => analyze_arithop(BinaryOperator::Ltu, operands, env)?,
+                    "<=u" => analyze_arithop(BinaryOperator::Leu, operands, env)?,
+                    ">u" => analyze_arithop(BinaryOperator::Gtu, operands, env)?,
+                    ">=u" => analyze_arithop(BinaryOperator::Geu, operands, env)?,
+                    "~" => analyze_unary_operator(UnaryOperator::Not, operands, env)?,
+                    "and" => analyze_logical_op(LogicalOperator::And, operands, env)?,
+                    "or" => analyze_logical_op(LogicalOperator::Or, operands, env)?,
+                    "not" => analyze_logical_not(operands, env)?,
+                    "as" => analyze_cast(operands, env)?,
+                    "read" => analyze_read_memory(operands, env)?,
+                    "write" => analyze_write_memory(operands, env)?,
+                    "get" => analyze_get(operands, env)?,
+                    "put" => analyze_put(operands, env)?,
+                    "if" => analyze_if(operands, *span, env)?,
+                    "define" => analyze_define(operands, env)?,
+                    "set" => analyze_assign(operands, env)?,
+                    "loop" => analyze_loop(operands, *span, env)?,
+                    "while" => analyze_while(operands, *span, env)?,
+                    "for" => analyze_for(operands, *span, env)?,
+                    "cond" => analyze_cond(operands, *span, env)?,
+                    "break" => analyze_break(operands, env)?,
+                    "continue" => analyze_continue(operands, env)?,
+                    "begin" => analyze_block(operands, env)?,
+                    "return" => analyze_return(operands, env)?,
                     "input" => analyze_input(operands)?,
-                    _ => bail!("invalid operator in call expression: {}", operator),
+                    _ => analyze_call(operator, operands, env)?,
                 }
             } else {
                 bail!("call expressions must have an operator")
             }
         }
-        ParseExpr::Symbol(val) => Expr::VarAccess(val.to_string()),
+        ParseExpr::Symbol(val, _) => ExprKind::VarAccess(val.to_string()),
+        ParseExpr::String(..) => bail!("string literals can only be used in imports"),
     })
 }
 
-fn analyze_function(exprs: &[ParseExpr]) -> Result<FuncDefinition> {
+fn analyze_signature(exprs: &[ParseExpr], span: Span) -> Result<FuncSignature> {
     let (signature, body) = exprs
         .split_first()
         .context("functions must have a signature")?;
-    let ParseExpr::List(signature) = signature else {
-        bail!("function signatures must be lists");
+    let ParseExpr::List(signature, signature_span) = signature else {
+        bail_at!(signature.span(), "function signatures must be lists");
     };
     let (name, args) = signature
         .split_first()
-        .context("function signatures cannot be empty")?;
-    let ParseExpr::Symbol(name) = name else {
-        bail!("function signatures must begin with the name");
+        .context("function signatures cannot be empty")
+        .at(*signature_span)?;
+    let ParseExpr::Symbol(name, _) = name else {
+        bail_at!(name.span(), "function signatures must begin with the name");
     };
-    let args = args
+    let (args, arg_types, arg_spans): (Vec<_>, Vec<_>, Vec<_>) = args
         .iter()
-        .map(|arg| match arg {
-            ParseExpr::Symbol(arg) => Some(arg.to_owned()),
-            _ => None,
+        .map(|arg| match analyze_binding(arg)? {
+            // parameters are i64s unless annotated otherwise
+            Some((name, ty)) => Ok((name, ty.unwrap_or(Type::I64), arg.span())),
+            None => bail_at!(arg.span(), "all args must be symbols or (name type) pairs"),
         })
-        .collect::<Option<_>>()
-        .context("all args must be symbols")?;
-    Ok(FuncDefinition {
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .multiunzip();
+    Ok(FuncSignature {
         name: name.to_owned(),
-        args,
-        body: analyze_block(body)?,
+        args: args.into_boxed_slice(),
+        arg_types: arg_types.into_boxed_slice(),
+        arg_spans: arg_spans.into_boxed_slice(),
+        body,
+        span,
+        implicit: false,
     })
 }
 
-pub fn analyze(exprs: &[ParseExpr]) -> Result<Program<FuncDefinition>> {
-    let mut funcs = HashMap::new();
-    for expr in exprs {
-        let ParseExpr::List(lst) = expr else {
-            bail!("all top-level expressions must be functions or structs");
+fn analyze_function(signature: FuncSignature, env: &Env) -> Result<FuncDefinition> {
+    Ok(FuncDefinition {
+        name: signature.name,
+        args: signature.args,
+        arg_types: signature.arg_types,
+        arg_spans: signature.arg_spans,
+        body: Expr::new(analyze_block(signature.body, env)?, signature.span),
+        implicit: signature.implicit,
+    })
+}
+
+fn analyze_struct(operands: &[ParseExpr], span: Span) -> Result<(String, StructLayout)> {
+    let [ParseExpr::Symbol(name, _), ParseExpr::List(fields, _)] = operands else {
+        bail_at!(span, "struct definitions must be of the form (struct name (field1 ...))");
+    };
+    let mut field_offsets = HashMap::new();
+    for (offset, field) in fields.iter().enumerate() {
+        let ParseExpr::Symbol(field_name, field_span) = field else {
+            bail_at!(field.span(), "all struct fields must be symbols");
         };
-        let Some((ParseExpr::Symbol(operator), operands)) = lst.split_first() else {
-            bail!("all top-level expressions must be functions or structs");
+        if field_offsets
+            .insert(field_name.clone(), offset as i64)
+            .is_some()
+        {
+            bail_at!(*field_span, "all fields of a struct must be uniquely named");
+        }
+    }
+    Ok((name.clone(), StructLayout { field_offsets }))
+}
+
+// imported maps each function imported from another file to the number of args it takes.
+// if script is set, top-level expressions other than definitions form an implicit main function
+pub fn analyze(
+    exprs: &[ParseExpr],
+    imported: &HashMap<String, usize>,
+    script: bool,
+) -> Result<Program<FuncDefinition>> {
+    let mut script_body = vec![];
+    let mut signatures = vec![];
+    let mut structs = HashMap::new();
+    // each top-level expression is analyzed even if an earlier one fails
+    collect_errors(exprs.iter().map(|expr| {
+        let operator = match expr {
+            ParseExpr::List(lst, _) => match lst.split_first() {
+                Some((ParseExpr::Symbol(operator, _), operands)) => {
+                    Some((operator.as_str(), operands))
+                }
+                _ => None,
+            },
+            _ => None,
         };
-        match operator.as_str() {
-            "func" => {
-                let func = analyze_function(operands)?;
-                if funcs.insert(func.name.clone(), func).is_some() {
-                    bail!("all functions must be uniquely named");
-                };
+        let span = expr.span();
+        match operator {
+            Some(("func", operands)) => {
+                signatures.push(analyze_signature(operands, span).at(span)?);
+            }
+            Some(("struct", operands)) => {
+                let (name, layout) = analyze_struct(operands, span)?;
+                if structs.insert(name, layout).is_some() {
+                    bail_at!(span, "all structs must be uniquely named");
+                }
             }
+            // already resolved by load_program
+            Some(("import", _)) => {}
+            _ if script => script_body.push(expr.clone()),
             _ => {
-                bail!("all top-level expressions must be functions or structs");
+                bail_at!(
+                    span,
+                    "all top-level expressions in imported files must be functions, structs or imports"
+                );
             }
         }
+        Ok(())
+    }))?;
+    if let (Some(first), Some(last)) = (script_body.first(), script_body.last()) {
+        let span = first.span().to(last.span());
+        if let Some(main) = signatures.iter().find(|signature| signature.name == "main") {
+            bail_at!(
+                main.span,
+                "main cannot be defined in a file with top-level expressions, since they form an implicit main function"
+            );
+        }
+        signatures.push(FuncSignature {
+            name: "main".to_string(),
+            args: Box::new([]),
+            arg_types: Box::new([]),
+            arg_spans: Box::new([]),
+            body: &script_body,
+            span,
+            implicit: true,
+        });
+    }
+
+    // collect all signatures before analyzing any bodies, so functions can call each other
+    let mut funcs = HashMap::new();
+    collect_errors(signatures.iter().map(|signature| {
+        if funcs
+            .insert(signature.name.clone(), signature.args.len())
+            .is_some()
+        {
+            bail_at!(signature.span, "all functions must be uniquely named");
+        }
+        Ok(())
+    }))?;
+    // clashes with local functions are reported by load_program, which knows where both are from
+    for (name, arity) in imported {
+        funcs.entry(name.clone()).or_insert(*arity);
+    }
+    let env = Env {
+        funcs: &funcs,
+        structs: &structs,
+        loop_labels: vec![],
+    };
+
+    let funcs = collect_errors(signatures.into_iter().map(|signature| {
+        let func = analyze_function(signature, &env)?;
+        Ok((func.name.clone(), func))
+    }))?;
+    Ok(Program {
+        funcs: funcs.into_iter().collect(),
+    })
+}
+
+// loads and type checks source as if it were the file test.lang, for tests
+#[cfg(test)]
+pub fn load_test_program(source: &str) -> Result<Program<FuncDefinition>> {
+    let mut program = load_program_from_source(
+        std::path::Path::new("test.lang"),
+        source.to_string(),
+        &mut crate::diagnostics::SourceMap::new(),
+    )?;
+    typecheck(&mut program)?;
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::load_test_program;
+    use crate::interpreter::interpret;
+    use crate::ir::gen_test_ir;
+
+    fn run(source: &str) -> Option<i64> {
+        let program = gen_test_ir(source).unwrap();
+        interpret(&program, "main", vec![], vec![], HashMap::new()).unwrap()
+    }
+
+    fn error(source: &str) -> String {
+        let Err(err) = load_test_program(source) else {
+            panic!("expected an error");
+        };
+        format!("{:#}", err)
+    }
+
+    #[test]
+    fn structs() {
+        let source = "
+(struct Point (x y))
+(struct Pair (first second))
+(func (main)
+    (put 10 Point y 2)
+    (put 10 Point x 1)
+    (put 20 Pair second (get 10 Point y))
+    (return (+ (* 100 (read 10)) (* 10 (read 11)) (get 20 Pair second))))";
+        assert_eq!(run(source), Some(122));
+        assert_eq!(
+            error("(struct P (x x))"),
+            "all fields of a struct must be uniquely named"
+        );
+        assert_eq!(
+            error("(struct P (x)) (struct P (y))"),
+            "all structs must be uniquely named"
+        );
+        assert_eq!(error("(get 0 Q x)"), "unknown struct Q");
+        assert_eq!(
+            error("(struct P (x)) (get 0 P y)"),
+            "struct P has no field y"
+        );
+        assert_eq!(
+            error("(struct P (x)) (put 0 P x)"),
+            "field writes must be of the form (put ptr struct field value)"
+        );
+    }
+
+    #[test]
+    fn implicit_main() {
+        // top-level expressions run in order, around the definitions between them
+        let source = "
+(write 0 1)
+(func (f) (write 0 (+ (* (read 0) 10) 2)) (return))
+(f)
+(write 0 (+ (* (read 0) 10) 3))
+(return (read 0))";
+        assert_eq!(run(source), Some(123));
+        assert_eq!(
+            error("(write 0 1) (func (main) (return 0))"),
+            "main cannot be defined in a file with top-level expressions, since they form an implicit main function"
+        );
     }
-    Ok(Program { funcs })
 }
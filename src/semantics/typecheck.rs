@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::mem::replace;
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use super::{BinaryOperator, Expr, ExprKind, FuncDefinition, IntType, Program, Type};
+use crate::diagnostics::{bail_at, collect_errors, Span, WithSpan};
+
+struct Checker<'a> {
+    // the parameter types of each function. Every function returns an i64
+    signatures: &'a HashMap<String, Box<[Type]>>,
+    // mirrors the frames used in ir::gen, so a variable is visible here iff it is visible there
+    scopes: Vec<HashMap<String, Type>>,
+    // the label of each enclosing loop, and the type of the values its breaks carry, if any
+    loops: Vec<(Option<String>, Option<Type>)>,
+    // the type of each expression checked so far, or None for statements
+    types: Vec<(Span, Option<Type>)>,
+}
+
+// an integer literal takes on the type of the value it is used with, if it fits
+fn adopt_type(expr: &Expr, other: &Type) -> Option<Type> {
+    let ExprKind::IntegerLiteral(val) = expr.kind else {
+        return None;
+    };
+    let fits = match other {
+        Type::Int(int_type) => int_type.fits(val),
+        Type::Bool => val == 0 || val == 1,
+        // so that pointers can be compared against 0
+        Type::Ptr(_) => val == 0,
+    };
+    fits.then(|| other.clone())
+}
+
+fn unify(a: Type, b: Type) -> Result<Type> {
+    if a.converts_to(&b) {
+        Ok(b)
+    } else if b.converts_to(&a) {
+        Ok(a)
+    } else {
+        bail!(
+            "mismatched types {} and {}, use (as <type> <expr>) to convert between them",
+            a,
+            b
+        )
+    }
+}
+
+impl Checker<'_> {
+    fn lookup(&self, name: &str) -> Option<Type> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()
+    }
+
+    fn in_scope<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.scopes.push(HashMap::new());
+        let out = f(self);
+        self.scopes.pop();
+        out
+    }
+
+    fn check(&mut self, expr: &mut Expr) -> Result<Option<Type>> {
+        let ty = self.check_kind(&mut expr.kind).at(expr.span)?;
+        // memory holds full 64-bit values, so narrow reads must be wrapped to their type
+        if let (ExprKind::ReadMemory(_), Some(Type::Int(int_type))) = (&expr.kind, &ty) {
+            if int_type.bits < 64 {
+                let read = replace(&mut expr.kind, ExprKind::Noop);
+                expr.kind = ExprKind::Cast {
+                    ty: Type::Int(*int_type),
+                    value: Box::new(Expr::new(read, expr.span)),
+                };
+            }
+        }
+        self.types.push((expr.span, ty.clone()));
+        Ok(ty)
+    }
+
+    fn value(&mut self, expr: &mut Expr) -> Result<Type> {
+        self.check(expr)?
+            .context("expected a value, but found a statement")
+            .at(expr.span)
+    }
+
+    fn coerce(&mut self, expr: &mut Expr, target: &Type) -> Result<()> {
+        if let ExprKind::IntegerLiteral(val) = expr.kind {
+            if adopt_type(expr, target).is_none() {
+                bail_at!(
+                    expr.span,
+                    "integer literal {} does not fit in {}",
+                    val,
+                    target
+                );
+            }
+            return Ok(());
+        }
+        let ty = self.value(expr)?;
+        if !ty.converts_to(target) {
+            bail_at!(
+                expr.span,
+                "expected a value of type {}, but found {}",
+                target,
+                ty
+            );
+        }
+        Ok(())
+    }
+
+    fn check_arith(
+        &mut self,
+        operator: &mut BinaryOperator,
+        width: &mut IntType,
+        arg1: &mut Expr,
+        arg2: &mut Expr,
+    ) -> Result<Type> {
+        let t1 = self.value(arg1)?;
+        let t2 = self.value(arg2)?;
+
+        if let BinaryOperator::Shl | BinaryOperator::Shr | BinaryOperator::Shru = operator {
+            let (Type::Int(int_type), Type::Int(_)) = (&t1, &t2) else {
+                bail!("cannot shift a {} by a {}", t1, t2);
+            };
+            *width = *int_type;
+            if !int_type.signed {
+                *operator = operator.to_unsigned();
+            }
+            return Ok(t1);
+        }
+
+        // pointer arithmetic is done on the raw addresses
+        match (&t1, &t2, *operator) {
+            (Type::Ptr(_), Type::Int(_), BinaryOperator::Add | BinaryOperator::Sub) => {
+                *width = t1.int_type();
+                return Ok(t1);
+            }
+            (Type::Int(_), Type::Ptr(_), BinaryOperator::Add) => {
+                *width = t2.int_type();
+                return Ok(t2);
+            }
+            (Type::Ptr(_), Type::Ptr(_), BinaryOperator::Sub) if t1 == t2 => {
+                *width = IntType::I64;
+                return Ok(Type::I64);
+            }
+            _ => {}
+        }
+
+        let t1 = adopt_type(arg1, &t2).unwrap_or(t1);
+        let t2 = adopt_type(arg2, &t1).unwrap_or(t2);
+        let ty = match (unify(t1, t2)?, *operator) {
+            (Type::Ptr(_), operator) if !operator.is_comparison() => {
+                bail!("cannot apply {:?} to pointers", operator)
+            }
+            // bools only stay bools under bitwise operations
+            (Type::Bool, BinaryOperator::And | BinaryOperator::Or | BinaryOperator::Xor) => {
+                Type::Bool
+            }
+            (Type::Bool, operator) if !operator.is_comparison() => Type::I64,
+            (ty, _) => ty,
+        };
+        *width = ty.int_type();
+        if !width.signed {
+            *operator = operator.to_unsigned();
+        }
+        Ok(if operator.is_comparison() {
+            Type::Bool
+        } else {
+            ty
+        })
+    }
+
+    fn check_kind(&mut self, expr: &mut ExprKind) -> Result<Option<Type>> {
+        Ok(match expr {
+            ExprKind::VarDecl { name, ty, value } => {
+                let ty = match ty {
+                    Some(ty) => {
+                        self.coerce(value, ty)?;
+                        ty.clone()
+                    }
+                    None => self.value(value)?,
+                };
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.clone(), ty.clone());
+                Some(ty)
+            }
+            ExprKind::VarAssign { name, value } => {
+                let ty = self
+                    .lookup(name)
+                    .context("cannot assign to undeclared variable")?;
+                self.coerce(value, &ty)?;
+                None
+            }
+            ExprKind::VarAccess(name) => {
+                Some(self.lookup(name).context("variable not found in scope")?)
+            }
+            ExprKind::ArithOp {
+                operator,
+                width,
+                arg1,
+                arg2,
+            } => Some(self.check_arith(operator, width, arg1, arg2)?),
+            ExprKind::LogicalOp { arg1, arg2, .. } => {
+                self.value(arg1)?;
+                self.in_scope(|checker| checker.value(arg2))?;
+                Some(Type::Bool)
+            }
+            ExprKind::UnaryOp { width, arg, .. } => {
+                let ty = self.value(arg)?;
+                let Type::Int(int_type) = ty else {
+                    bail!("cannot apply a bitwise not to a {}", ty);
+                };
+                *width = int_type;
+                Some(ty)
+            }
+            ExprKind::Cast { ty, value } => {
+                if let Type::Bool = ty {
+                    bail!("cannot cast to bool, compare with 0 instead");
+                }
+                self.value(value)?;
+                Some(ty.clone())
+            }
+            ExprKind::Block(exprs) => {
+                let mut out = None;
+                for expr in exprs.iter_mut() {
+                    out = self.check(expr)?;
+                }
+                out
+            }
+            ExprKind::Scope(body) => self.in_scope(|checker| checker.check(body))?,
+            ExprKind::IfElse { pred, conseq, alt } => {
+                self.value(pred)?;
+                let conseq_ty = self.in_scope(|checker| checker.check(conseq))?;
+                let alt_ty = self.in_scope(|checker| checker.check(alt))?;
+                match (conseq_ty, alt_ty) {
+                    (Some(conseq_ty), Some(alt_ty)) => {
+                        let conseq_ty = adopt_type(conseq, &alt_ty).unwrap_or(conseq_ty);
+                        let alt_ty = adopt_type(alt, &conseq_ty).unwrap_or(alt_ty);
+                        Some(unify(conseq_ty, alt_ty)?)
+                    }
+                    _ => None,
+                }
+            }
+            ExprKind::Loop { label, body } => {
+                self.loops.push((label.clone(), None));
+                let out = self.in_scope(|checker| checker.check(body));
+                let (_, ty) = self.loops.pop().unwrap();
+                out?;
+                ty
+            }
+            ExprKind::Break { label, value } => {
+                if let Some(value) = value {
+                    let ty = self.value(value)?;
+                    // ir::gen reports breaks outside of loops
+                    let ctx = match label {
+                        Some(_) => self.loops.iter_mut().rev().find(|(l, _)| l == label),
+                        None => self.loops.last_mut(),
+                    };
+                    if let Some((_, loop_ty)) = ctx {
+                        *loop_ty = Some(match loop_ty.take() {
+                            Some(loop_ty) => unify(loop_ty, ty)?,
+                            None => ty,
+                        });
+                    }
+                }
+                None
+            }
+            ExprKind::Continue(_) | ExprKind::Noop => None,
+            ExprKind::IntegerLiteral(_) | ExprKind::Input => Some(Type::I64),
+            ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    self.coerce(value, &Type::I64)?;
+                }
+                None
+            }
+            ExprKind::ReadMemory(addr) => match self.value(addr)? {
+                Type::Ptr(inner) => Some(*inner),
+                Type::Int(_) => Some(Type::I64),
+                Type::Bool => bail!("cannot read from a bool"),
+            },
+            ExprKind::WriteMemory { addr, value } => {
+                match self.value(addr)? {
+                    Type::Ptr(inner) => self.coerce(value, &inner)?,
+                    Type::Int(_) => {
+                        self.value(value)?;
+                    }
+                    Type::Bool => bail!("cannot write to a bool"),
+                }
+                None
+            }
+            ExprKind::Call { func, args } => {
+                let signatures = self.signatures;
+                // check attaches the span of the call
+                let Some(arg_types) = signatures.get(func.as_str()) else {
+                    bail!("function {} is not defined", func);
+                };
+                // analysis already checks this, but zip would silently skip any extra args
+                if args.len() != arg_types.len() {
+                    bail!(
+                        "function {} takes {} arguments, but {} were provided",
+                        func,
+                        arg_types.len(),
+                        args.len()
+                    );
+                }
+                for (arg, ty) in args.iter_mut().zip(arg_types.iter()) {
+                    self.coerce(arg, ty)?;
+                }
+                Some(Type::I64)
+            }
+        })
+    }
+}
+
+// assigns a type to every value, checks that they are used consistently, and records the
+// width of each operation so that later passes can wrap their results.
+// Returns the type of every expression, by span
+pub fn typecheck(program: &mut Program<FuncDefinition>) -> Result<Vec<(Span, Option<Type>)>> {
+    let signatures = program
+        .funcs
+        .iter()
+        .map(|(name, func)| (name.clone(), func.arg_types.clone()))
+        .collect();
+    let mut types = vec![];
+    // each function is checked even if an earlier one fails
+    let funcs = program
+        .funcs
+        .iter_mut()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b));
+    collect_errors(funcs.map(|(_, func)| {
+        let mut checker = Checker {
+            signatures: &signatures,
+            scopes: vec![func
+                .args
+                .iter()
+                .cloned()
+                .zip(func.arg_types.iter().cloned())
+                .collect()],
+            loops: vec![],
+            types: vec![],
+        };
+        checker.check(&mut func.body)?;
+        types.append(&mut checker.types);
+        Ok(())
+    }))?;
+    Ok(types)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::typecheck;
+    use crate::diagnostics::SourceMap;
+    use crate::semantics::{load_program_from_source, FuncDefinition, Program, Type};
+
+    fn load(source: &str) -> Program<FuncDefinition> {
+        load_program_from_source(
+            Path::new("test.lang"),
+            source.to_string(),
+            &mut SourceMap::new(),
+        )
+        .unwrap()
+    }
+
+    fn error(program: &mut Program<FuncDefinition>) -> String {
+        let Err(err) = typecheck(program) else {
+            panic!("expected an error");
+        };
+        err.to_string()
+    }
+
+    // analysis rejects these calls, so the program is altered afterwards, as if it had not
+    #[test]
+    fn call_to_missing_function() {
+        let mut program = load("(func (g a) (return a)) (func (main) (return (g 1)))");
+        program.funcs.remove("g");
+        assert_eq!(error(&mut program), "function g is not defined");
+    }
+
+    #[test]
+    fn call_with_wrong_arity() {
+        let mut program = load("(func (g) (return 1)) (func (main) (return (g)))");
+        program.funcs.get_mut("g").unwrap().arg_types = Box::new([Type::I64]);
+        assert_eq!(
+            error(&mut program),
+            "function g takes 1 arguments, but 0 were provided"
+        );
+    }
+}
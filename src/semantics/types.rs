@@ -0,0 +1,119 @@
+use std::fmt::{self, Display, Formatter};
+
+// the width and signedness of an integer.
+// values are held in 64-bit registers, sign-extended if signed and zero-extended otherwise
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IntType {
+    pub signed: bool,
+    pub bits: u8,
+}
+
+impl IntType {
+    pub const I64: Self = Self {
+        signed: true,
+        bits: 64,
+    };
+    pub const U64: Self = Self {
+        signed: false,
+        bits: 64,
+    };
+
+    // truncates a value to this width, then sign- or zero-extends it back to 64 bits
+    pub const fn wrap(self, val: i64) -> i64 {
+        if self.bits == 64 {
+            return val;
+        }
+        let shift = 64 - self.bits as u32;
+        if self.signed {
+            (val << shift) >> shift
+        } else {
+            (((val as u64) << shift) >> shift) as i64
+        }
+    }
+
+    pub const fn fits(self, val: i64) -> bool {
+        self.wrap(val) == val
+    }
+
+    // the bits that are significant at this width
+    pub const fn mask(self) -> i64 {
+        if self.bits == 64 {
+            -1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    // whether every value of this type is also a value of other
+    pub const fn widens_to(self, other: Self) -> bool {
+        if self.signed == other.signed {
+            self.bits <= other.bits
+        } else {
+            !self.signed && self.bits < other.bits
+        }
+    }
+}
+
+impl Display for IntType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", if self.signed { "i" } else { "u" }, self.bits)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Int(IntType),
+    // held as 0 or 1
+    Bool,
+    // memory is untyped, so reading through a plain integer address yields an i64
+    Ptr(Box<Type>),
+}
+
+impl Type {
+    pub const I64: Self = Type::Int(IntType::I64);
+
+    pub fn parse(name: &str) -> Option<Self> {
+        if name == "bool" {
+            return Some(Type::Bool);
+        }
+        if let Some(inner) = name.strip_prefix("ptr<").and_then(|s| s.strip_suffix('>')) {
+            return Some(Type::Ptr(Box::new(Type::parse(inner)?)));
+        }
+        let signed = match name.chars().next()? {
+            'i' => true,
+            'u' => false,
+            _ => return None,
+        };
+        match name[1..].parse() {
+            Ok(bits @ (8 | 16 | 32 | 64)) => Some(Type::Int(IntType { signed, bits })),
+            _ => None,
+        }
+    }
+
+    // the integer type used when operating on values of this type
+    pub const fn int_type(&self) -> IntType {
+        match self {
+            Type::Int(int_type) => *int_type,
+            Type::Bool | Type::Ptr(_) => IntType::U64,
+        }
+    }
+
+    // implicit conversions never change the bits held in a register, so they are free
+    pub fn converts_to(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Int(from), Type::Int(to)) => from.widens_to(*to),
+            (Type::Bool, Type::Int(_)) => true,
+            (from, to) => from == to,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int(int_type) => int_type.fmt(f),
+            Type::Bool => write!(f, "bool"),
+            Type::Ptr(inner) => write!(f, "ptr<{inner}>"),
+        }
+    }
+}
@@ -41,6 +41,11 @@ impl<'a, K: Hash + Eq, V: Copy> Frame<K, V> {
             })
     }
 
+    // unlike lookup, ignores the parent frames
+    pub fn contains_local<Q: Borrow<K>>(&self, name: &Q) -> bool {
+        (*self.0).borrow().symbol_table.contains_key(name.borrow())
+    }
+
     pub fn assoc(&mut self, name: K, reg: V) {
         (*self.0).borrow_mut().symbol_table.insert(name, reg);
     }
@@ -1,4 +1,6 @@
 pub mod frame;
 pub mod graph;
 pub mod rcequality;
+#[cfg(test)]
+pub mod temp_dir;
 pub mod union_find;
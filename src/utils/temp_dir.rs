@@ -0,0 +1,34 @@
+use std::fs::{create_dir, remove_dir_all};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+// a fresh directory for a test to write files to, which is removed once the test is done with it,
+// even if it fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(test: &str) -> Self {
+        // the process id tells apart concurrent runs, and the attempt any directory left behind
+        for attempt in 0.. {
+            let path =
+                env::temp_dir().join(format!("mylang-{}-{}-{}", test, process::id(), attempt));
+            match create_dir(&path) {
+                Ok(()) => return Self(path),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => panic!("unable to create {}: {}", path.display(), err),
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}